@@ -12,21 +12,38 @@ date
 # Configure Pacman mirrors
 echo "${GB_MIRRORLIST:?}" >/etc/pacman.d/mirrorlist
 
+# The disk to install to (its name depends on the bus)
+DISK="${GB_DISK:-/dev/vda}"
+
+# Partitions on NVMe disks are named like nvme0n1p1
+case "${DISK}" in
+*[0-9]) PART="${DISK}p" ;;
+*) PART="${DISK}" ;;
+esac
+
 # Create partitions
-parted --script -a optimal -- /dev/vda \
-	mklabel gpt \
-	mkpart primary 1MiB 256MiB \
-	set 1 esp on \
-	mkpart primary 256MiB 100%
+if [ "${GB_FIRMWARE:-uefi}" = "bios" ]; then
+	parted --script -a optimal -- "${DISK}" \
+		mklabel msdos \
+		mkpart primary 1MiB 256MiB \
+		set 1 boot on \
+		mkpart primary 256MiB 100%
+else
+	parted --script -a optimal -- "${DISK}" \
+		mklabel gpt \
+		mkpart primary 1MiB 256MiB \
+		set 1 esp on \
+		mkpart primary 256MiB 100%
+fi
 
 # Format boot partition
-mkfs.vfat /dev/vda1
+mkfs.vfat "${PART}1"
 
 if [ "${GB_LUKS_PASSPHRASE}" != "" ]; then
 
 	# TODO configure parameters
-	echo -n "${GB_LUKS_PASSPHRASE}" | cryptsetup -v luksFormat /dev/vda2 -
-	echo -n "${GB_LUKS_PASSPHRASE}" | cryptsetup open /dev/vda2 root -
+	echo -n "${GB_LUKS_PASSPHRASE}" | cryptsetup -v luksFormat "${PART}2" -
+	echo -n "${GB_LUKS_PASSPHRASE}" | cryptsetup open "${PART}2" root -
 	history -cw
 
 	# Format root
@@ -36,14 +53,14 @@ if [ "${GB_LUKS_PASSPHRASE}" != "" ]; then
 	mount /dev/mapper/root /mnt
 else
 	# Format root
-	mkfs.ext4 /dev/vda2
+	mkfs.ext4 "${PART}2"
 
 	# Mount root
-	mount /dev/vda2 /mnt
+	mount "${PART}2" /mnt
 fi
 
 # Mount boot partition
-mount --mkdir /dev/vda1 /mnt/boot
+mount --mkdir "${PART}1" /mnt/boot
 
 # Display mounts before install
 mount
@@ -56,7 +73,7 @@ genfstab -U /mnt >/mnt/etc/fstab
 
 if [ -e /dev/mapper/root ]; then
 	cat <<-EOF >>/mnt/etc/default/grub
-		GRUB_CMDLINE_LINUX="cryptdevice=UUID=$(blkid -s UUID -o value /dev/vda2):root root=/dev/mapper/root"
+		GRUB_CMDLINE_LINUX="cryptdevice=UUID=$(blkid -s UUID -o value "${PART}2"):root root=/dev/mapper/root"
 	EOF
 
 	# Update initramfs
@@ -64,12 +81,16 @@ if [ -e /dev/mapper/root ]; then
 	arch-chroot /mnt mkinitcpio -P
 else
 	cat <<-EOF >>/mnt/etc/default/grub
-		GRUB_CMDLINE_LINUX="root=UUID=$(blkid -s UUID -o value /dev/vda2)"
+		GRUB_CMDLINE_LINUX="root=UUID=$(blkid -s UUID -o value "${PART}2")"
 	EOF
 fi
 
 # Install bootloader
-arch-chroot /mnt grub-install --target=x86_64-efi --efi-directory=/boot --bootloader-id=GRUB
+if [ "${GB_FIRMWARE:-uefi}" = "bios" ]; then
+	arch-chroot /mnt grub-install --target=i386-pc "${DISK}"
+else
+	arch-chroot /mnt grub-install --target=x86_64-efi --efi-directory=/boot --bootloader-id=GRUB
+fi
 arch-chroot /mnt grub-mkconfig -o /boot/grub/grub.cfg
 
 # Enable sshd
@@ -0,0 +1,585 @@
+//! Interactive authoring of boot commands. `goldboot record` boots installation
+//! media behind a VNC proxy which transcribes the keys pressed through it and
+//! the screen regions marked along the way into a boot command sequence for a
+//! new template.
+
+use crate::{
+	build::{BuildConfig, BuildJob, BuildWorker},
+	keyboard::{self, RETURN, TAB},
+	qcow::Qcow3,
+	qemu::{QemuArgs, QemuProcess},
+	resources,
+	templates::Template,
+	vnc::{VncCmd, VncConnection},
+	Architecture,
+};
+use log::{debug, error, info, warn};
+use simple_error::bail;
+use std::{
+	error::Error,
+	io::{BufRead, Read, Write},
+	net::{Shutdown, TcpListener, TcpStream},
+	path::Path,
+	sync::{mpsc, Arc, Mutex},
+};
+use validator::Validate;
+
+/// The size of the disk installers are recorded with (it's allocated lazily).
+const DISK_SIZE: u64 = 32 * 1024 * 1024 * 1024;
+
+/// Modifier keys which are only recorded as part of a combination.
+const MODIFIERS: &[u32] = &[
+	0xffe1, 0xffe2, 0xffe3, 0xffe4, 0xffe9, 0xffea, 0xffeb, 0xffec, 0xfe03,
+];
+
+/// Modifiers which viewers already apply to the characters they send.
+const CHARACTER_MODIFIERS: &[u32] = &[0xffe1, 0xffe2, 0xffea, 0xfe03];
+
+/// The mask bit of the middle mouse button which marks screen regions.
+const MARK_BUTTON: u8 = 0b010;
+
+/// Turns key presses and marked screens into boot commands.
+#[derive(Default, Debug)]
+pub struct Transcript {
+	commands: Vec<VncCmd>,
+
+	/// Characters typed since the last command
+	typed: String,
+
+	/// Modifiers which are currently held down
+	held: Vec<u32>,
+
+	/// Whether another key was pressed while modifiers were held
+	combined: bool,
+}
+
+impl Transcript {
+	fn flush(&mut self) {
+		if !self.typed.is_empty() {
+			self.commands
+				.push(VncCmd::Type(std::mem::take(&mut self.typed)));
+		}
+	}
+
+	/// Record a key event from the viewer.
+	pub fn key(&mut self, down: bool, keysym: u32) {
+		if MODIFIERS.contains(&keysym) {
+			if down {
+				if self.held.is_empty() {
+					self.combined = false;
+				}
+				if !self.held.contains(&keysym) {
+					self.held.push(keysym);
+				}
+			} else {
+				self.held.retain(|held| *held != keysym);
+
+				// A modifier on its own (i.e. Super to open a menu)
+				if !self.combined && !CHARACTER_MODIFIERS.contains(&keysym) {
+					self.combined = true;
+					self.press(&[keysym]);
+				}
+			}
+			return;
+		}
+		if !down {
+			return;
+		}
+
+		let character = keyboard::keysym_char(keysym);
+		let modifiers: Vec<u32> = self
+			.held
+			.iter()
+			.filter(|held| character.is_none() || !CHARACTER_MODIFIERS.contains(held))
+			.cloned()
+			.collect();
+		self.combined = true;
+
+		match (character, keysym) {
+			(Some(ch), _) if modifiers.is_empty() => self.typed.push(ch),
+			(None, RETURN) if modifiers.is_empty() => {
+				self.flush();
+				self.commands.push(VncCmd::Enter);
+			}
+			(None, TAB) if modifiers.is_empty() => {
+				self.flush();
+				self.commands.push(VncCmd::Tab);
+			}
+			_ => self.press(&[modifiers, vec![keysym]].concat()),
+		}
+	}
+
+	/// Record a key combination.
+	fn press(&mut self, keysyms: &[u32]) {
+		match keysyms
+			.iter()
+			.map(|keysym| keyboard::name(*keysym))
+			.collect::<Option<Vec<String>>>()
+		{
+			Some(names) => {
+				self.flush();
+				self.commands.push(VncCmd::Keys(names.join("+")));
+			}
+			None => warn!("Skipping unknown keys: {:x?}", keysyms),
+		}
+	}
+
+	/// Record a command like a screen wait.
+	pub fn push(&mut self, command: VncCmd) {
+		self.flush();
+		self.commands.push(command);
+	}
+
+	pub fn finish(mut self) -> Vec<VncCmd> {
+		self.flush();
+		self.commands
+	}
+}
+
+/// Write boot commands as the macros which produce them.
+pub fn to_source(commands: &[VncCmd]) -> String {
+	let mut lines = Vec::new();
+	let mut commands = commands.iter().peekable();
+	while let Some(command) = commands.next() {
+		lines.push(match command {
+			VncCmd::Type(text) => match commands.peek() {
+				Some(VncCmd::Enter) => {
+					commands.next();
+					format!("enter!({:?}),", text)
+				}
+				Some(VncCmd::Tab) => {
+					commands.next();
+					format!("tab!({:?}),", text)
+				}
+				_ => format!("input!({:?}),", text),
+			},
+			VncCmd::Enter => String::from("enter!(),"),
+			VncCmd::Tab => String::from("tab!(),"),
+			VncCmd::Spacebar => String::from("spacebar!(),"),
+			VncCmd::Escape => String::from("escape!(),"),
+			VncCmd::LeftSuper => String::from("leftSuper!(),"),
+			VncCmd::Keys(keys) => format!("keys!({:?}),", keys),
+			VncCmd::KeyDown(key) => format!("key_down!({:?}),", key),
+			VncCmd::KeyUp(key) => format!("key_up!({:?}),", key),
+			VncCmd::Wait(seconds) => format!("wait!({}),", seconds),
+			VncCmd::WaitScreen(hash, _) => format!("wait_screen!({:?}),", hash),
+			VncCmd::WaitScreenRect(hash, top, left, width, height, _) => format!(
+				"wait_screen_rect!({:?}, {}, {}, {}, {}),",
+				hash, top, left, width, height
+			),
+		});
+	}
+
+	format!(
+		"qemu.vnc.boot_command(vec![\n{}])?;\n",
+		lines
+			.iter()
+			.map(|line| format!("\t{}\n", line))
+			.collect::<String>()
+	)
+}
+
+/// Stands in for a template since recording sessions don't build images.
+#[derive(Validate)]
+struct RecordTemplate {}
+
+impl Template for RecordTemplate {
+	fn build(&self, _context: &BuildWorker) -> Result<(), Box<dyn Error>> {
+		bail!("Recording sessions don't build images");
+	}
+}
+
+/// The state shared by the proxy and the terminal.
+struct Session {
+	transcript: Transcript,
+
+	/// A separate connection for screenshots of marked regions
+	vnc: VncConnection,
+
+	/// Where the mark button was pressed
+	mark: Option<(u16, u16)>,
+}
+
+impl Session {
+	/// Add a wait for the given region (or the whole screen) as it looks now.
+	fn mark(&mut self, rect: Option<vnc::Rect>) -> Result<(), Box<dyn Error>> {
+		let screenshot = self.vnc.screenshot()?;
+		let screenshot = match rect {
+			Some(rect) => screenshot.trim(rect)?,
+			None => screenshot,
+		};
+		let hash = screenshot.hash();
+		screenshot.write_png(Path::new(&format!("screenshots/{hash}.png")))?;
+
+		info!(
+			"Marked screen: {} ({} x {})",
+			hash, screenshot.width, screenshot.height
+		);
+		self.transcript.push(match rect {
+			Some(rect) => {
+				VncCmd::WaitScreenRect(hash, rect.top, rect.left, rect.width, rect.height, None)
+			}
+			None => VncCmd::WaitScreen(hash, None),
+		});
+		Ok(())
+	}
+
+	/// Track the mark button and add a wait when a region has been dragged out.
+	fn pointer(&mut self, mask: u8, x: u16, y: u16) -> Result<(), Box<dyn Error>> {
+		match (mask & MARK_BUTTON != 0, self.mark) {
+			(true, None) => self.mark = Some((x, y)),
+			(false, Some((start_x, start_y))) => {
+				self.mark = None;
+				let rect = vnc::Rect {
+					left: start_x.min(x),
+					top: start_y.min(y),
+					width: start_x.abs_diff(x),
+					height: start_y.abs_diff(y),
+				};
+				if rect.width > 0 && rect.height > 0 {
+					self.mark(Some(rect))?;
+				}
+			}
+			_ => {}
+		}
+		Ok(())
+	}
+}
+
+fn read_bytes(stream: &mut TcpStream, count: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+	let mut buffer = vec![0u8; count];
+	stream.read_exact(&mut buffer)?;
+	Ok(buffer)
+}
+
+/// Forward the viewer's messages to the VM, transcribing key events and
+/// keeping the mark button to ourselves. QEMU drops clients which send
+/// messages it doesn't support, so the proxy stops at those too.
+fn relay(
+	mut client: TcpStream,
+	mut server: TcpStream,
+	session: Arc<Mutex<Session>>,
+) -> Result<(), Box<dyn Error>> {
+	// The handshake is the protocol version, the security type (except in
+	// version 3.3) and the ClientInit message
+	let version = read_bytes(&mut client, 12)?;
+	server.write_all(&version)?;
+	if version != b"RFB 003.003\n" {
+		server.write_all(&read_bytes(&mut client, 1)?)?;
+	}
+
+	// The session must be shared or QEMU would disconnect our own connections
+	read_bytes(&mut client, 1)?;
+	server.write_all(&[1])?;
+
+	loop {
+		let mut message = read_bytes(&mut client, 1)?;
+		match message[0] {
+			// SetPixelFormat
+			0 => message.extend(read_bytes(&mut client, 19)?),
+			// SetEncodings
+			2 => {
+				message.extend(read_bytes(&mut client, 3)?);
+				let count = u16::from_be_bytes([message[2], message[3]]) as usize;
+				message.extend(read_bytes(&mut client, 4 * count)?);
+			}
+			// FramebufferUpdateRequest
+			3 => message.extend(read_bytes(&mut client, 9)?),
+			// KeyEvent
+			4 => {
+				message.extend(read_bytes(&mut client, 7)?);
+				let keysym = u32::from_be_bytes([message[4], message[5], message[6], message[7]]);
+				debug!("Key event: {} {:x}", message[1], keysym);
+				session
+					.lock()
+					.unwrap()
+					.transcript
+					.key(message[1] != 0, keysym);
+			}
+			// PointerEvent
+			5 => {
+				message.extend(read_bytes(&mut client, 5)?);
+				let x = u16::from_be_bytes([message[2], message[3]]);
+				let y = u16::from_be_bytes([message[4], message[5]]);
+				if let Err(error) = session.lock().unwrap().pointer(message[1], x, y) {
+					warn!("Failed to mark region: {}", error);
+				}
+				message[1] &= !MARK_BUTTON;
+			}
+			// ClientCutText (a negative length is an extended clipboard message)
+			6 => {
+				message.extend(read_bytes(&mut client, 7)?);
+				let length = i32::from_be_bytes([message[4], message[5], message[6], message[7]]);
+				message.extend(read_bytes(&mut client, length.unsigned_abs() as usize)?);
+			}
+			// SetDesktopSize
+			251 => {
+				message.extend(read_bytes(&mut client, 7)?);
+				message.extend(read_bytes(&mut client, 16 * message[6] as usize)?);
+			}
+			// QEMU extensions
+			255 => {
+				message.extend(read_bytes(&mut client, 1)?);
+				match message[1] {
+					// Extended key event
+					0 => {
+						message.extend(read_bytes(&mut client, 10)?);
+						let keysym =
+							u32::from_be_bytes([message[4], message[5], message[6], message[7]]);
+						let down = message[2] != 0 || message[3] != 0;
+						session.lock().unwrap().transcript.key(down, keysym);
+					}
+					// Audio
+					1 => {
+						message.extend(read_bytes(&mut client, 2)?);
+						if message[3] == 2 {
+							message.extend(read_bytes(&mut client, 6)?);
+						}
+					}
+					other => bail!("Unsupported QEMU message: {}", other),
+				}
+			}
+			other => bail!("Unsupported VNC message: {}", other),
+		}
+		server.write_all(&message)?;
+	}
+}
+
+/// What the terminal loop waits on.
+enum Input {
+	Line(std::io::Result<String>),
+
+	/// The proxy failed, so nothing more can be recorded
+	Stopped(String),
+}
+
+/// Boots installation media for recording.
+pub struct RecordJob {
+	pub worker: BuildWorker,
+	pub iso: String,
+}
+
+impl RecordJob {
+	pub fn new(iso: &str, arch: Architecture) -> Result<Self, Box<dyn Error>> {
+		if !Path::new(iso).is_file() {
+			bail!("Installation media not found: {}", iso);
+		}
+
+		let config = BuildConfig {
+			name: String::from("record"),
+			arch,
+			..Default::default()
+		};
+		let job = BuildJob::new(config, false, false, Some(1));
+		let worker = job.new_worker(0, Box::new(RecordTemplate {}), &job.schedule()?)?;
+		Qcow3::create(&worker.image_path, DISK_SIZE)?;
+
+		Ok(Self {
+			worker,
+			iso: iso.to_string(),
+		})
+	}
+
+	fn start(&self) -> Result<QemuProcess, Box<dyn Error>> {
+		let mut qemuargs = QemuArgs::new(&self.worker);
+		qemuargs.add_disk(&self.worker.image_path);
+		qemuargs
+			.drive
+			.push(format!("file={},media=cdrom", self.iso));
+		qemuargs.start_process()
+	}
+
+	/// Boot the media and record until the user quits on the terminal.
+	pub fn record(&self) -> Result<Vec<VncCmd>, Box<dyn Error>> {
+		let _qemu = self.start()?;
+
+		let port = resources::reserve_port(5900, 5999)?;
+		let listener = TcpListener::bind(("127.0.0.1", port.port))?;
+		info!(
+			"Connect a VNC viewer to 127.0.0.1:{} and drive the installer by hand",
+			port.port
+		);
+		let (client, _) = listener.accept()?;
+		let server = TcpStream::connect(("127.0.0.1", self.worker.vnc_port))?;
+
+		let session = Arc::new(Mutex::new(Session {
+			transcript: Transcript::default(),
+			vnc: VncConnection::new(
+				"127.0.0.1",
+				self.worker.vnc_port,
+				self.worker.id,
+				false,
+				false,
+				self.worker.cancel.clone(),
+			)?,
+			mark: None,
+		}));
+
+		// The VM's output goes straight to the viewer
+		{
+			let mut client = client.try_clone()?;
+			let mut server = server.try_clone()?;
+			std::thread::spawn(move || std::io::copy(&mut server, &mut client));
+		}
+
+		// Terminal input and the end of the proxy arrive on the same channel so
+		// that recording stops when the viewer can't be relayed anymore
+		let (sender, receiver) = mpsc::channel();
+		{
+			let client = client.try_clone()?;
+			let session = session.clone();
+			let sender = sender.clone();
+			std::thread::spawn(move || {
+				if let Err(error) = relay(client, server, session) {
+					sender
+						.send(Input::Stopped(error.to_string()))
+						.unwrap_or_default();
+				}
+			});
+		}
+		std::thread::spawn(move || {
+			for line in std::io::stdin().lock().lines() {
+				if sender.send(Input::Line(line)).is_err() {
+					break;
+				}
+			}
+		});
+
+		info!(
+			"Drag with the middle mouse button to wait for a region, or enter 's' to wait for the whole screen, 'w <seconds>' to pause, or 'q' to finish"
+		);
+		while let Ok(input) = receiver.recv() {
+			self.worker.cancel.check()?;
+			let line = match input {
+				Input::Line(line) => line?,
+				Input::Stopped(error) => {
+					error!("VNC proxy stopped, finishing the recording: {}", error);
+					break;
+				}
+			};
+			let mut words = line.split_whitespace();
+			let mut session = session.lock().unwrap();
+			match (words.next(), words.next()) {
+				(Some("s"), _) => {
+					if let Err(error) = session.mark(None) {
+						warn!("Failed to mark screen: {}", error);
+					}
+				}
+				(Some("w"), Some(seconds)) => match seconds.parse() {
+					Ok(seconds) => session.transcript.push(VncCmd::Wait(seconds)),
+					Err(_) => warn!("Invalid number of seconds: {}", seconds),
+				},
+				(Some("q"), _) => break,
+				_ => continue,
+			}
+		}
+
+		client.shutdown(Shutdown::Both).unwrap_or_default();
+		let transcript = std::mem::take(&mut session.lock().unwrap().transcript);
+		Ok(transcript.finish())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::harness::Harness;
+
+	#[test]
+	fn test_relay() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![])?;
+		let session = Arc::new(Mutex::new(Session {
+			transcript: Transcript::default(),
+			vnc: harness.vnc_connection()?,
+			mark: None,
+		}));
+
+		let listener = TcpListener::bind("127.0.0.1:0")?;
+		let mut viewer = TcpStream::connect(listener.local_addr()?)?;
+		let (client, _) = listener.accept()?;
+		let mut vm = TcpStream::connect(listener.local_addr()?)?;
+		let (server, _) = listener.accept()?;
+
+		let relayed = session.clone();
+		let proxy = std::thread::spawn(move || {
+			relay(client, server, relayed).map_err(|error| error.to_string())
+		});
+
+		let messages: Vec<u8> = [
+			// SetDesktopSize with one screen
+			&[251, 0, 2, 128, 1, 224, 1, 0][..],
+			&[0; 16],
+			// KeyEvent
+			&[4, 1, 0, 0, 0, 0, 0, 0x61],
+		]
+		.concat();
+		viewer.write_all(b"RFB 003.008\n\x01\x00")?;
+		viewer.write_all(&messages)?;
+		viewer.write_all(&[200])?;
+
+		let mut received = vec![0u8; 14 + messages.len()];
+		vm.read_exact(&mut received)?;
+		assert_eq!(received, [b"RFB 003.008\n\x01\x01", &messages[..]].concat());
+
+		assert_eq!(
+			proxy.join().unwrap(),
+			Err(String::from("Unsupported VNC message: 200"))
+		);
+		let transcript = std::mem::take(&mut session.lock().unwrap().transcript);
+		assert_eq!(
+			to_source(&transcript.finish()),
+			"qemu.vnc.boot_command(vec![\n\tinput!(\"a\"),\n])?;\n"
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_transcript() {
+		let mut transcript = Transcript::default();
+		for ch in "root".chars() {
+			transcript.key(true, ch as u32);
+			transcript.key(false, ch as u32);
+		}
+		transcript.key(true, RETURN);
+		transcript.push(VncCmd::WaitScreenRect(
+			String::from("abc"),
+			1,
+			2,
+			3,
+			4,
+			None,
+		));
+
+		// Shift is part of the character
+		transcript.key(true, 0xffe1);
+		transcript.key(true, 'A' as u32);
+		transcript.key(false, 0xffe1);
+
+		// Combinations
+		for (down, keysym) in [
+			(true, 0xffe3),
+			(true, 0xffe9),
+			(true, 0xffff),
+			(false, 0xffe9),
+			(false, 0xffe3),
+		] {
+			transcript.key(down, keysym);
+		}
+		transcript.key(true, 0xffeb);
+		transcript.key(false, 0xffeb);
+		transcript.key(true, 0xffc9);
+
+		assert_eq!(
+			to_source(&transcript.finish()),
+			r#"qemu.vnc.boot_command(vec![
+	enter!("root"),
+	wait_screen_rect!("abc", 1, 2, 3, 4),
+	input!("A"),
+	keys!("Ctrl+Alt+Delete"),
+	keys!("Super"),
+	keys!("F12"),
+])?;
+"#
+		);
+	}
+}
@@ -1,11 +1,42 @@
 use crate::{
-	image::ImageHandle, library::ImageLibrary, qcow::Qcow3, templates::Template, Architecture,
+	cancel::CancelToken,
+	events::{self, BuildEvent},
+	hardware::{Firmware, HardwareProfile},
+	hooks::{HookStage, Hooks},
+	image::ImageHandle,
+	library::ImageLibrary,
+	network::NetworkConfig,
+	qcow::Qcow3,
+	qemu::Launcher,
+	resources::{self, HostResources, PortLease, Schedule},
+	run::TestConfig,
+	sbom,
+	shares::ShareConfig,
+	ssh::SshConnection,
+	templates::{Template, TemplateId},
+	tpm::{self, TpmConfig},
+	uefi::{self, SecureBootConfig},
+	variables::Variable,
+	Architecture,
+};
+use log::{debug, error, info, warn};
+use schemars::{
+	gen::SchemaSettings,
+	schema::{RootSchema, Schema, SchemaObject, SubschemaValidation},
+	JsonSchema,
 };
-use log::{debug, info};
-use rand::Rng;
 use serde::{Deserialize, Serialize};
 use simple_error::bail;
-use std::{error::Error, thread, time::SystemTime};
+use std::{
+	collections::{BTreeMap, VecDeque},
+	error::Error,
+	panic::{self, AssertUnwindSafe},
+	path::{Path, PathBuf},
+	sync::mpsc,
+	thread,
+	time::{Duration, SystemTime},
+};
+use strum::{EnumIter, IntoEnumIterator};
 use validator::Validate;
 
 // UEFI firmwares for various platforms
@@ -13,8 +44,63 @@ const OVMF_X86_64: &[u8; 1051773] = include_bytes!("../res/OVMF_x86_64.fd.zst");
 const OVMF_I386: &[u8; 1635380] = include_bytes!("../res/OVMF_i386.fd.zst");
 const OVMF_AARCH64: &[u8; 1478920] = include_bytes!("../res/OVMF_aarch64.fd.zst");
 
+/// Config filenames in the order they're searched for.
+const CONFIG_FILENAMES: [&str; 4] = [
+	"goldboot.json",
+	"goldboot.yaml",
+	"goldboot.yml",
+	"goldboot.toml",
+];
+
+/// The file formats a build config can be written in.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq, EnumIter)]
+pub enum ConfigFormat {
+	Json,
+	Yaml,
+	Toml,
+}
+
+impl ConfigFormat {
+	/// Determine the format of a config file from its extension.
+	pub fn from_path(path: &Path) -> Result<Self, Box<dyn Error>> {
+		match path.extension().and_then(|extension| extension.to_str()) {
+			Some("json") => Ok(ConfigFormat::Json),
+			Some("yaml") | Some("yml") => Ok(ConfigFormat::Yaml),
+			Some("toml") => Ok(ConfigFormat::Toml),
+			_ => bail!("Unknown config format: {}", path.display()),
+		}
+	}
+
+	/// The default config filename for this format.
+	pub fn filename(&self) -> &'static str {
+		match self {
+			ConfigFormat::Json => "goldboot.json",
+			ConfigFormat::Yaml => "goldboot.yaml",
+			ConfigFormat::Toml => "goldboot.toml",
+		}
+	}
+
+	/// Parse the given content into a JSON value.
+	pub fn parse(&self, content: &[u8]) -> Result<serde_json::Value, Box<dyn Error>> {
+		Ok(match self {
+			ConfigFormat::Json => serde_json::from_slice(content)?,
+			ConfigFormat::Yaml => serde_yaml::from_slice(content)?,
+			ConfigFormat::Toml => toml::from_str(std::str::from_utf8(content)?)?,
+		})
+	}
+
+	/// Serialize the given value in this format.
+	pub fn to_string<T: Serialize>(&self, value: &T) -> Result<String, Box<dyn Error>> {
+		Ok(match self {
+			ConfigFormat::Json => serde_json::to_string_pretty(value)?,
+			ConfigFormat::Yaml => serde_yaml::to_string(value)?,
+			ConfigFormat::Toml => toml::to_string_pretty(value)?,
+		})
+	}
+}
+
 /// The global configuration
-#[derive(Clone, Serialize, Deserialize, Validate, Default, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Default, Debug)]
 pub struct BuildConfig {
 	/// The image name
 	#[validate(length(min = 1, max = 64))]
@@ -30,35 +116,208 @@ pub struct BuildConfig {
 	pub arch: Architecture,
 
 	/// The amount of memory to allocate to the VM
+	#[validate(custom(function = "resources::validate_memory"))]
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub memory: Option<String>,
 
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub nvme: Option<bool>,
 
+	/// The hardware that the build VM should resemble
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub hardware: Option<HardwareProfile>,
+
+	/// The firmware the image boots with (defaults to the hardware profile's
+	/// firmware or UEFI). Only some templates support BIOS.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub firmware: Option<Firmware>,
+
+	/// Attach an emulated TPM 2.0 to each worker (requires swtpm)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub tpm: Option<TpmConfig>,
+
+	/// Networking options for the build VMs
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub network: Option<NetworkConfig>,
+
+	/// Host directories which are mounted in the guest before provisioners run
+	/// (not supported by the Windows and macOS templates)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub shares: Option<Vec<ShareConfig>>,
+
+	/// Enroll Secure Boot keys before the first boot (amd64 only)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub secure_boot: Option<SecureBootConfig>,
+
+	/// Keep each worker's EFI variable store next to the output image
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub keep_vars: Option<bool>,
+
+	/// Checks for `goldboot test` to run in the booted image
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub test: Option<TestConfig>,
+
 	/// The encryption password. This value can alternatively be specified on
 	/// the command line and will be cleared before the config is included in
 	/// an image file.
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub password: Option<String>,
 
+	/// The maximum number of seconds the build may run before it's cancelled
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub timeout: Option<u64>,
+
+	/// The default number of seconds each boot command waits for the screen
+	/// (or serial console) before the build fails
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub wait_timeout: Option<u64>,
+
+	/// Variables which can be referenced elsewhere in the config
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub variables: Option<BTreeMap<String, Variable>>,
+
+	/// Fields which were interpolated from secret variables by JSON pointer,
+	/// with the secrets left as references
+	#[serde(skip)]
+	pub redactions: BTreeMap<String, String>,
+
+	/// Host commands to run at various points of the build
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub hooks: Option<Hooks>,
+
 	#[validate(length(min = 1))]
 	pub templates: Vec<serde_json::Value>,
 }
 
 impl BuildConfig {
+	/// Find a config file in the given directory.
+	pub fn find(directory: &Path) -> Option<PathBuf> {
+		CONFIG_FILENAMES
+			.iter()
+			.map(|filename| directory.join(filename))
+			.find(|path| path.exists())
+	}
+
+	/// Read the given config file into a JSON value without interpreting it.
+	pub fn read(path: &Path) -> Result<serde_json::Value, Box<dyn Error>> {
+		ConfigFormat::from_path(path)?.parse(&std::fs::read(path)?)
+	}
+
+	/// Generate a JSON Schema for build configs. Templates are stored as raw
+	/// values, so their schemas are listed explicitly.
+	pub fn schema() -> RootSchema {
+		let mut generator = SchemaSettings::draft07().into_generator();
+
+		let templates: Vec<Schema> = TemplateId::iter()
+			.filter_map(|id| id.schema(&mut generator))
+			.collect();
+
+		let mut schema = generator.root_schema_for::<BuildConfig>();
+		if let Some(Schema::Object(property)) =
+			schema.schema.object().properties.get_mut("templates")
+		{
+			property.array().items = Some(
+				Schema::Object(SchemaObject {
+					subschemas: Some(Box::new(SubschemaValidation {
+						one_of: Some(templates),
+						..Default::default()
+					})),
+					..Default::default()
+				})
+				.into(),
+			);
+		}
+
+		schema
+	}
+
+	/// Decide how many workers can run at once (up to the given number of
+	/// jobs) and what resources each one receives.
+	pub fn schedule(&self, jobs: Option<usize>) -> Result<Schedule, Box<dyn Error>> {
+		let memory = match &self.memory {
+			Some(memory) => Some(resources::parse_memory(memory)?),
+			None => None,
+		};
+
+		Ok(Schedule::new(
+			self.templates.len(),
+			jobs,
+			memory,
+			&HostResources::detect(),
+		))
+	}
+
+	/// Check the combinations of settings which the validation rules can't
+	/// express.
+	pub fn check(&self) -> Result<(), Box<dyn Error>> {
+		if self.firmware() == Firmware::Bios {
+			if self.arch == Architecture::arm64 {
+				bail!("BIOS firmware is not supported on arm64");
+			}
+			if self.secure_boot.is_some() {
+				bail!("Secure Boot requires UEFI firmware");
+			}
+		}
+
+		// Only the amd64 firmware ships with a formatted variable store
+		if self.secure_boot.is_some() && self.arch != Architecture::amd64 {
+			bail!("Secure Boot is only supported on amd64");
+		}
+
+		for share in self.shares.iter().flatten() {
+			share.check()?;
+		}
+		Ok(())
+	}
+
+	/// Check that the given template supports the configured settings.
+	pub fn check_template(&self, template: &dyn Template) -> Result<(), Box<dyn Error>> {
+		if self.firmware() == Firmware::Bios && !template.supports_bios() {
+			bail!("BIOS firmware is not supported by this template");
+		}
+		if self
+			.shares
+			.as_ref()
+			.is_some_and(|shares| !shares.is_empty())
+			&& !template.supports_shares()
+		{
+			bail!("Shared directories are not supported by this template");
+		}
+		Ok(())
+	}
+
+	/// The firmware that build VMs boot with.
+	pub fn firmware(&self) -> Firmware {
+		match (&self.firmware, &self.hardware) {
+			(Some(firmware), _) => *firmware,
+			(None, Some(hardware)) => hardware.firmware,
+			(None, None) => Firmware::Uefi,
+		}
+	}
+
 	pub fn get_templates(&self) -> Result<Vec<Box<dyn Template>>, Box<dyn Error>> {
 		let mut templates: Vec<Box<dyn Template>> = Vec::new();
 
-		for template in &self.templates {
-			// Get type
-			let t: TemplateBase = serde_json::from_value(template.to_owned())?;
-			templates.push(t.parse_template(template.to_owned())?);
+		for (id, template) in self.get_template_ids()?.iter().zip(&self.templates) {
+			templates.push(id.parse(template.to_owned())?);
 		}
 
 		Ok(templates)
 	}
 
+	pub fn get_template_ids(&self) -> Result<Vec<TemplateId>, Box<dyn Error>> {
+		let mut ids: Vec<TemplateId> = Vec::new();
+
+		for template in &self.templates {
+			match template.get("id") {
+				Some(id) => ids.push(serde_json::from_value(id.to_owned())?),
+				None => bail!("Template is missing an id"),
+			}
+		}
+
+		Ok(ids)
+	}
+
 	pub fn get_template_bases(&self) -> Result<Vec<String>, Box<dyn Error>> {
 		let mut bases: Vec<String> = Vec::new();
 
@@ -88,86 +347,126 @@ pub struct BuildJob {
 	/// Whether screenshots will be generated during the run for debugging
 	pub record: bool,
 
+	/// Whether each worker's whole session is recorded to an animated PNG
+	pub timeline: bool,
+
+	/// Where diagnostics and timelines are written
+	pub artifacts: PathBuf,
+
 	/// When set, the run will pause before each step in the boot sequence
 	pub debug: bool,
 
 	/// The path to the final image artifact
 	pub image_path: String,
+
+	/// Stops the build when triggered
+	pub cancel: CancelToken,
+
+	/// The maximum number of workers to run at once (further limited by the
+	/// host's resources)
+	pub jobs: Option<usize>,
 }
 
 impl BuildJob {
-	pub fn new(config: BuildConfig, record: bool, debug: bool) -> Self {
+	pub fn new(config: BuildConfig, record: bool, debug: bool, jobs: Option<usize>) -> Self {
 		// Obtain a temporary directory
 		let tmp = tempfile::tempdir().unwrap();
 
 		// Determine image path
 		let image_path = tmp.path().join("image.gb").to_string_lossy().to_string();
 
+		// The timeout starts counting now
+		let cancel = CancelToken::new(config.timeout.map(Duration::from_secs));
+
 		Self {
 			tmp,
 			start_time: None,
 			end_time: None,
 			config,
 			record,
+			timeline: false,
+			artifacts: PathBuf::from("artifacts"),
 			debug,
 			image_path,
+			cancel,
+			jobs,
 		}
 	}
 
+	/// Decide how many workers can run at once and what resources each one
+	/// receives.
+	pub fn schedule(&self) -> Result<Schedule, Box<dyn Error>> {
+		self.config.schedule(self.jobs)
+	}
+
 	/// Create a new generic build context.
-	fn new_worker(&self, template: Box<dyn Template>) -> Result<BuildWorker, Box<dyn Error>> {
+	pub(crate) fn new_worker(
+		&self,
+		id: usize,
+		template: Box<dyn Template>,
+		schedule: &Schedule,
+	) -> Result<BuildWorker, Box<dyn Error>> {
+		self.config.check()?;
+		self.config.check_template(template.as_ref())?;
+
 		// Obtain a temporary directory
 		let tmp = tempfile::tempdir().unwrap();
+		let worker = BuildWorker::plan(
+			id,
+			self.config.clone(),
+			template,
+			schedule,
+			tmp.path().to_path_buf(),
+		);
 
-		// Determine image path
-		let image_path = tmp.path().join("image.qcow2").to_string_lossy().to_string();
-
-		// Unpack included firmware
-		let ovmf_path = tmp.path().join("OVMF.fd").to_string_lossy().to_string();
-
-		match &self.config.arch {
-			Architecture::amd64 => {
-				std::fs::write(
-					&ovmf_path,
-					zstd::decode_all(std::io::Cursor::new(OVMF_X86_64))?,
-				)?;
-			}
-			Architecture::i386 => {
-				std::fs::write(
-					&ovmf_path,
-					zstd::decode_all(std::io::Cursor::new(OVMF_I386))?,
-				)?;
-			}
-			Architecture::arm64 => {
-				std::fs::write(
-					&ovmf_path,
-					zstd::decode_all(std::io::Cursor::new(OVMF_AARCH64))?,
-				)?;
-			}
+		// Unpack included firmware into separate code and variable stores
+		let firmware = match &self.config.arch {
+			Architecture::amd64 => OVMF_X86_64.as_slice(),
+			Architecture::i386 => OVMF_I386.as_slice(),
+			Architecture::arm64 => OVMF_AARCH64.as_slice(),
 			_ => bail!("Unsupported architecture"),
+		};
+		let (code, vars) =
+			uefi::split_firmware(&zstd::decode_all(std::io::Cursor::new(firmware))?)?;
+		std::fs::write(&worker.ovmf_path, code)?;
+		std::fs::write(&worker.ovmf_vars_path, vars)?;
+
+		if let Some(secure_boot) = &self.config.secure_boot {
+			secure_boot.enroll(Path::new(&worker.ovmf_vars_path))?;
 		}
 
+		let ssh_port = resources::reserve_port(10000, 11000)?;
+		let vnc_port = if self.debug {
+			resources::reserve_port(5900, 5901)?
+		} else {
+			resources::reserve_port(5900, 5999)?
+		};
+
 		Ok(BuildWorker {
-			tmp,
-			image_path,
-			ovmf_path,
-			template,
-			ssh_port: rand::thread_rng().gen_range(10000..11000),
-			vnc_port: if self.debug {
-				5900
-			} else {
-				rand::thread_rng().gen_range(5900..5999)
-			},
-			config: self.config.clone(),
+			tmp_dir: Some(tmp),
+			ssh_port: ssh_port.port,
+			vnc_port: vnc_port.port,
+			ports: vec![ssh_port, vnc_port],
 			record: self.record,
+			timeline: self.timeline,
+			artifacts: self.artifacts.clone(),
 			debug: self.debug,
+			cancel: self.cancel.clone(),
+			..worker
 		})
 	}
 
+	/// The environment variables given to every hook.
+	fn hook_env(&self) -> Vec<(&'static str, String)> {
+		vec![("GOLDBOOT_IMAGE_NAME", self.config.name.clone())]
+	}
+
 	/// Run the entire build process. If no output file is given, the image is
 	/// moved into the image library.
 	pub fn run(&mut self, output: Option<String>) -> Result<(), Box<dyn Error>> {
 		self.start_time = Some(SystemTime::now());
+		let hooks = self.config.hooks.clone().unwrap_or_default();
+		hooks.run(HookStage::PreBuild, self.hook_env(), &self.cancel)?;
 
 		// Load templates
 		let templates = self.config.get_templates()?;
@@ -184,30 +483,90 @@ impl BuildJob {
 		// Track the workers
 		let mut workers = Vec::new();
 
+		// Track failed workers by ID
+		let mut failures = Vec::new();
+
+		let schedule = self.schedule()?;
+		debug!("Using schedule: {:?}", schedule);
+
 		// If we're in debug mode, run workers sequentially
 		if self.debug {
-			for template in templates.into_iter() {
-				let worker = self.new_worker(template)?;
-				worker.run()?;
+			for (id, template) in templates.into_iter().enumerate() {
+				let worker = self.new_worker(id, template, &schedule)?;
+				if let Err(error) = worker.run() {
+					failures.push((id, error.to_string()));
+					break;
+				}
 				workers.push(worker);
 			}
 		}
 		// Otherwise run independent builds in parallel
 		else {
-			let mut handles = Vec::new();
-
-			for template in templates.into_iter() {
-				let worker = self.new_worker(template)?;
-				handles.push(thread::spawn(move || {
-					worker.run().unwrap();
-					worker
-				}));
+			let mut queue: VecDeque<(usize, Box<dyn Template>)> =
+				templates.into_iter().enumerate().collect();
+			let (sender, receiver) = mpsc::channel();
+			let mut running = 0;
+
+			loop {
+				// Start queued workers until the schedule's limit is reached
+				while running < schedule.parallel
+					&& failures.len() == 0
+					&& !self.cancel.is_cancelled()
+				{
+					let (id, template) = match queue.pop_front() {
+						Some(next) => next,
+						None => break,
+					};
+					if queue.len() > 0 {
+						info!("Starting worker {} ({} queued)", id, queue.len());
+					}
+
+					let worker = match self.new_worker(id, template, &schedule) {
+						Ok(worker) => worker,
+						Err(error) => {
+							failures.push((id, error.to_string()));
+							break;
+						}
+					};
+					let sender = sender.clone();
+					thread::spawn(move || {
+						let result = match panic::catch_unwind(AssertUnwindSafe(|| worker.run())) {
+							Ok(Ok(_)) => Ok(worker),
+							Ok(Err(error)) => Err(error.to_string()),
+							Err(_) => Err(String::from("Worker panicked")),
+						};
+						sender.send((id, result)).unwrap_or_default();
+					});
+					running += 1;
+				}
+
+				if running == 0 {
+					break;
+				}
+
+				// Wait for the next worker to complete
+				let (id, result) = receiver.recv()?;
+				running -= 1;
+				match result {
+					Ok(worker) => workers.push(worker),
+					Err(error) => failures.push((id, error)),
+				}
 			}
 
-			// Wait for each build to complete
-			for handle in handles {
-				workers.push(handle.join().unwrap());
+			// Workers complete in any order
+			workers.sort_by_key(|worker| worker.id);
+		}
+
+		if failures.len() > 0 {
+			for (id, error) in &failures {
+				if self.cancel.is_cancelled() {
+					error!("Worker {} was cancelled: {}", id, error);
+				} else {
+					error!("Worker {} failed: {}", id, error);
+				}
 			}
+			self.cancel.check()?;
+			bail!("Build failed");
 		}
 
 		let final_qcow = if workers.len() > 1 {
@@ -218,23 +577,70 @@ impl BuildJob {
 			Qcow3::open(&workers[0].image_path)?
 		};
 
+		// Combine the software inventories from each worker
+		let mut documents = Vec::new();
+		for worker in &workers {
+			if let Ok(content) = std::fs::read(worker.sbom_path()) {
+				documents.push(serde_json::from_slice(&content)?);
+			}
+		}
+		let sbom = sbom::merge(documents);
+
 		// Convert into final immutable image
-		ImageHandle::convert(&final_qcow, self.config.clone(), &self.image_path)?;
+		let image = ImageHandle::convert(&final_qcow, self.config.clone(), sbom, &self.image_path)?;
+
+		let mut env = self.hook_env();
+		env.push(("GOLDBOOT_IMAGE_PATH", self.image_path.clone()));
+		env.push(("GOLDBOOT_IMAGE_ID", image.id.clone()));
+		hooks.run(HookStage::PostConvert, env, &self.cancel)?;
 
-		if let Some(output) = output {
+		let image_path = if let Some(output) = output {
 			// Move the image to output
 			std::fs::copy(&self.image_path, &output)?;
+			output
 		} else {
 			// Move the image to the library
-			ImageLibrary::add(&self.image_path)?;
+			ImageLibrary::add(&self.image_path)?
+				.to_string_lossy()
+				.to_string()
+		};
+
+		if let Some(config) = &self.config.tpm {
+			if config.keep_state {
+				for worker in &workers {
+					tpm::save_state(
+						&worker.tpm_path(),
+						&Path::new(&format!("{}.tpm", image_path)).join(worker.id.to_string()),
+					)?;
+				}
+			}
 		}
 
+		if self.config.keep_vars == Some(true) {
+			let dest = PathBuf::from(format!("{}.vars", image_path));
+			std::fs::create_dir_all(&dest)?;
+			for worker in &workers {
+				std::fs::copy(
+					&worker.ovmf_vars_path,
+					dest.join(format!("{}.fd", worker.id)),
+				)?;
+			}
+			info!("Saved EFI variables to: {}", dest.display());
+		}
+
+		let mut env = self.hook_env();
+		env.push(("GOLDBOOT_IMAGE_PATH", image_path));
+		env.push(("GOLDBOOT_IMAGE_ID", image.id.clone()));
+		hooks.run(HookStage::PostBuild, env, &self.cancel)?;
+
 		info!(
 			"Build completed in: {:?}",
 			self.start_time.unwrap().elapsed()?
 		);
 		self.end_time = Some(SystemTime::now());
 
+		events::emit(BuildEvent::BuildCompleted { image_id: image.id });
+
 		Ok(())
 	}
 }
@@ -242,8 +648,14 @@ impl BuildJob {
 /// Represents a template build process. Multiple workers can run in parallel
 /// to speed up multiboot configurations.
 pub struct BuildWorker {
+	/// The worker's index within the build job
+	pub id: usize,
+
 	/// A general purpose temporary directory for the run
-	pub tmp: tempfile::TempDir,
+	pub tmp: PathBuf,
+
+	/// Removes the temporary directory when the worker is dropped
+	pub tmp_dir: Option<tempfile::TempDir>,
 
 	/// The path to the intermediate image artifact
 	pub image_path: String,
@@ -254,26 +666,90 @@ pub struct BuildWorker {
 	/// The VM port for VNC
 	pub vnc_port: u16,
 
+	/// Keeps the worker's ports reserved until it's dropped
+	pub ports: Vec<PortLease>,
+
+	/// The number of vCPUs to give the VM
+	pub cpus: usize,
+
+	/// The amount of memory to give the VM in bytes
+	pub memory: u64,
+
 	/// The build config
 	pub config: BuildConfig,
 
 	pub template: Box<dyn Template>,
 
-	/// The path to an OVMF.fd file
+	/// The path to the UEFI firmware code
 	pub ovmf_path: String,
 
+	/// The path to the worker's EFI variable store
+	pub ovmf_vars_path: String,
+
 	/// Whether screenshots will be generated during the run for debugging
 	pub record: bool,
 
+	/// Whether each worker's whole session is recorded to an animated PNG
+	pub timeline: bool,
+
+	/// Where diagnostics and timelines are written
+	pub artifacts: PathBuf,
+
 	/// When set, the run will pause before each step in the boot sequence
 	pub debug: bool,
+
+	/// Stops the worker when triggered
+	pub cancel: CancelToken,
+
+	/// Starts the worker's VM in place of QEMU (i.e. a test harness)
+	pub launcher: Option<Launcher>,
 }
 
 unsafe impl Send for BuildWorker {}
 
 impl BuildWorker {
+	/// Describe a worker without reserving anything on the host. Nothing is
+	/// created in the given temporary directory and ports are left unassigned.
+	pub fn plan(
+		id: usize,
+		config: BuildConfig,
+		template: Box<dyn Template>,
+		schedule: &Schedule,
+		tmp: PathBuf,
+	) -> BuildWorker {
+		let path = |name: &str| tmp.join(name).to_string_lossy().to_string();
+
+		BuildWorker {
+			id,
+			image_path: path("image.qcow2"),
+			ovmf_path: path("OVMF_CODE.fd"),
+			ovmf_vars_path: path("OVMF_VARS.fd"),
+			tmp_dir: None,
+			template,
+			ssh_port: 0,
+			vnc_port: 0,
+			ports: vec![],
+			cpus: schedule.cpus,
+			memory: schedule.memory,
+			config,
+			record: false,
+			timeline: false,
+			artifacts: PathBuf::from("artifacts"),
+			debug: false,
+			cancel: CancelToken::new(None),
+			launcher: None,
+			tmp,
+		}
+	}
+
 	/// Run the template build.
 	pub fn run(&self) -> Result<(), Box<dyn Error>> {
+		events::emit(BuildEvent::WorkerStarted {
+			worker: self.id,
+			ssh_port: self.ssh_port,
+			vnc_port: self.vnc_port,
+		});
+
 		debug!(
 			"Allocating new {} image: {}",
 			self.template.general().storage_size,
@@ -284,7 +760,191 @@ impl BuildWorker {
 			self.template.general().storage_size_bytes(),
 		)?;
 
+		self.cancel.check()?;
 		self.template.build(&self)?;
 		Ok(())
 	}
+
+	/// The environment variables given to the worker's hooks.
+	pub fn hook_env(&self) -> Vec<(&'static str, String)> {
+		vec![
+			("GOLDBOOT_IMAGE_NAME", self.config.name.clone()),
+			("GOLDBOOT_WORKER", self.id.to_string()),
+			("GOLDBOOT_SSH_PORT", self.ssh_port.to_string()),
+			("GOLDBOOT_QCOW2_PATH", self.image_path.clone()),
+		]
+	}
+
+	/// The directory where the worker's TPM keeps its state.
+	pub fn tpm_path(&self) -> PathBuf {
+		self.tmp.join("tpm")
+	}
+
+	/// The path where the worker's software inventory is stored.
+	pub fn sbom_path(&self) -> PathBuf {
+		self.tmp.join("sbom.json")
+	}
+
+	/// Capture the guest's installed packages. This should be called after
+	/// provisioning while the SSH connection is still open. Failures are not
+	/// fatal since the inventory is informational.
+	pub fn capture_sbom(&self, ssh: &mut SshConnection) {
+		match sbom::capture(ssh, &self.config.name) {
+			Ok(Some(document)) => {
+				if let Err(error) = std::fs::write(self.sbom_path(), document.to_string()) {
+					warn!("Failed to store software inventory: {}", error);
+				}
+			}
+			Ok(None) => warn!("No supported package manager found in the guest"),
+			Err(error) => warn!("Failed to capture software inventory: {}", error),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::variables::VariableDefinition;
+	use serde_json::json;
+
+	#[test]
+	fn test_firmware() {
+		let mut config = BuildConfig::default();
+		assert_eq!(config.firmware(), Firmware::Uefi);
+
+		// The hardware profile's firmware is used unless one is given
+		config.hardware = Some(HardwareProfile {
+			firmware: Firmware::Bios,
+			..Default::default()
+		});
+		assert_eq!(config.firmware(), Firmware::Bios);
+
+		config.firmware = Some(Firmware::Uefi);
+		assert_eq!(config.firmware(), Firmware::Uefi);
+	}
+
+	/// A template with the given capabilities.
+	#[derive(Validate)]
+	struct Capabilities {
+		bios: bool,
+		shares: bool,
+	}
+
+	impl Template for Capabilities {
+		fn build(&self, _: &BuildWorker) -> Result<(), Box<dyn Error>> {
+			Ok(())
+		}
+
+		fn supports_bios(&self) -> bool {
+			self.bios
+		}
+
+		fn supports_shares(&self) -> bool {
+			self.shares
+		}
+	}
+
+	#[test]
+	fn test_check_template() -> Result<(), Box<dyn Error>> {
+		let none = Capabilities {
+			bios: false,
+			shares: false,
+		};
+		let all = Capabilities {
+			bios: true,
+			shares: true,
+		};
+
+		let mut config = BuildConfig::default();
+		config.check_template(&none)?;
+
+		config.firmware = Some(Firmware::Bios);
+		assert!(config.check_template(&none).is_err());
+		config.check_template(&all)?;
+
+		config.firmware = None;
+		config.shares = Some(vec![ShareConfig {
+			source: String::from("/srv/repo"),
+			target: String::from("/mnt/repo"),
+			readonly: true,
+			driver: Default::default(),
+		}]);
+		assert!(config.check_template(&none).is_err());
+		config.check_template(&all)?;
+		Ok(())
+	}
+
+	#[test]
+	fn test_config_formats() -> Result<(), Box<dyn Error>> {
+		let config = BuildConfig {
+			name: String::from("Test"),
+			description: Some(String::from("A test config")),
+			arch: Architecture::arm64,
+			memory: Some(String::from("4 GiB")),
+			timeout: Some(3600),
+			variables: Some(BTreeMap::from([
+				(String::from("user"), Variable::Value(String::from("admin"))),
+				(
+					String::from("password"),
+					Variable::Definition(VariableDefinition {
+						value: Some(String::from("1234")),
+						secret: true,
+						description: None,
+					}),
+				),
+			])),
+			templates: vec![json!({
+				"base": "Arch",
+				"hostname": "test",
+				"packages": ["git", "vim"],
+			})],
+			..Default::default()
+		};
+
+		for format in ConfigFormat::iter() {
+			let content = format.to_string(&config)?;
+			let parsed: BuildConfig = serde_json::from_value(format.parse(content.as_bytes())?)?;
+			assert_eq!(
+				serde_json::to_value(&parsed)?,
+				serde_json::to_value(&config)?,
+				"{:?} round trip",
+				format
+			);
+			assert_eq!(
+				ConfigFormat::from_path(Path::new(format.filename()))?,
+				format
+			);
+		}
+		assert!(ConfigFormat::from_path(Path::new("goldboot.ini")).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_schema() -> Result<(), Box<dyn Error>> {
+		let schema = serde_json::to_value(BuildConfig::schema())?;
+		let properties = schema["properties"].as_object().unwrap();
+
+		for field in [
+			"firmware",
+			"hardware",
+			"hooks",
+			"keep_vars",
+			"network",
+			"secure_boot",
+			"shares",
+			"test",
+			"timeout",
+			"tpm",
+			"variables",
+			"wait_timeout",
+		] {
+			assert!(properties.contains_key(field), "missing {}", field);
+		}
+		assert!(!properties.contains_key("redactions"));
+		assert!(!schema["properties"]["templates"]["items"]["oneOf"]
+			.as_array()
+			.unwrap()
+			.is_empty());
+		Ok(())
+	}
 }
@@ -1,4 +1,7 @@
-use crate::progress::ProgressBar;
+use crate::{
+	events::{self, BuildEvent},
+	progress::ProgressBar,
+};
 use log::{debug, info};
 use sha1::{Digest, Sha1};
 use sha2::{Sha256, Sha512};
@@ -39,6 +42,7 @@ impl MediaCache {
 			}
 
 			// Try to download it
+			events::emit(BuildEvent::DownloadStarted { url: url.clone() });
 			let rs = reqwest::blocking::get(&url)?;
 			if rs.status().is_success() {
 				let length = rs.content_length().ok_or("Failed to get content length")?;
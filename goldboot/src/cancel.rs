@@ -0,0 +1,97 @@
+use simple_error::bail;
+use std::{
+	error::Error,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	time::{Duration, Instant},
+};
+
+/// How often long-running waits check for cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A cheaply cloneable handle used to stop a running build. A build is
+/// cancelled when the token is triggered explicitly (i.e. by a signal) or when
+/// its deadline passes.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+	cancelled: Arc<AtomicBool>,
+	deadline: Option<Instant>,
+}
+
+impl CancelToken {
+	/// Create a new token which expires after the given timeout (if any).
+	pub fn new(timeout: Option<Duration>) -> Self {
+		Self {
+			cancelled: Arc::new(AtomicBool::new(false)),
+			deadline: timeout.map(|timeout| Instant::now() + timeout),
+		}
+	}
+
+	/// Cancel everything that holds this token.
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::SeqCst);
+	}
+
+	/// Whether the token was cancelled or its deadline has passed.
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::SeqCst) || self.is_expired()
+	}
+
+	fn is_expired(&self) -> bool {
+		match self.deadline {
+			Some(deadline) => Instant::now() >= deadline,
+			None => false,
+		}
+	}
+
+	/// Return an error if the token has been cancelled.
+	pub fn check(&self) -> Result<(), Box<dyn Error>> {
+		if self.cancelled.load(Ordering::SeqCst) {
+			bail!("Build cancelled");
+		}
+		if self.is_expired() {
+			bail!("Build timed out");
+		}
+		Ok(())
+	}
+
+	/// Sleep for the given duration, returning early with an error if the
+	/// token is cancelled in the meantime.
+	pub fn sleep(&self, duration: Duration) -> Result<(), Box<dyn Error>> {
+		let end = Instant::now() + duration;
+		loop {
+			self.check()?;
+
+			let now = Instant::now();
+			if now >= end {
+				return Ok(());
+			}
+			std::thread::sleep(POLL_INTERVAL.min(end - now));
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_cancel_interrupts_sleep() {
+		let token = CancelToken::new(None);
+		assert!(token.sleep(Duration::from_millis(10)).is_ok());
+
+		token.clone().cancel();
+		assert!(token.is_cancelled());
+		assert!(token.sleep(Duration::from_secs(60)).is_err());
+	}
+
+	#[test]
+	fn test_deadline() {
+		let token = CancelToken::new(Some(Duration::from_millis(50)));
+		assert!(!token.is_cancelled());
+		assert!(token.sleep(Duration::from_secs(60)).is_err());
+		assert!(token.is_cancelled());
+	}
+}
@@ -4,29 +4,68 @@ use crate::{
 };
 use console::Style;
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
-use log::debug;
-use std::error::Error;
+use log::{debug, warn};
+use simple_error::bail;
+use std::{
+	collections::BTreeMap,
+	error::Error,
+	path::{Path, PathBuf},
+};
 use validator::Validate;
 
+/// Load the build config from the given path (or the current directory) and
+/// resolve its variables.
+pub fn load_config(
+	config: Option<String>,
+	variables: Vec<String>,
+	var_file: Option<String>,
+) -> Result<BuildConfig, Box<dyn Error>> {
+	let config_path = if let Some(path) = config {
+		PathBuf::from(path)
+	} else if let Some(path) = BuildConfig::find(Path::new(".")) {
+		path
+	} else {
+		bail!("No goldboot config found in the current directory");
+	};
+	debug!("Loading config from {}", config_path.display());
+
+	// Collect variable values with the command line taking precedence
+	let mut overrides: BTreeMap<String, String> = match var_file {
+		Some(path) => serde_json::from_value(BuildConfig::read(Path::new(&path))?)?,
+		None => BTreeMap::new(),
+	};
+	for assignment in variables {
+		let (key, value) = crate::variables::parse_assignment(&assignment)?;
+		overrides.insert(key, value);
+	}
+
+	let config = crate::variables::load(BuildConfig::read(&config_path)?, &overrides)?;
+	debug!("Loaded: {:#?}", crate::variables::redact(&config)?);
+
+	Ok(config)
+}
+
 pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
 	match cmd {
 		Commands::Build {
 			record,
+			timeline,
+			artifacts,
 			debug,
 			read_password,
 			output,
 			config,
+			events,
+			variables,
+			var_file,
+			jobs,
 		} => {
-			let config_path = if let Some(path) = config.to_owned() {
-				path
-			} else {
-				String::from("./goldboot.json")
-			};
-			debug!("Loading config from {}", config_path);
+			if let Some(format) = events {
+				crate::events::init(format);
+			}
 
 			// Load build config from current directory
-			let mut config: BuildConfig = serde_json::from_slice(&std::fs::read(config_path)?)?;
-			debug!("Loaded: {:#?}", &config);
+			let mut config = load_config(config, variables, var_file)?;
 
 			// Include the encryption password if provided
 			if read_password {
@@ -44,7 +83,25 @@ pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
 			config.validate()?;
 
 			// Run the build finally
-			let mut job = BuildJob::new(config, record, debug);
+			let mut job = BuildJob::new(config, record, debug, jobs);
+			job.timeline = timeline;
+			job.artifacts = match (artifacts, &output) {
+				(Some(artifacts), _) => PathBuf::from(artifacts),
+				(None, Some(output)) => PathBuf::from(format!("{}.artifacts", output)),
+				(None, None) => PathBuf::from("artifacts"),
+			};
+
+			// Cancel the build on SIGINT/SIGTERM so the VMs are cleaned up
+			let cancel = job.cancel.clone();
+			ctrlc::set_handler(move || {
+				if cancel.is_cancelled() {
+					// The user really wants to quit
+					std::process::exit(130);
+				}
+				warn!("Cancelling build (interrupt again to exit immediately)");
+				cancel.cancel();
+			})?;
+
 			job.run(output.to_owned())
 		}
 		_ => panic!(),
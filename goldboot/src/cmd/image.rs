@@ -1,13 +1,27 @@
 use crate::{
 	cmd::{Commands, ImageCommands},
+	image::ImageHandle,
 	library::ImageLibrary,
 };
 use chrono::TimeZone;
 use console::Style;
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
-use std::error::Error;
+use simple_error::bail;
+use std::{error::Error, path::Path};
 use ubyte::ToByteUnit;
 
+/// Open an image by path if it exists, otherwise search the library by ID.
+pub(crate) fn open_image(image: &str) -> Result<ImageHandle, Box<dyn Error>> {
+	let mut image = if Path::new(image).is_file() {
+		ImageHandle::open(image)?
+	} else {
+		ImageLibrary::find_by_id(image)?
+	};
+
+	image.load(std::env::var("GOLDBOOT_PASSWORD").ok())?;
+	Ok(image)
+}
+
 pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
 	match cmd {
 		Commands::Image { command } => match &command {
@@ -37,6 +51,34 @@ pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
 
 				Ok(())
 			}
+			ImageCommands::Compare { first, second } => {
+				let first = open_image(first)?;
+				let second = open_image(second)?;
+
+				let differences = first.compare(&second)?;
+				if differences.len() == 0 {
+					println!("Images are identical ({})", &first.id[0..12]);
+				} else {
+					println!(
+						"Images {} and {} differ:",
+						&first.id[0..12],
+						&second.id[0..12]
+					);
+					for difference in differences {
+						println!("  - {}", difference);
+					}
+				}
+				Ok(())
+			}
+			ImageCommands::Sbom { image } => {
+				let image = open_image(image)?;
+
+				match &image.sbom {
+					Some(sbom) => println!("{}", serde_json::to_string_pretty(sbom)?),
+					None => bail!("Image does not contain a software inventory"),
+				}
+				Ok(())
+			}
 		},
 		_ => panic!(),
 	}
@@ -1,6 +1,7 @@
 use crate::{
 	build::BuildConfig,
 	cmd::Commands,
+	hardware::HardwareProfile,
 	templates::{Template, TemplateId},
 	Architecture,
 };
@@ -33,11 +34,13 @@ pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
 			name,
 			template,
 			mimic_hardware,
+			format,
 		} => {
-			let config_path = Path::new("goldboot.json");
-
-			if config_path.exists() {
-				bail!("This directory has already been initialized. Delete goldboot.json to reinitialize.");
+			if let Some(config_path) = BuildConfig::find(Path::new(".")) {
+				bail!(
+					"This directory has already been initialized. Delete {} to reinitialize.",
+					config_path.display()
+				);
 			}
 
 			// Build a new default config that we'll override
@@ -64,9 +67,6 @@ pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
 						bail!("Template not found");
 					}
 				}
-
-			// Generate QEMU flags for this hardware
-			//config.qemuargs = generate_qemuargs()?;
 			} else {
 				// Begin interactive config
 				print_banner();
@@ -131,8 +131,13 @@ pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
 				}
 			}
 
+			// Record this machine's hardware so the build VM can resemble it
+			if mimic_hardware {
+				config.hardware = Some(HardwareProfile::probe()?);
+			}
+
 			// Finally write out the config
-			std::fs::write(config_path, serde_json::to_string_pretty(&config)?)?;
+			std::fs::write(format.filename(), format.to_string(&config)?)?;
 			Ok(())
 		}
 		_ => panic!(),
@@ -1,7 +1,13 @@
+use crate::{build::ConfigFormat, events::EventFormat};
+
 pub mod build;
 pub mod image;
 pub mod init;
+pub mod record;
 pub mod registry;
+pub mod run;
+pub mod schema;
+pub mod validate;
 pub mod write;
 
 #[derive(clap::Subcommand, Debug)]
@@ -13,6 +19,17 @@ pub enum Commands {
 		#[clap(long, takes_value = false)]
 		record: bool,
 
+		/// Record each worker's whole session to an animated PNG in the
+		/// artifacts directory
+		#[clap(long, takes_value = false)]
+		timeline: bool,
+
+		/// Where diagnostics for failed waits and timelines are written
+		/// (defaults to <output>.artifacts, or ./artifacts when building to
+		/// the image library)
+		#[clap(long)]
+		artifacts: Option<String>,
+
 		/// Insert a breakpoint after each boot command
 		#[clap(long, takes_value = false)]
 		debug: bool,
@@ -25,9 +42,27 @@ pub enum Commands {
 		#[clap(long)]
 		output: Option<String>,
 
-		/// The config file path (default: ./goldboot.json)
+		/// The config file path (default: ./goldboot.json, ./goldboot.yaml, or
+		/// ./goldboot.toml)
 		#[clap(long)]
 		config: Option<String>,
+
+		/// Write machine-readable build events to STDOUT in the given format
+		#[clap(long, value_enum)]
+		events: Option<EventFormat>,
+
+		/// Set a config variable (key=value)
+		#[clap(long = "var")]
+		variables: Vec<String>,
+
+		/// A JSON, YAML, or TOML file containing config variable values
+		#[clap(long)]
+		var_file: Option<String>,
+
+		/// The maximum number of workers to run at once (defaults to as many
+		/// as the host's CPUs and memory allow)
+		#[clap(long)]
+		jobs: Option<usize>,
 	},
 
 	/// Manage local images
@@ -65,6 +100,61 @@ pub enum Commands {
 		/// as possible
 		#[clap(long, takes_value = false)]
 		mimic_hardware: bool,
+
+		/// The format of the generated config file
+		#[clap(long, value_enum, default_value_t = ConfigFormat::Json)]
+		format: ConfigFormat,
+	},
+
+	/// Boot installation media behind a VNC proxy and transcribe the session
+	/// into boot commands for a new template
+	Record {
+		/// The installation media
+		#[clap(long)]
+		iso: String,
+
+		/// The architecture of the installation media
+		#[clap(long, default_value = "amd64")]
+		arch: String,
+
+		/// Write the boot commands to this file instead of STDOUT
+		#[clap(long)]
+		output: Option<String>,
+	},
+
+	/// Boot an image in a temporary VM
+	Run {
+		/// The image ID or path
+		image: String,
+	},
+
+	/// Boot an image in a temporary VM and run its tests over SSH
+	Test {
+		/// The image ID or path
+		image: String,
+
+		/// A config file whose tests are run instead of the image's own
+		#[clap(long)]
+		config: Option<String>,
+	},
+
+	/// Print a JSON Schema for build configs
+	Schema {},
+
+	/// Check a build config for problems without building it
+	Validate {
+		/// The config file path (default: ./goldboot.json, ./goldboot.yaml, or
+		/// ./goldboot.toml)
+		#[clap(long)]
+		config: Option<String>,
+
+		/// Set a config variable (key=value)
+		#[clap(long = "var")]
+		variables: Vec<String>,
+
+		/// A JSON, YAML, or TOML file containing config variable values
+		#[clap(long)]
+		var_file: Option<String>,
 	},
 
 	/// Manage image registries
@@ -93,4 +183,19 @@ pub enum ImageCommands {
 
 	/// Get detailed image info
 	Info { image: Option<String> },
+
+	/// Compare two images and explain any differences
+	Compare {
+		/// The first image ID or path
+		first: String,
+
+		/// The second image ID or path
+		second: String,
+	},
+
+	/// Print the software inventory of an image as CycloneDX JSON
+	Sbom {
+		/// The image ID or path
+		image: String,
+	},
 }
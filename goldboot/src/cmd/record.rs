@@ -0,0 +1,26 @@
+use crate::{
+	authoring::{self, RecordJob},
+	cmd::Commands,
+	Architecture,
+};
+use log::info;
+use std::error::Error;
+
+pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
+	match cmd {
+		Commands::Record { iso, arch, output } => {
+			let job = RecordJob::new(&iso, Architecture::try_from(arch)?)?;
+			let source = authoring::to_source(&job.record()?);
+
+			match output {
+				Some(path) => {
+					std::fs::write(&path, source)?;
+					info!("Saved boot commands to: {}", path);
+				}
+				None => print!("{}", source),
+			}
+			Ok(())
+		}
+		_ => panic!(),
+	}
+}
@@ -0,0 +1,49 @@
+use crate::{
+	cmd::{build::load_config, image::open_image, Commands},
+	run::RunJob,
+};
+use log::warn;
+use simple_error::bail;
+use std::error::Error;
+use validator::Validate;
+
+pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
+	match cmd {
+		Commands::Run { image } => {
+			let job = RunJob::new(&open_image(&image)?)?;
+			stop_on_interrupt(&job)?;
+			job.run()
+		}
+		Commands::Test { image, config } => {
+			let image = open_image(&image)?;
+
+			let test = match config {
+				Some(path) => load_config(Some(path), Vec::new(), None)?.test,
+				None => image.config.as_ref().and_then(|config| config.test.clone()),
+			};
+			let test = match test {
+				Some(test) => test,
+				None => bail!("No tests are configured"),
+			};
+			test.validate()?;
+
+			let job = RunJob::new(&image)?;
+			stop_on_interrupt(&job)?;
+			job.test(&test)
+		}
+		_ => panic!(),
+	}
+}
+
+/// Stop the VM on SIGINT/SIGTERM.
+fn stop_on_interrupt(job: &RunJob) -> Result<(), Box<dyn Error>> {
+	let cancel = job.worker.cancel.clone();
+	ctrlc::set_handler(move || {
+		if cancel.is_cancelled() {
+			std::process::exit(130);
+		}
+		warn!("Stopping VM (interrupt again to exit immediately)");
+		cancel.cancel();
+	})?;
+	Ok(())
+}
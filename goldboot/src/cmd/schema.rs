@@ -0,0 +1,12 @@
+use crate::{build::BuildConfig, cmd::Commands};
+use std::error::Error;
+
+pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
+	match cmd {
+		Commands::Schema {} => {
+			println!("{}", serde_json::to_string_pretty(&BuildConfig::schema())?);
+			Ok(())
+		}
+		_ => panic!(),
+	}
+}
@@ -0,0 +1,102 @@
+use crate::{
+	build::BuildWorker,
+	cmd::{build::load_config, Commands},
+	qemu::QemuArgs,
+};
+use console::Style;
+use simple_error::bail;
+use std::{error::Error, path::PathBuf};
+use validator::Validate;
+
+pub fn run(cmd: crate::cmd::Commands) -> Result<(), Box<dyn Error>> {
+	match cmd {
+		Commands::Validate {
+			config,
+			variables,
+			var_file,
+		} => {
+			let config = load_config(config, variables, var_file)?;
+
+			// Collect every problem rather than stopping at the first
+			let mut problems: Vec<String> = Vec::new();
+
+			if let Err(error) = config.validate() {
+				problems.push(format!("config: {}", error));
+			}
+			if let Err(error) = config.check() {
+				problems.push(format!("config: {}", error));
+			}
+
+			let mut templates = Vec::new();
+			for (index, (id, value)) in config
+				.get_template_ids()?
+				.iter()
+				.zip(&config.templates)
+				.enumerate()
+			{
+				if !id.architectures().contains(&config.arch) {
+					problems.push(format!(
+						"template {} ({}): architecture {} is not supported",
+						index, id, config.arch
+					));
+				}
+
+				match id.parse(value.to_owned()) {
+					Ok(template) => {
+						if let Err(error) = template.validate() {
+							problems.push(format!("template {} ({}): {}", index, id, error));
+						}
+						if let Err(error) = template.check() {
+							problems.push(format!("template {} ({}): {}", index, id, error));
+						}
+						if let Err(error) = config.check_template(template.as_ref()) {
+							problems.push(format!("template {} ({}): {}", index, id, error));
+						}
+						templates.push(template);
+					}
+					Err(error) => {
+						problems.push(format!("template {} ({}): {}", index, id, error));
+					}
+				}
+			}
+
+			if problems.len() > 0 {
+				let style = Style::new().red();
+				for problem in &problems {
+					println!("{} {}", style.apply_to("✗"), problem);
+				}
+				bail!("Found {} problem(s) in the config", problems.len());
+			}
+
+			// Show what each worker would run without reserving anything
+			let schedule = config.schedule(None)?;
+			println!(
+				"Up to {} worker(s) will run at once with {} vCPU(s) and {} MiB of memory each",
+				schedule.parallel,
+				schedule.cpus,
+				schedule.memory / 1024 / 1024
+			);
+			println!("Temporary paths and ports are assigned when the build starts");
+			for (id, template) in templates.into_iter().enumerate() {
+				let worker = BuildWorker::plan(
+					id,
+					config.clone(),
+					template,
+					&schedule,
+					PathBuf::from(format!("$TMPDIR/worker{}", id)),
+				);
+				let qemuargs = QemuArgs::new(&worker);
+				println!(
+					"Worker {}: {} {}",
+					id,
+					qemuargs.exe,
+					qemuargs.to_cmdline().join(" ")
+				);
+			}
+
+			println!("{} Config is valid", Style::new().green().apply_to("✓"));
+			Ok(())
+		}
+		_ => panic!(),
+	}
+}
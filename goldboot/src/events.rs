@@ -0,0 +1,141 @@
+//! Machine-readable build events. When enabled, each event is written to
+//! stdout as a single line so CI pipelines can follow a build's progress
+//! without parsing log output.
+
+use serde::Serialize;
+use std::{
+	io::Write,
+	sync::{
+		mpsc::{channel, Receiver, Sender},
+		Mutex,
+	},
+};
+
+/// The output format of the build event stream.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventFormat {
+	/// Newline-delimited JSON
+	Json,
+}
+
+/// The currently configured event format (if any).
+static FORMAT: Mutex<Option<EventFormat>> = Mutex::new(None);
+
+/// Channels which receive a copy of every event.
+static SUBSCRIBERS: Mutex<Vec<Sender<BuildEvent>>> = Mutex::new(Vec::new());
+
+/// Represents something notable that happened during a build.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum BuildEvent {
+	/// A build worker has started
+	WorkerStarted {
+		worker: usize,
+		ssh_port: u16,
+		vnc_port: u16,
+	},
+
+	/// Installation media is being downloaded
+	DownloadStarted { url: String },
+
+	/// Progress of the current download
+	DownloadProgress { bytes: u64, total: u64 },
+
+	/// A boot command step is about to be executed
+	BootCommandStep {
+		worker: usize,
+		step: usize,
+		total: usize,
+	},
+
+	/// An SSH connection to the VM was established
+	SshConnected { worker: usize, port: u16 },
+
+	/// A provisioner is about to run
+	ProvisionerStarted {
+		worker: usize,
+		index: usize,
+		provisioner: String,
+	},
+
+	/// A provisioner has finished running
+	ProvisionerFinished {
+		worker: usize,
+		index: usize,
+		provisioner: String,
+		exit_code: i32,
+	},
+
+	/// A host hook is about to run
+	HookStarted { hook: String, index: usize },
+
+	/// A host hook has finished running
+	HookFinished {
+		hook: String,
+		index: usize,
+		exit_code: i32,
+	},
+
+	/// Progress of the conversion into the final image
+	ConvertProgress { bytes: u64, total: u64 },
+
+	/// The build finished and produced the given image
+	BuildCompleted { image_id: String },
+}
+
+/// Enable the event stream with the given format.
+pub fn init(format: EventFormat) {
+	*FORMAT.lock().unwrap() = Some(format);
+}
+
+/// Whether the event stream is enabled.
+pub fn enabled() -> bool {
+	FORMAT.lock().unwrap().is_some()
+}
+
+/// Receive every event emitted from now on (whether or not the event stream is
+/// enabled).
+pub fn subscribe() -> Receiver<BuildEvent> {
+	let (sender, receiver) = channel();
+	SUBSCRIBERS.lock().unwrap().push(sender);
+	receiver
+}
+
+/// Send an event to subscribers and write it to the event stream if it's
+/// enabled.
+pub fn emit(event: BuildEvent) {
+	// Subscribers which hung up are forgotten
+	SUBSCRIBERS
+		.lock()
+		.unwrap()
+		.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+
+	match *FORMAT.lock().unwrap() {
+		Some(EventFormat::Json) => {
+			if let Ok(line) = serde_json::to_string(&event) {
+				let mut stdout = std::io::stdout().lock();
+				writeln!(stdout, "{}", line).unwrap_or_default();
+				stdout.flush().unwrap_or_default();
+			}
+		}
+		None => {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_event_json() -> Result<(), Box<dyn std::error::Error>> {
+		assert_eq!(
+			serde_json::to_string(&BuildEvent::BootCommandStep {
+				worker: 0,
+				step: 3,
+				total: 10,
+			})?,
+			r#"{"event":"boot_command_step","worker":0,"step":3,"total":10}"#
+		);
+		Ok(())
+	}
+}
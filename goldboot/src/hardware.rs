@@ -0,0 +1,348 @@
+//! Hardware profiles describe a physical machine so the VM that builds an image
+//! can resemble it. Drivers and the initramfs generated during installation then
+//! match the hardware that the image will eventually be written to.
+
+use log::debug;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::{error::Error, fs, path::Path};
+use strum::Display;
+
+/// The storage bus of the machine's primary disk.
+#[derive(Clone, Copy, Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskBus {
+	#[default]
+	Virtio,
+	Sata,
+	Nvme,
+}
+
+impl DiskBus {
+	/// The guest's name for a disk attached to this bus.
+	pub fn device(&self) -> &'static str {
+		match self {
+			DiskBus::Virtio => "/dev/vda",
+			DiskBus::Sata => "/dev/sda",
+			DiskBus::Nvme => "/dev/nvme0n1",
+		}
+	}
+
+	/// The guest's name for a partition on the disk.
+	pub fn partition(&self, number: u32) -> String {
+		match self {
+			DiskBus::Nvme => format!("{}p{}", self.device(), number),
+			_ => format!("{}{}", self.device(), number),
+		}
+	}
+
+	/// Point a Debian preseed file at the disk.
+	pub fn preseed(&self, preseed: Vec<u8>) -> Vec<u8> {
+		String::from_utf8_lossy(&preseed)
+			.replace(
+				"d-i partman-auto/disk string /dev/vda\n",
+				&format!("d-i partman-auto/disk string {}\n", self.device()),
+			)
+			.into_bytes()
+	}
+}
+
+/// The type of firmware the machine boots with.
+#[derive(
+	Clone, Copy, Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq, Eq, Display,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum Firmware {
+	#[default]
+	Uefi,
+	Bios,
+}
+
+/// SMBIOS strings as reported by the kernel in `/sys/class/dmi/id`. Serial
+/// numbers and UUIDs are deliberately excluded.
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq, Eq)]
+pub struct Smbios {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub bios_vendor: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub bios_version: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub bios_date: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub sys_vendor: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub product_name: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub product_version: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub product_family: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub product_sku: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub board_vendor: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub board_name: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub board_version: Option<String>,
+}
+
+impl Smbios {
+	fn probe(root: &Path) -> Self {
+		let read = |name: &str| read_value(&root.join("sys/class/dmi/id").join(name));
+		Self {
+			bios_vendor: read("bios_vendor"),
+			bios_version: read("bios_version"),
+			bios_date: read("bios_date"),
+			sys_vendor: read("sys_vendor"),
+			product_name: read("product_name"),
+			product_version: read("product_version"),
+			product_family: read("product_family"),
+			product_sku: read("product_sku"),
+			board_vendor: read("board_vendor"),
+			board_name: read("board_name"),
+			board_version: read("board_version"),
+		}
+	}
+
+	/// The values for QEMU's `-smbios` options (one per SMBIOS table).
+	pub fn to_qemu(&self) -> Vec<String> {
+		[
+			(
+				0,
+				vec![
+					("vendor", &self.bios_vendor),
+					("version", &self.bios_version),
+					("date", &self.bios_date),
+				],
+			),
+			(
+				1,
+				vec![
+					("manufacturer", &self.sys_vendor),
+					("product", &self.product_name),
+					("version", &self.product_version),
+					("family", &self.product_family),
+					("sku", &self.product_sku),
+				],
+			),
+			(
+				2,
+				vec![
+					("manufacturer", &self.board_vendor),
+					("product", &self.board_name),
+					("version", &self.board_version),
+				],
+			),
+		]
+		.into_iter()
+		.filter_map(|(table, fields)| {
+			let fields: Vec<String> = fields
+				.into_iter()
+				.filter_map(|(key, value)| {
+					// Commas are escaped by doubling them
+					value
+						.as_ref()
+						.map(|value| format!("{}={}", key, value.replace(',', ",,")))
+				})
+				.collect();
+
+			if fields.is_empty() {
+				None
+			} else {
+				Some(format!("type={},{}", table, fields.join(",")))
+			}
+		})
+		.collect()
+	}
+}
+
+/// Describes the hardware that an image is intended for.
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq, Eq)]
+pub struct HardwareProfile {
+	#[serde(default)]
+	pub smbios: Smbios,
+
+	/// The CPU vendor (i.e. "GenuineIntel")
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub cpu_vendor: Option<String>,
+
+	/// The CPU model name (i.e. "Intel(R) Core(TM) i7-8650U CPU @ 1.90GHz")
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub cpu_model: Option<String>,
+
+	#[serde(default)]
+	pub disk: DiskBus,
+
+	/// The QEMU device which most closely matches the primary network adapter
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub nic: Option<String>,
+
+	#[serde(default)]
+	pub firmware: Firmware,
+}
+
+impl HardwareProfile {
+	/// Probe the current machine.
+	pub fn probe() -> Result<Self, Box<dyn Error>> {
+		Self::probe_root(Path::new("/"))
+	}
+
+	/// Probe a machine whose `/sys` and `/proc` are under the given root.
+	pub fn probe_root(root: &Path) -> Result<Self, Box<dyn Error>> {
+		let cpuinfo = fs::read_to_string(root.join("proc/cpuinfo")).unwrap_or_default();
+		let cpu_field = |name: &str| {
+			cpuinfo
+				.lines()
+				.filter_map(|line| line.split_once(':'))
+				.find(|(key, _)| key.trim() == name)
+				.map(|(_, value)| value.trim().to_string())
+		};
+
+		let profile = Self {
+			smbios: Smbios::probe(root),
+			cpu_vendor: cpu_field("vendor_id"),
+			cpu_model: cpu_field("model name"),
+			disk: probe_disk(root)?,
+			nic: probe_nic(root)?,
+			firmware: if root.join("sys/firmware/efi").exists() {
+				Firmware::Uefi
+			} else {
+				Firmware::Bios
+			},
+		};
+		debug!("Probed hardware profile: {:?}", profile);
+		Ok(profile)
+	}
+}
+
+/// Read a sysfs attribute, ignoring missing or empty values.
+fn read_value(path: &Path) -> Option<String> {
+	let value = fs::read_to_string(path).ok()?.trim().to_string();
+	if value.is_empty() {
+		None
+	} else {
+		Some(value)
+	}
+}
+
+/// Determine the bus of the first non-removable disk.
+fn probe_disk(root: &Path) -> Result<DiskBus, Box<dyn Error>> {
+	let block = root.join("sys/block");
+	if !block.exists() {
+		return Ok(DiskBus::default());
+	}
+
+	let mut names: Vec<String> = fs::read_dir(&block)?
+		.filter_map(|entry| entry.ok())
+		.map(|entry| entry.file_name().to_string_lossy().to_string())
+		.filter(|name| read_value(&block.join(name).join("removable")).as_deref() != Some("1"))
+		.collect();
+	names.sort();
+
+	for name in names {
+		if name.starts_with("nvme") {
+			return Ok(DiskBus::Nvme);
+		} else if name.starts_with("sd") {
+			return Ok(DiskBus::Sata);
+		} else if name.starts_with("vd") {
+			return Ok(DiskBus::Virtio);
+		}
+	}
+	Ok(DiskBus::default())
+}
+
+/// Find the QEMU device model for the first wired network adapter.
+fn probe_nic(root: &Path) -> Result<Option<String>, Box<dyn Error>> {
+	let net = root.join("sys/class/net");
+	if !net.exists() {
+		return Ok(None);
+	}
+
+	let mut interfaces: Vec<_> = fs::read_dir(&net)?
+		.filter_map(|entry| entry.ok())
+		.map(|entry| entry.path())
+		.filter(|path| !path.join("wireless").exists())
+		.collect();
+	interfaces.sort();
+
+	for interface in interfaces {
+		// Virtual interfaces have no backing device
+		let driver = match fs::read_link(interface.join("device/driver")) {
+			Ok(driver) => driver,
+			Err(_) => continue,
+		};
+
+		let model = match driver.file_name().and_then(|name| name.to_str()) {
+			Some("e1000e") => "e1000e",
+			Some("e1000") => "e1000",
+			Some("igb") => "igb",
+			Some("r8169" | "8139too") => "rtl8139",
+			Some("virtio_net") => "virtio-net",
+			Some("vmxnet3") => "vmxnet3",
+			_ => continue,
+		};
+		return Ok(Some(String::from(model)));
+	}
+	Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_probe() -> Result<(), Box<dyn Error>> {
+		let root = tempfile::tempdir()?;
+		let root = root.path();
+
+		fs::create_dir_all(root.join("sys/class/dmi/id"))?;
+		fs::write(root.join("sys/class/dmi/id/sys_vendor"), "LENOVO\n")?;
+		fs::write(root.join("sys/class/dmi/id/product_name"), "20L5, 20L6\n")?;
+		fs::write(root.join("sys/class/dmi/id/bios_version"), "\n")?;
+
+		fs::create_dir_all(root.join("proc"))?;
+		fs::write(
+			root.join("proc/cpuinfo"),
+			"processor\t: 0\nvendor_id\t: GenuineIntel\nmodel name\t: Intel(R) Core(TM) i7-8650U\n",
+		)?;
+
+		fs::create_dir_all(root.join("sys/block/nvme0n1"))?;
+		fs::write(root.join("sys/block/nvme0n1/removable"), "0\n")?;
+		fs::create_dir_all(root.join("sys/block/sda"))?;
+		fs::write(root.join("sys/block/sda/removable"), "1\n")?;
+
+		fs::create_dir_all(root.join("sys/bus/pci/drivers/e1000e"))?;
+		fs::create_dir_all(root.join("sys/class/net/enp0s31f6/device"))?;
+		std::os::unix::fs::symlink(
+			root.join("sys/bus/pci/drivers/e1000e"),
+			root.join("sys/class/net/enp0s31f6/device/driver"),
+		)?;
+		fs::create_dir_all(root.join("sys/class/net/lo"))?;
+
+		let profile = HardwareProfile::probe_root(root)?;
+		assert_eq!(profile.cpu_vendor.as_deref(), Some("GenuineIntel"));
+		assert_eq!(profile.disk, DiskBus::Nvme);
+		assert_eq!(profile.nic.as_deref(), Some("e1000e"));
+		assert_eq!(profile.firmware, Firmware::Bios);
+		assert_eq!(profile.firmware.to_string(), "bios");
+		assert_eq!(
+			profile.smbios.to_qemu(),
+			vec![String::from(
+				"type=1,manufacturer=LENOVO,product=20L5,, 20L6"
+			)]
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_disk_names() {
+		assert_eq!(DiskBus::Virtio.partition(3), "/dev/vda3");
+		assert_eq!(DiskBus::Sata.device(), "/dev/sda");
+		assert_eq!(DiskBus::Nvme.partition(1), "/dev/nvme0n1p1");
+		assert_eq!(
+			DiskBus::Nvme.preseed(b"d-i partman-auto/disk string /dev/vda\n".to_vec()),
+			b"d-i partman-auto/disk string /dev/nvme0n1\n"
+		);
+	}
+}
@@ -0,0 +1,200 @@
+//! A test harness which stands in for a running VM. The fake VNC server serves
+//! canned framebuffers and records key events, and the fake SSH server records
+//! uploaded scripts and executed commands while returning scripted output. This
+//! allows boot commands and provisioners to be tested without QEMU or any
+//! installation media. Template builds can run against the harness by giving
+//! the build worker its launcher, with the fake serial console scripted for
+//! templates that install over it.
+
+use crate::{
+	cancel::CancelToken,
+	qemu::{Launcher, QemuProcess},
+	ssh::SshConnection,
+	vnc::VncConnection,
+};
+use std::{
+	error::Error,
+	path::Path,
+	process::{Command, Stdio},
+	sync::Arc,
+};
+
+pub mod qemu;
+pub mod ssh;
+pub mod vnc;
+
+pub use self::{
+	qemu::{FakeQmpServer, FakeSerialServer},
+	ssh::{Execution, FakeSshServer, Response},
+	vnc::{blank_screen, FakeVncServer, KeyEvent},
+};
+
+/// A fake guest reachable over VNC and SSH.
+pub struct Harness {
+	pub vnc: FakeVncServer,
+	pub ssh: Arc<FakeSshServer>,
+
+	/// The serial console of VMs started by the launcher
+	pub serial: FakeSerialServer,
+
+	pub cancel: CancelToken,
+}
+
+impl Harness {
+	pub fn new(screens: Vec<crate::vnc::VncScreenshot>) -> Result<Self, Box<dyn Error>> {
+		Ok(Self {
+			vnc: FakeVncServer::start(screens)?,
+			ssh: Arc::new(FakeSshServer::start("root", "root")?),
+			serial: FakeSerialServer::default(),
+			cancel: CancelToken::new(None),
+		})
+	}
+
+	/// Connect to the fake VNC server as a build worker would.
+	pub fn vnc_connection(&self) -> Result<VncConnection, Box<dyn Error>> {
+		VncConnection::new(
+			"127.0.0.1",
+			self.vnc.port,
+			0,
+			false,
+			false,
+			self.cancel.clone(),
+		)
+	}
+
+	/// Start VMs against the harness instead of QEMU. The stand-in process exits
+	/// once a command starting with the given shutdown command is executed over
+	/// SSH. Since the harness can't reproduce real screens, screen waits and
+	/// delays in boot commands pass immediately.
+	pub fn launcher(&self, shutdown: &str) -> Launcher {
+		let ssh = self.ssh.clone();
+		let serial = self.serial.clone();
+		let shutdown = shutdown.to_string();
+
+		Arc::new(move |args| {
+			FakeQmpServer::start(Path::new(&args.qmp))?;
+			serial.listen(Path::new(&args.serial))?;
+
+			let mut process = Command::new("cat")
+				.stdin(Stdio::piped())
+				.stdout(Stdio::null())
+				.spawn()?;
+
+			// Closing its input ends the process like a powered off VM
+			let stdin = process.stdin.take();
+			ssh.on_exec(&shutdown, move || drop(stdin));
+
+			let mut qemu = QemuProcess::connect(args, process)?;
+			qemu.vnc.simulated = true;
+			Ok(qemu)
+		})
+	}
+
+	/// Connect to the fake SSH server as a build worker would.
+	pub fn ssh_connection(&self) -> Result<SshConnection, Box<dyn Error>> {
+		SshConnection::new(
+			self.ssh.port,
+			&self.ssh.username,
+			&self.ssh.password,
+			self.cancel.clone(),
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		provisioners::{ExecutableProvisioner, ShellProvisioner},
+		sbom,
+		vnc::{VncCmd, VncScreenshot},
+	};
+
+	#[test]
+	fn test_boot_command() -> Result<(), Box<dyn Error>> {
+		// The screen after the first Enter press differs from the initial one
+		let installer = VncScreenshot {
+			data: vec![1u8; 320 * 240],
+			width: 320,
+			height: 240,
+		};
+		let harness = Harness::new(vec![blank_screen(640, 480), installer.clone()])?;
+
+		let mut vnc = harness.vnc_connection()?;
+		vnc.boot_command(vec![
+			vec![VncCmd::Type(String::from("boot: Linux\n"))],
+			vec![VncCmd::WaitScreen(installer.hash(), None)],
+			// Key events are handled in order, so waiting for the screen ensures
+			// the server has received all of them
+			vec![
+				VncCmd::Tab,
+				VncCmd::Enter,
+				VncCmd::WaitScreen(installer.hash(), None),
+			],
+		])?;
+
+		assert_eq!(harness.vnc.typed(), "boot: Linux\n\t\n");
+		assert!(harness.vnc.keys().contains(&KeyEvent {
+			down: true,
+			key: 0xffe1,
+		}));
+		Ok(())
+	}
+
+	#[test]
+	fn test_provisioners() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![])?;
+		harness.ssh.respond(
+			"false",
+			Response {
+				exit_code: 3,
+				..Default::default()
+			},
+		);
+
+		let script = tempfile::NamedTempFile::new()?;
+		std::fs::write(script.path(), "#!/bin/sh\necho test\n")?;
+
+		let mut ssh = harness.ssh_connection()?;
+		assert_eq!(ShellProvisioner::new("true").run(&mut ssh)?, 0);
+		assert_eq!(ShellProvisioner::new("false").run(&mut ssh)?, 3);
+		assert_eq!(
+			ExecutableProvisioner {
+				path: script.path().to_string_lossy().to_string(),
+				order: None,
+			}
+			.run(&mut ssh)?,
+			0
+		);
+
+		let executions = harness.ssh.executions();
+		assert_eq!(
+			executions
+				.iter()
+				.map(|execution| execution.command.as_str())
+				.collect::<Vec<&str>>(),
+			vec!["true", "false", "/tmp/tmp.script", "rm -f /tmp/tmp.script"]
+		);
+		assert_eq!(
+			executions[2].script,
+			Some(b"#!/bin/sh\necho test\n".to_vec())
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_sbom_capture() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![])?;
+		harness.ssh.respond(
+			"pacman -Q",
+			Response {
+				stdout: String::from("linux 6.0.1.arch1-1\nopenssh 9.1p1-1\n"),
+				..Default::default()
+			},
+		);
+
+		let document = sbom::capture(&mut harness.ssh_connection()?, "test")?.unwrap();
+		assert_eq!(document["components"].as_array().unwrap().len(), 2);
+		Ok(())
+	}
+}
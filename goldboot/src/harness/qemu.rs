@@ -0,0 +1,175 @@
+//! Stand-ins for the QMP and serial sockets that QEMU would serve.
+
+use serde_json::Value;
+use simple_error::bail;
+use std::{
+	error::Error,
+	io::{BufRead, BufReader, Write},
+	os::unix::net::{UnixListener, UnixStream},
+	path::Path,
+	sync::{Arc, Mutex},
+};
+
+/// A QMP server which accepts every command.
+#[derive(Clone)]
+pub struct FakeQmpServer {
+	commands: Arc<Mutex<Vec<String>>>,
+
+	/// The client's connection, shared for sending events
+	stream: Arc<Mutex<Option<UnixStream>>>,
+}
+
+impl FakeQmpServer {
+	pub fn start(path: &Path) -> Result<Self, Box<dyn Error>> {
+		let listener = UnixListener::bind(path)?;
+		let commands = Arc::new(Mutex::new(Vec::new()));
+		let stream = Arc::new(Mutex::new(None));
+
+		let received = commands.clone();
+		let connected = stream.clone();
+		std::thread::spawn(move || {
+			let mut stream = match listener.accept() {
+				Ok((stream, _)) => stream,
+				Err(_) => return,
+			};
+			let reader = match stream.try_clone() {
+				Ok(reader) => BufReader::new(reader),
+				Err(_) => return,
+			};
+			if stream
+				.write_all(b"{\"QMP\": {\"version\": {}, \"capabilities\": []}}\n")
+				.is_err()
+			{
+				return;
+			}
+			*connected.lock().unwrap() = Some(stream);
+
+			for line in reader.lines() {
+				let request: Value = match line.ok().and_then(|l| serde_json::from_str(&l).ok()) {
+					Some(request) => request,
+					None => break,
+				};
+				if let Some(command) = request["execute"].as_str() {
+					received.lock().unwrap().push(command.to_string());
+				}
+				if let Some(stream) = connected.lock().unwrap().as_mut() {
+					if stream.write_all(b"{\"return\": {}}\n").is_err() {
+						break;
+					}
+				}
+			}
+		});
+
+		Ok(Self { commands, stream })
+	}
+
+	/// Send an asynchronous event (i.e. "RESET") to the client.
+	pub fn event(&self, name: &str) -> Result<(), Box<dyn Error>> {
+		match self.stream.lock().unwrap().as_mut() {
+			Some(stream) => {
+				stream.write_all(
+					format!("{{\"event\": \"{}\", \"data\": {{}}}}\n", name).as_bytes(),
+				)?;
+				Ok(())
+			}
+			None => bail!("No QMP client is connected"),
+		}
+	}
+
+	/// All commands received so far.
+	pub fn commands(&self) -> Vec<String> {
+		self.commands.lock().unwrap().clone()
+	}
+}
+
+#[derive(Default)]
+struct Console {
+	/// Output sent as soon as a client connects
+	greeting: String,
+
+	/// Output by line prefix, checked in order
+	responses: Vec<(String, String)>,
+
+	/// Lines received so far
+	lines: Vec<String>,
+}
+
+/// A serial console which answers the lines written to it with scripted
+/// output. Unless told otherwise it stays silent.
+#[derive(Clone, Default)]
+pub struct FakeSerialServer {
+	console: Arc<Mutex<Console>>,
+}
+
+impl FakeSerialServer {
+	pub fn start(path: &Path) -> Result<Self, Box<dyn Error>> {
+		let serial = Self::default();
+		serial.listen(path)?;
+		Ok(serial)
+	}
+
+	/// Send the given output when a client connects (i.e. a login prompt).
+	pub fn greet(&self, output: &str) {
+		self.console.lock().unwrap().greeting = output.to_string();
+	}
+
+	/// Reply to lines starting with the given prefix. Earlier responses take
+	/// precedence and unmatched lines get no reply.
+	pub fn respond(&self, prefix: &str, output: &str) {
+		self.console
+			.lock()
+			.unwrap()
+			.responses
+			.push((prefix.to_string(), output.to_string()));
+	}
+
+	/// All lines received so far.
+	pub fn lines(&self) -> Vec<String> {
+		self.console.lock().unwrap().lines.clone()
+	}
+
+	/// Serve the console on the given socket.
+	pub fn listen(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+		let listener = UnixListener::bind(path)?;
+		let console = self.console.clone();
+
+		// Hold the connection open until the client goes away
+		std::thread::spawn(move || {
+			let (mut stream, _) = match listener.accept() {
+				Ok(connection) => connection,
+				Err(_) => return,
+			};
+			let reader = match stream.try_clone() {
+				Ok(reader) => BufReader::new(reader),
+				Err(_) => return,
+			};
+			let greeting = console.lock().unwrap().greeting.clone();
+			if stream.write_all(greeting.as_bytes()).is_err() {
+				return;
+			}
+
+			for line in reader.lines() {
+				let line = match line {
+					Ok(line) => line,
+					Err(_) => break,
+				};
+				let response = {
+					let mut console = console.lock().unwrap();
+					let response = console
+						.responses
+						.iter()
+						.find(|(prefix, _)| line.starts_with(prefix))
+						.map(|(_, output)| output.clone());
+					console.lines.push(line);
+					response
+				};
+				if let Some(output) = response {
+					if stream.write_all(output.as_bytes()).is_err() {
+						break;
+					}
+				}
+			}
+		});
+		Ok(())
+	}
+}
@@ -0,0 +1,241 @@
+//! A minimal RFB server which serves canned framebuffers and records the key
+//! events it receives.
+
+use crate::vnc::VncScreenshot;
+use log::{debug, trace};
+use simple_error::bail;
+use std::{
+	collections::VecDeque,
+	error::Error,
+	io::{Read, Write},
+	net::{TcpListener, TcpStream},
+	sync::{Arc, Mutex},
+	thread,
+};
+
+/// The keysym for the return key.
+const XK_RETURN: u32 = 0xff0d;
+
+/// The DesktopSize pseudo-encoding.
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+/// A key event sent by the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+	pub down: bool,
+	pub key: u32,
+}
+
+struct Screen {
+	/// The framebuffer currently being served
+	current: VncScreenshot,
+
+	/// Framebuffers which will be served after each press of the return key
+	queue: VecDeque<VncScreenshot>,
+
+	keys: Vec<KeyEvent>,
+}
+
+/// An RFB server that stands in for QEMU's VNC display. The first screen is
+/// served until the client presses the return key, which advances to the next
+/// queued screen (if any).
+pub struct FakeVncServer {
+	pub port: u16,
+	screen: Arc<Mutex<Screen>>,
+}
+
+impl FakeVncServer {
+	pub fn start(screens: Vec<VncScreenshot>) -> Result<Self, Box<dyn Error>> {
+		let mut queue: VecDeque<VncScreenshot> = screens.into();
+		let current = queue.pop_front().unwrap_or_else(|| blank_screen(640, 480));
+
+		let screen = Arc::new(Mutex::new(Screen {
+			current,
+			queue,
+			keys: Vec::new(),
+		}));
+
+		let listener = TcpListener::bind("127.0.0.1:0")?;
+		let port = listener.local_addr()?.port();
+
+		{
+			let screen = screen.clone();
+			thread::spawn(move || {
+				for stream in listener.incoming().filter_map(|stream| stream.ok()) {
+					let screen = screen.clone();
+					thread::spawn(move || {
+						if let Err(error) = serve(stream, screen) {
+							debug!("Fake VNC client disconnected: {}", error);
+						}
+					});
+				}
+			});
+		}
+
+		Ok(Self { port, screen })
+	}
+
+	/// Queue a screen to be served after the next press of the return key.
+	pub fn push_screen(&self, screenshot: VncScreenshot) {
+		self.screen.lock().unwrap().queue.push_back(screenshot);
+	}
+
+	/// All key events received so far.
+	pub fn keys(&self) -> Vec<KeyEvent> {
+		self.screen.lock().unwrap().keys.clone()
+	}
+
+	/// The text typed so far, reconstructed from key presses. The return key
+	/// becomes a newline and other non-character keys are ignored.
+	pub fn typed(&self) -> String {
+		self.keys()
+			.iter()
+			.filter(|event| event.down)
+			.filter_map(|event| match event.key {
+				XK_RETURN => Some('\n'),
+				0xff09 => Some('\t'),
+				0x0020..=0x007e => char::from_u32(event.key),
+				key if key & 0xff000000 == 0x01000000 => char::from_u32(key & 0x00ffffff),
+				_ => None,
+			})
+			.collect()
+	}
+}
+
+/// Create a uniform black screen of the given size.
+pub fn blank_screen(width: u16, height: u16) -> VncScreenshot {
+	VncScreenshot {
+		data: vec![0u8; width as usize * height as usize],
+		width,
+		height,
+	}
+}
+
+fn read_u8(stream: &mut TcpStream) -> std::io::Result<u8> {
+	let mut buffer = [0u8; 1];
+	stream.read_exact(&mut buffer)?;
+	Ok(buffer[0])
+}
+
+fn read_u16(stream: &mut TcpStream) -> std::io::Result<u16> {
+	let mut buffer = [0u8; 2];
+	stream.read_exact(&mut buffer)?;
+	Ok(u16::from_be_bytes(buffer))
+}
+
+fn read_u32(stream: &mut TcpStream) -> std::io::Result<u32> {
+	let mut buffer = [0u8; 4];
+	stream.read_exact(&mut buffer)?;
+	Ok(u32::from_be_bytes(buffer))
+}
+
+fn skip(stream: &mut TcpStream, count: usize) -> std::io::Result<()> {
+	let mut buffer = vec![0u8; count];
+	stream.read_exact(&mut buffer)
+}
+
+/// Write a framebuffer update header for a single rectangle.
+fn write_rect(
+	stream: &mut TcpStream,
+	width: u16,
+	height: u16,
+	encoding: i32,
+) -> std::io::Result<()> {
+	let mut header = vec![0u8, 0u8];
+	header.extend_from_slice(&1u16.to_be_bytes());
+	header.extend_from_slice(&0u16.to_be_bytes());
+	header.extend_from_slice(&0u16.to_be_bytes());
+	header.extend_from_slice(&width.to_be_bytes());
+	header.extend_from_slice(&height.to_be_bytes());
+	header.extend_from_slice(&encoding.to_be_bytes());
+	stream.write_all(&header)
+}
+
+/// Handle one client connection.
+fn serve(mut stream: TcpStream, screen: Arc<Mutex<Screen>>) -> Result<(), Box<dyn Error>> {
+	// Version handshake
+	stream.write_all(b"RFB 003.008\n")?;
+	skip(&mut stream, 12)?;
+
+	// Offer no authentication
+	stream.write_all(&[1, 1])?;
+	read_u8(&mut stream)?;
+	stream.write_all(&0u32.to_be_bytes())?;
+
+	// ClientInit
+	read_u8(&mut stream)?;
+
+	let (mut width, mut height) = {
+		let screen = screen.lock().unwrap();
+		(screen.current.width, screen.current.height)
+	};
+
+	// ServerInit with the 8-bit pixel format that clients are expected to use
+	let mut init = Vec::new();
+	init.extend_from_slice(&width.to_be_bytes());
+	init.extend_from_slice(&height.to_be_bytes());
+	init.extend_from_slice(&[8, 8, 0, 1, 0, 8, 0, 8, 0, 4, 5, 2, 0, 0, 0, 0]);
+	init.extend_from_slice(&8u32.to_be_bytes());
+	init.extend_from_slice(b"goldboot");
+	stream.write_all(&init)?;
+
+	loop {
+		match read_u8(&mut stream)? {
+			// SetPixelFormat
+			0 => {
+				skip(&mut stream, 3)?;
+				let bits_per_pixel = read_u8(&mut stream)?;
+				skip(&mut stream, 15)?;
+				if bits_per_pixel != 8 {
+					bail!("Unsupported pixel format: {} bpp", bits_per_pixel);
+				}
+			}
+			// SetEncodings
+			2 => {
+				skip(&mut stream, 1)?;
+				let count = read_u16(&mut stream)?;
+				skip(&mut stream, count as usize * 4)?;
+			}
+			// FramebufferUpdateRequest
+			3 => {
+				skip(&mut stream, 9)?;
+
+				let current = screen.lock().unwrap().current.clone();
+
+				if (current.width, current.height) != (width, height) {
+					// Tell the client about the new size first
+					width = current.width;
+					height = current.height;
+					write_rect(&mut stream, width, height, ENCODING_DESKTOP_SIZE)?;
+				} else {
+					write_rect(&mut stream, width, height, 0)?;
+					stream.write_all(&current.data)?;
+				}
+			}
+			// KeyEvent
+			4 => {
+				let down = read_u8(&mut stream)? != 0;
+				skip(&mut stream, 2)?;
+				let key = read_u32(&mut stream)?;
+				trace!("Fake VNC received key: {:#x} (down: {})", key, down);
+
+				let mut screen = screen.lock().unwrap();
+				screen.keys.push(KeyEvent { down, key });
+				if down && key == XK_RETURN {
+					if let Some(next) = screen.queue.pop_front() {
+						screen.current = next;
+					}
+				}
+			}
+			// PointerEvent
+			5 => skip(&mut stream, 5)?,
+			// ClientCutText
+			6 => {
+				skip(&mut stream, 3)?;
+				let length = read_u32(&mut stream)?;
+				skip(&mut stream, length as usize)?;
+			}
+			message => bail!("Unknown client message: {}", message),
+		}
+	}
+}
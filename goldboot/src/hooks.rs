@@ -0,0 +1,142 @@
+//! Host commands which run at fixed points of a build. Hooks are executed with
+//! `sh -c` from the current directory and receive details about the build in
+//! `GOLDBOOT_*` environment variables. A hook that exits with a nonzero code
+//! fails the build.
+
+use crate::{
+	cancel::CancelToken,
+	events::{self, BuildEvent},
+};
+use log::info;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use simple_error::bail;
+use std::{error::Error, process::Command, time::Duration};
+use strum::Display;
+
+/// The points in a build where hooks can run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display)]
+#[strum(serialize_all = "snake_case")]
+pub enum HookStage {
+	/// Before any workers are started
+	PreBuild,
+
+	/// When each worker completes its template (before the VM shuts down)
+	PostWorker,
+
+	/// After the final image has been created
+	PostConvert,
+
+	/// After the image has been moved to its destination
+	PostBuild,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Default, Debug)]
+pub struct Hooks {
+	/// Commands to run before any workers are started
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub pre_build: Vec<String>,
+
+	/// Commands to run when each worker completes, right before its VM is shut
+	/// down. `GOLDBOOT_WORKER`, `GOLDBOOT_SSH_PORT` and `GOLDBOOT_QCOW2_PATH`
+	/// are set.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub post_worker: Vec<String>,
+
+	/// Commands to run after the final image is created.
+	/// `GOLDBOOT_IMAGE_PATH` and `GOLDBOOT_IMAGE_ID` are set.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub post_convert: Vec<String>,
+
+	/// Commands to run after the image is moved to the output path or image
+	/// library. `GOLDBOOT_IMAGE_PATH` and `GOLDBOOT_IMAGE_ID` are set.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub post_build: Vec<String>,
+}
+
+impl Hooks {
+	pub fn get(&self, stage: HookStage) -> &Vec<String> {
+		match stage {
+			HookStage::PreBuild => &self.pre_build,
+			HookStage::PostWorker => &self.post_worker,
+			HookStage::PostConvert => &self.post_convert,
+			HookStage::PostBuild => &self.post_build,
+		}
+	}
+
+	/// Run each command for the given stage in order, stopping at the first
+	/// failure.
+	pub fn run(
+		&self,
+		stage: HookStage,
+		env: Vec<(&str, String)>,
+		cancel: &CancelToken,
+	) -> Result<(), Box<dyn Error>> {
+		for (index, command) in self.get(stage).iter().enumerate() {
+			info!("Running {} hook: {}", stage, command);
+			events::emit(BuildEvent::HookStarted {
+				hook: stage.to_string(),
+				index,
+			});
+
+			let mut child = Command::new("sh")
+				.arg("-c")
+				.arg(command)
+				.env("GOLDBOOT_HOOK", stage.to_string())
+				.envs(env.iter().map(|(key, value)| (key, value)))
+				.spawn()?;
+
+			// Poll so the hook can be stopped if the build is cancelled
+			let status = loop {
+				if let Some(status) = child.try_wait()? {
+					break status;
+				}
+				if let Err(error) = cancel.sleep(Duration::from_millis(500)) {
+					child.kill().unwrap_or_default();
+					child.wait()?;
+					return Err(error);
+				}
+			};
+
+			// No exit code means the hook was killed by a signal
+			let exit_code = status.code().unwrap_or(-1);
+			events::emit(BuildEvent::HookFinished {
+				hook: stage.to_string(),
+				index,
+				exit_code,
+			});
+
+			if exit_code != 0 {
+				bail!("The {} hook failed with exit code: {}", stage, exit_code);
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_run() -> Result<(), Box<dyn Error>> {
+		let hooks = Hooks {
+			post_build: vec![
+				String::from("test \"$GOLDBOOT_HOOK\" = post_build"),
+				String::from("test \"$GOLDBOOT_IMAGE_ID\" = 1234"),
+			],
+			post_convert: vec![String::from("true"), String::from("exit 3")],
+			..Default::default()
+		};
+
+		let cancel = CancelToken::new(None);
+		hooks.run(HookStage::PreBuild, vec![], &cancel)?;
+		hooks.run(
+			HookStage::PostBuild,
+			vec![("GOLDBOOT_IMAGE_ID", String::from("1234"))],
+			&cancel,
+		)?;
+		assert!(hooks.run(HookStage::PostConvert, vec![], &cancel).is_err());
+		Ok(())
+	}
+}
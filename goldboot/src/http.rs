@@ -1,20 +1,24 @@
+use crate::resources::{self, PortLease};
 use log::info;
 use std::{error::Error, io::Write, net::TcpListener};
 
 /// Minimal HTTP server for serving files to virtual machines
 pub struct HttpServer {
 	pub port: u16,
+
+	/// Keeps the port reserved while the server is in use
+	pub lease: PortLease,
 }
 
 impl HttpServer {
 	/// Server a file to all requests.
 	pub fn serve_file(data: Vec<u8>) -> Result<Self, Box<dyn Error>> {
-		let port = crate::find_open_port(8000, 9000);
+		let lease = resources::reserve_port(8000, 9000)?;
+		let port = lease.port;
 		info!("Starting static HTTP server on port: {}", port);
 
+		let listener = TcpListener::bind(format!("0.0.0.0:{port}"))?;
 		std::thread::spawn(move || {
-			let listener = TcpListener::bind(format!("0.0.0.0:{port}")).unwrap();
-
 			for stream in listener.incoming() {
 				let mut stream = stream.unwrap();
 
@@ -29,6 +33,6 @@ impl HttpServer {
 			}
 		});
 
-		Ok(Self { port })
+		Ok(Self { port, lease })
 	}
 }
@@ -1,4 +1,4 @@
-use crate::{build::BuildConfig, progress::ProgressBar, qcow::Qcow3};
+use crate::{build::BuildConfig, progress::ProgressBar, qcow::Qcow3, variables};
 use aes_gcm::{
 	aead::{Aead, NewAead},
 	Aes256Gcm, Key, Nonce,
@@ -30,6 +30,7 @@ use validator::Validate;
 /// | Image Config        | Password + SHA256 |
 /// | Cluster Table       | Cluster Key       |
 /// | Digest Table        | Password + SHA256 |
+/// | Software Inventory  | Password + SHA256 |
 /// | Directory           | Password + SHA256 |
 ///
 /// The target data is divided into equal size sections called "blocks". Blocks
@@ -53,6 +54,9 @@ pub struct ImageHandle {
 	/// The section directory
 	pub directory: Option<Directory>,
 
+	/// The guest's software inventory as a CycloneDX document
+	pub sbom: Option<serde_json::Value>,
+
 	/// The filesystem path to the image file
 	pub path: std::path::PathBuf,
 
@@ -96,6 +100,9 @@ pub enum HeaderEncryptionType {
 	Aes256 = 1,
 }
 
+/// The current image format version. Version 2 added the software inventory.
+const FORMAT_VERSION: u8 = 2;
+
 /// Contains metadata which is always plaintext. Anything potentially useful to
 /// an attacker should instead reside in the protected header unless the user
 /// may want to read it without decrypting the image first.
@@ -103,7 +110,7 @@ pub enum HeaderEncryptionType {
 #[brw(magic = b"\xc0\x1d\xb0\x01", big)]
 pub struct PrimaryHeader {
 	/// The format version
-	#[br(assert(version == 1))]
+	#[br(assert(version >= 1 && version <= FORMAT_VERSION))]
 	pub version: u8,
 
 	/// The total size of all blocks combined in bytes
@@ -163,6 +170,7 @@ pub struct ProtectedHeader {
 
 #[derive(BinRead, BinWrite, Debug)]
 #[brw(big)]
+#[br(import(version: u8))]
 pub struct Directory {
 	/// Protected header nonce
 	pub protected_nonce: [u8; 12],
@@ -187,6 +195,18 @@ pub struct Directory {
 
 	/// The size of the digest table in bytes
 	pub digest_table_size: u32,
+
+	/// The nonce value used to encrypt the software inventory
+	#[br(if(version >= 2))]
+	pub sbom_nonce: [u8; 12],
+
+	/// The byte offset of the software inventory
+	#[br(if(version >= 2))]
+	pub sbom_offset: u64,
+
+	/// The size of the software inventory in bytes (zero if absent)
+	#[br(if(version >= 2))]
+	pub sbom_size: u32,
 }
 
 #[derive(BinRead, BinWrite, Debug, Eq, PartialEq, Clone)]
@@ -242,6 +262,26 @@ pub fn compute_id(path: impl AsRef<Path>) -> Result<String, Box<dyn Error>> {
 	Ok(hex::encode(hasher.finalize()))
 }
 
+/// Generate random key material if the image will be encrypted. Otherwise a
+/// fixed placeholder is returned so identical contents produce identical
+/// images.
+fn key_material<const N: usize>(rng: &mut impl Rng, encrypted: bool) -> [u8; N] {
+	let mut material = [0u8; N];
+	if encrypted {
+		rng.fill_bytes(&mut material);
+	}
+	material
+}
+
+/// Determine the image creation time. If a `SOURCE_DATE_EPOCH` is given, it's
+/// used instead of the current time so builds can be reproduced.
+fn build_timestamp(source_date_epoch: Option<String>) -> Result<u64, Box<dyn Error>> {
+	match source_date_epoch {
+		Some(epoch) => Ok(epoch.trim().parse::<u64>()?),
+		None => Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()),
+	}
+}
+
 impl ImageHandle {
 	/// Load all sections into memory except the cluster table. If the image is
 	/// encrypted, the sections will be decrypted.
@@ -252,8 +292,9 @@ impl ImageHandle {
 
 		// Load the directory first because other sections rely on it
 		file.seek(SeekFrom::Start(self.primary_header.directory_offset))?;
+		let version = self.primary_header.version;
 		let directory: Directory = match self.primary_header.encryption_type {
-			HeaderEncryptionType::None => file.read_be()?,
+			HeaderEncryptionType::None => file.read_be_args((version,))?,
 			HeaderEncryptionType::Aes256 => {
 				let mut directory_bytes = vec![0u8; self.primary_header.directory_size as usize];
 				file.read_exact(&mut directory_bytes)?;
@@ -262,7 +303,7 @@ impl ImageHandle {
 					Nonce::from_slice(&self.primary_header.directory_nonce),
 					directory_bytes.as_ref(),
 				)?;
-				Cursor::new(directory_bytes).read_be()?
+				Cursor::new(directory_bytes).read_be_args((version,))?
 			}
 		};
 
@@ -317,8 +358,29 @@ impl ImageHandle {
 			}
 		};
 
+		// Load the software inventory if there is one
+		let sbom = if directory.sbom_size > 0 {
+			file.seek(SeekFrom::Start(directory.sbom_offset))?;
+			let mut sbom_bytes = vec![0u8; directory.sbom_size as usize];
+			file.read_exact(&mut sbom_bytes)?;
+
+			match self.primary_header.encryption_type {
+				HeaderEncryptionType::None => Some(serde_json::from_slice(&sbom_bytes)?),
+				HeaderEncryptionType::Aes256 => {
+					let sbom_bytes = cipher.decrypt(
+						Nonce::from_slice(&directory.sbom_nonce),
+						sbom_bytes.as_ref(),
+					)?;
+					Some(serde_json::from_slice(&sbom_bytes)?)
+				}
+			}
+		} else {
+			None
+		};
+
 		// Modify the current image handle finally
 		self.directory = Some(directory);
+		self.sbom = sbom;
 		self.protected_header = Some(protected_header);
 		self.digest_table = Some(digest_table);
 		self.config = Some(config);
@@ -353,7 +415,7 @@ impl ImageHandle {
 
 			// Read directory
 			file.seek(SeekFrom::Start(primary_header.directory_offset))?;
-			let directory: Directory = file.read_be()?;
+			let directory: Directory = file.read_be_args((primary_header.version,))?;
 
 			// Read config
 			let mut config_bytes = vec![0u8; directory.config_size as usize];
@@ -368,6 +430,7 @@ impl ImageHandle {
 				config: Some(config),
 				digest_table: None,
 				directory: Some(directory),
+				sbom: None,
 				path: path.to_path_buf(),
 				file_size: std::fs::metadata(&path)?.len(),
 			})
@@ -379,6 +442,7 @@ impl ImageHandle {
 				config: None,
 				digest_table: None,
 				directory: None,
+				sbom: None,
 				path: path.to_path_buf(),
 				file_size: std::fs::metadata(&path)?.len(),
 			})
@@ -399,11 +463,26 @@ impl ImageHandle {
 		todo!()
 	}
 
-	/// Convert a qcow image into a goldboot image.
+	/// Convert a qcow image into a goldboot image. Clusters are always written
+	/// in block order, so unencrypted images built from identical storage
+	/// (with `SOURCE_DATE_EPOCH` set) are byte-for-byte identical.
 	pub fn convert(
 		source: &Qcow3,
 		config: BuildConfig,
+		sbom: Option<serde_json::Value>,
+		dest: impl AsRef<Path>,
+	) -> Result<ImageHandle, Box<dyn Error>> {
+		let timestamp = build_timestamp(std::env::var("SOURCE_DATE_EPOCH").ok())?;
+		ImageHandle::convert_at(source, config, sbom, dest, timestamp)
+	}
+
+	/// Convert a qcow image into a goldboot image with the given creation time.
+	pub fn convert_at(
+		source: &Qcow3,
+		config: BuildConfig,
+		sbom: Option<serde_json::Value>,
 		dest: impl AsRef<Path>,
+		timestamp: u64,
 	) -> Result<ImageHandle, Box<dyn Error>> {
 		info!("Exporting storage to goldboot image");
 
@@ -412,28 +491,32 @@ impl ImageHandle {
 
 		// Prepare cipher and RNG if the image header should be encrypted
 		let header_cipher = new_key(config.password.clone().unwrap_or("".to_string()));
+		let encrypted = config.password.is_some();
 		let mut rng = rand::thread_rng();
 
 		// Prepare directory
 		let mut directory = Directory {
-			protected_nonce: rng.gen::<[u8; 12]>(),
+			protected_nonce: key_material(&mut rng, encrypted),
 			protected_size: 0,
-			config_nonce: rng.gen::<[u8; 12]>(),
+			config_nonce: key_material(&mut rng, encrypted),
 			config_offset: 0,
 			config_size: 0,
-			digest_table_nonce: rng.gen::<[u8; 12]>(),
+			digest_table_nonce: key_material(&mut rng, encrypted),
 			digest_table_offset: 0,
 			digest_table_size: 0,
+			sbom_nonce: key_material(&mut rng, encrypted),
+			sbom_offset: 0,
+			sbom_size: 0,
 		};
 
 		// Prepare primary header
 		let mut primary_header = PrimaryHeader {
-			version: 1,
+			version: FORMAT_VERSION,
 			size: source.header.size,
-			directory_nonce: rng.gen::<[u8; 12]>(),
+			directory_nonce: key_material(&mut rng, encrypted),
 			directory_offset: 0,
 			directory_size: 0,
-			timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+			timestamp,
 			encryption_type: if config.password.is_some() {
 				HeaderEncryptionType::Aes256
 			} else {
@@ -446,7 +529,7 @@ impl ImageHandle {
 			.copy_from_slice(&config.name.clone().as_bytes()[..]);
 
 		// Prepare config
-		let mut config = config.clone();
+		let mut config = variables::redact(&config)?;
 		config.password = None;
 
 		// Prepare protected header
@@ -459,7 +542,7 @@ impl ImageHandle {
 			} else {
 				ClusterEncryptionType::None
 			},
-			cluster_key: rng.gen::<[u8; 32]>(),
+			cluster_key: key_material(&mut rng, encrypted),
 			nonce_count: 0,
 			nonce_table: vec![],
 		};
@@ -467,7 +550,7 @@ impl ImageHandle {
 		if config.password.is_some() {
 			protected_header.nonce_count = protected_header.cluster_count;
 			protected_header.nonce_table = (0..protected_header.cluster_count)
-				.map(|_| rng.gen::<[u8; 12]>())
+				.map(|_| key_material(&mut rng, encrypted))
 				.collect();
 		}
 
@@ -619,6 +702,23 @@ impl ImageHandle {
 			dest_file.write_all(&digest_table_bytes)?;
 		}
 
+		// Write the software inventory
+		if let Some(sbom) = &sbom {
+			let sbom_bytes = serde_json::to_vec(sbom)?;
+
+			let sbom_bytes = match primary_header.encryption_type {
+				HeaderEncryptionType::None => sbom_bytes,
+				HeaderEncryptionType::Aes256 => header_cipher.encrypt(
+					Nonce::from_slice(&directory.sbom_nonce),
+					sbom_bytes.as_ref(),
+				)?,
+			};
+
+			directory.sbom_offset = dest_file.stream_position()?;
+			directory.sbom_size = sbom_bytes.len() as u32;
+			dest_file.write_all(&sbom_bytes)?;
+		}
+
 		// Write the completed directory
 		{
 			let mut directory_bytes = Cursor::new(Vec::new());
@@ -648,6 +748,7 @@ impl ImageHandle {
 			config: Some(config),
 			digest_table: Some(digest_table),
 			directory: Some(directory),
+			sbom,
 			path: dest.as_ref().to_path_buf(),
 			file_size: std::fs::metadata(&dest)?.len(),
 		})
@@ -737,6 +838,155 @@ impl ImageHandle {
 
 		Ok(())
 	}
+
+	/// Compare this image with another and describe each difference. Both
+	/// images must be loaded first. An empty result means the images are
+	/// identical.
+	pub fn compare(&self, other: &ImageHandle) -> Result<Vec<String>, Box<dyn Error>> {
+		let mut differences = Vec::new();
+
+		// Compare primary headers
+		{
+			let (a, b) = (&self.primary_header, &other.primary_header);
+
+			if a.version != b.version {
+				differences.push(format!("Format version: {} != {}", a.version, b.version));
+			}
+			if a.name != b.name {
+				differences.push(format!("Name: {} != {}", a.name(), b.name()));
+			}
+			if a.size != b.size {
+				differences.push(format!("Total size: {} != {}", a.size, b.size));
+			}
+			if a.timestamp != b.timestamp {
+				differences.push(format!(
+					"Timestamp: {} != {} (set SOURCE_DATE_EPOCH for reproducible builds)",
+					a.timestamp, b.timestamp
+				));
+			}
+			if a.encryption_type != b.encryption_type {
+				differences.push(format!(
+					"Header encryption: {:?} != {:?}",
+					a.encryption_type, b.encryption_type
+				));
+			}
+			if a.directory_nonce != b.directory_nonce {
+				differences.push(String::from("Directory nonce differs"));
+			}
+		}
+
+		let (protected_a, protected_b) = match (&self.protected_header, &other.protected_header) {
+			(Some(a), Some(b)) => (a, b),
+			_ => bail!("Image not loaded"),
+		};
+
+		// Compare protected headers
+		if protected_a.block_size != protected_b.block_size {
+			differences.push(format!(
+				"Block size: {} != {}",
+				protected_a.block_size, protected_b.block_size
+			));
+		}
+		if protected_a.cluster_count != protected_b.cluster_count {
+			differences.push(format!(
+				"Cluster count: {} != {}",
+				protected_a.cluster_count, protected_b.cluster_count
+			));
+		}
+		if protected_a.cluster_compression != protected_b.cluster_compression {
+			differences.push(format!(
+				"Cluster compression: {:?} != {:?}",
+				protected_a.cluster_compression, protected_b.cluster_compression
+			));
+		}
+		if protected_a.cluster_encryption != protected_b.cluster_encryption {
+			differences.push(format!(
+				"Cluster encryption: {:?} != {:?}",
+				protected_a.cluster_encryption, protected_b.cluster_encryption
+			));
+		}
+		if protected_a.cluster_key != protected_b.cluster_key {
+			differences.push(String::from("Cluster key differs"));
+		}
+		if protected_a.nonce_table != protected_b.nonce_table {
+			differences.push(String::from("Cluster nonces differ"));
+		}
+
+		// Compare configs by top-level field
+		{
+			let a = serde_json::to_value(&self.config)?;
+			let b = serde_json::to_value(&other.config)?;
+
+			if let (Some(a), Some(b)) = (a.as_object(), b.as_object()) {
+				let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+				keys.sort();
+				keys.dedup();
+
+				for key in keys {
+					if a.get(key) != b.get(key) {
+						differences.push(format!("Config field differs: {key}"));
+					}
+				}
+			} else if a != b {
+				differences.push(String::from("Config differs"));
+			}
+		}
+
+		if self.sbom != other.sbom {
+			differences.push(String::from("Software inventory differs"));
+		}
+
+		let (digests_a, digests_b) = match (&self.digest_table, &other.digest_table) {
+			(Some(a), Some(b)) => (&a.digest_table, &b.digest_table),
+			_ => bail!("Image not loaded"),
+		};
+
+		// Compare block contents
+		{
+			let blocks_a: std::collections::BTreeMap<u64, &DigestTableEntry> = digests_a
+				.iter()
+				.map(|entry| (entry.block_offset, entry))
+				.collect();
+			let blocks_b: std::collections::BTreeMap<u64, &DigestTableEntry> = digests_b
+				.iter()
+				.map(|entry| (entry.block_offset, entry))
+				.collect();
+
+			let mut changed = Vec::new();
+			let mut moved = 0;
+
+			for (offset, a) in &blocks_a {
+				match blocks_b.get(offset) {
+					Some(b) if a.digest != b.digest => changed.push(*offset),
+					Some(b) if a.cluster_offset != b.cluster_offset => moved += 1,
+					Some(_) => {}
+					None => changed.push(*offset),
+				}
+			}
+			for offset in blocks_b.keys() {
+				if !blocks_a.contains_key(offset) {
+					changed.push(*offset);
+				}
+			}
+			changed.sort();
+
+			if changed.len() > 0 {
+				differences.push(format!(
+					"{} blocks have different contents (first at offset {})",
+					changed.len(),
+					changed[0]
+				));
+			}
+			if moved > 0 {
+				differences.push(format!(
+					"{} identical blocks are stored at different cluster offsets",
+					moved
+				));
+			}
+		}
+
+		Ok(differences)
+	}
 }
 
 #[cfg(test)]
@@ -758,9 +1008,23 @@ mod tests {
 				arch: Architecture::amd64,
 				memory: None,
 				nvme: None,
+				hardware: None,
+				firmware: None,
+				tpm: None,
+				network: None,
+				shares: None,
+				secure_boot: None,
+				keep_vars: None,
+				test: None,
 				password: None,
+				timeout: None,
+				wait_timeout: None,
+				variables: None,
+				redactions: Default::default(),
+				hooks: None,
 				templates: vec![],
 			},
+			None,
 			tmp.path().join("small.gb"),
 		)?;
 
@@ -801,9 +1065,23 @@ mod tests {
 				arch: Architecture::amd64,
 				memory: None,
 				nvme: None,
+				hardware: None,
+				firmware: None,
+				tpm: None,
+				network: None,
+				shares: None,
+				secure_boot: None,
+				keep_vars: None,
+				test: None,
 				password: Some("1234".to_string()),
+				timeout: None,
+				wait_timeout: None,
+				variables: None,
+				redactions: Default::default(),
+				hooks: None,
 				templates: vec![],
 			},
+			None,
 			tmp.path().join("small.gb"),
 		)?;
 
@@ -828,4 +1106,104 @@ mod tests {
 
 		Ok(())
 	}
+
+	#[test_env_log::test]
+	fn convert_small_qcow2_with_sbom() -> Result<(), Box<dyn Error>> {
+		let tmp = tempfile::tempdir()?;
+
+		let sbom = crate::sbom::cyclonedx(
+			"Small test",
+			crate::sbom::PackageManager::Pacman,
+			&crate::sbom::PackageManager::Pacman.parse("linux 5.18.12.arch1-1\n"),
+		);
+
+		ImageHandle::convert(
+			&Qcow3::open("test/small.qcow2")?,
+			BuildConfig {
+				name: String::from("Small test"),
+				description: None,
+				arch: Architecture::amd64,
+				memory: None,
+				nvme: None,
+				hardware: None,
+				firmware: None,
+				tpm: None,
+				network: None,
+				shares: None,
+				secure_boot: None,
+				keep_vars: None,
+				test: None,
+				password: Some("1234".to_string()),
+				timeout: None,
+				wait_timeout: None,
+				variables: None,
+				redactions: Default::default(),
+				hooks: None,
+				templates: vec![],
+			},
+			Some(sbom.clone()),
+			tmp.path().join("small.gb"),
+		)?;
+
+		// The inventory is only available after loading
+		let mut loaded_image = ImageHandle::open(tmp.path().join("small.gb"))?;
+		assert_eq!(loaded_image.primary_header.version, FORMAT_VERSION);
+		assert_eq!(loaded_image.sbom, None);
+
+		loaded_image.load(Some("1234".to_string()))?;
+		assert_eq!(loaded_image.sbom, Some(sbom));
+
+		Ok(())
+	}
+
+	#[test_env_log::test]
+	fn convert_small_qcow2_reproducibly() -> Result<(), Box<dyn Error>> {
+		let tmp = tempfile::tempdir()?;
+		let timestamp = build_timestamp(Some(String::from("1657929600\n")))?;
+
+		let config = BuildConfig {
+			name: String::from("Small test"),
+			description: None,
+			arch: Architecture::amd64,
+			memory: None,
+			nvme: None,
+			hardware: None,
+			firmware: None,
+			tpm: None,
+			network: None,
+			shares: None,
+			secure_boot: None,
+			keep_vars: None,
+			test: None,
+			password: None,
+			timeout: None,
+			wait_timeout: None,
+			variables: None,
+			redactions: Default::default(),
+			hooks: None,
+			templates: vec![],
+		};
+
+		// Convert the test qcow2 twice
+		let first = ImageHandle::convert_at(
+			&Qcow3::open("test/small.qcow2")?,
+			config.clone(),
+			None,
+			tmp.path().join("first.gb"),
+			timestamp,
+		)?;
+		let second = ImageHandle::convert_at(
+			&Qcow3::open("test/small.qcow2")?,
+			config,
+			None,
+			tmp.path().join("second.gb"),
+			timestamp,
+		)?;
+
+		assert_eq!(first.primary_header.timestamp, 1657929600);
+		assert_eq!(first.id, second.id);
+		assert_eq!(first.compare(&second)?, Vec::<String>::new());
+
+		Ok(())
+	}
 }
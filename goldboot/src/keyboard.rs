@@ -0,0 +1,288 @@
+//! Keyboard input for boot commands. QEMU turns the keysyms it receives over
+//! VNC into scancodes for a US keyboard, so typing for a guest with another
+//! layout means pressing the keys where that layout has each character.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use simple_error::bail;
+use std::error::Error;
+
+pub const SHIFT: u32 = 0xffe1;
+pub const RETURN: u32 = 0xff0d;
+pub const TAB: u32 = 0xff09;
+
+/// Right Alt, which non-US layouts treat as AltGr.
+pub const ALTGR: u32 = 0xffea;
+
+/// Characters which need Shift on a US keyboard.
+const US_SHIFTED: &str = "~!@#$%^&*()_+{}|:\"<>?";
+
+/// The layout the guest uses to interpret key presses.
+#[derive(Clone, Copy, Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyboardLayout {
+	#[default]
+	Us,
+
+	/// German (QWERTZ)
+	De,
+
+	/// French (AZERTY)
+	Fr,
+}
+
+/// Where a character is typed, given as the character in the same position
+/// on a US keyboard.
+#[derive(Clone, Copy, Debug)]
+struct Position {
+	us: char,
+	altgr: bool,
+
+	/// Dead keys only produce their character when followed by a space
+	dead: bool,
+}
+
+const fn key(us: char) -> Position {
+	Position {
+		us,
+		altgr: false,
+		dead: false,
+	}
+}
+
+const fn altgr(us: char) -> Position {
+	Position {
+		us,
+		altgr: true,
+		dead: false,
+	}
+}
+
+const fn dead(us: char) -> Position {
+	Position {
+		us,
+		altgr: false,
+		dead: true,
+	}
+}
+
+/// The German layout. The key between Shift and Y (for <, > and |) doesn't
+/// exist on a US keyboard, so those characters can't be typed.
+#[rustfmt::skip]
+const DE: &[(char, Position)] = &[
+	('^', dead('`')), ('°', key('~')), ('"', key('@')), ('§', key('#')),
+	('&', key('^')), ('/', key('&')), ('(', key('*')), (')', key('(')),
+	('=', key(')')), ('ß', key('-')), ('?', key('_')), ('´', dead('=')),
+	('`', dead('+')), ('²', altgr('2')), ('³', altgr('3')), ('{', altgr('7')),
+	('[', altgr('8')), (']', altgr('9')), ('}', altgr('0')), ('\\', altgr('-')),
+	('z', key('y')), ('Z', key('Y')), ('ü', key('[')), ('Ü', key('{')),
+	('+', key(']')), ('*', key('}')), ('@', altgr('q')), ('€', altgr('e')),
+	('~', altgr(']')), ('ö', key(';')), ('Ö', key(':')), ('ä', key('\'')),
+	('Ä', key('"')), ('#', key('\\')), ('\'', key('|')), ('y', key('z')),
+	('Y', key('Z')), (';', key('<')), (':', key('>')), ('-', key('/')),
+	('_', key('?')), ('µ', altgr('m')), ('!', key('!')), ('$', key('$')),
+	('%', key('%')), (',', key(',')), ('.', key('.')),
+];
+
+/// The French layout. As with German, <, > and the characters on their key
+/// can't be typed.
+#[rustfmt::skip]
+const FR: &[(char, Position)] = &[
+	('²', key('`')), ('&', key('1')), ('é', key('2')), ('"', key('3')),
+	('\'', key('4')), ('(', key('5')), ('-', key('6')), ('è', key('7')),
+	('_', key('8')), ('ç', key('9')), ('à', key('0')), (')', key('-')),
+	('=', key('=')), ('1', key('!')), ('2', key('@')), ('3', key('#')),
+	('4', key('$')), ('5', key('%')), ('6', key('^')), ('7', key('&')),
+	('8', key('*')), ('9', key('(')), ('0', key(')')), ('°', key('_')),
+	('+', key('+')), ('~', Position { us: '2', altgr: true, dead: true }),
+	('#', altgr('3')), ('{', altgr('4')), ('[', altgr('5')), ('|', altgr('6')),
+	('`', Position { us: '7', altgr: true, dead: true }), ('\\', altgr('8')),
+	('^', altgr('9')), ('@', altgr('0')), (']', altgr('-')), ('}', altgr('=')),
+	('a', key('q')), ('A', key('Q')), ('z', key('w')), ('Z', key('W')),
+	('¨', dead('{')), ('$', key(']')), ('£', key('}')), ('€', altgr('e')),
+	('¤', altgr(']')), ('q', key('a')), ('Q', key('A')), ('m', key(';')),
+	('M', key(':')), ('ù', key('\'')), ('%', key('"')), ('*', key('\\')),
+	('µ', key('|')), ('w', key('z')), ('W', key('Z')), (',', key('m')),
+	('?', key('M')), (';', key(',')), ('.', key('<')), (':', key('.')),
+	('/', key('>')), ('!', key('/')), ('§', key('?')),
+];
+
+/// The keysym of a character.
+pub fn char_keysym(ch: char) -> u32 {
+	0x01000000 + ch as u32
+}
+
+/// The keysyms which type a character on a US keyboard.
+fn us_keysyms(ch: char) -> Vec<u32> {
+	if ch.is_ascii_uppercase() || US_SHIFTED.contains(ch) {
+		vec![SHIFT, char_keysym(ch)]
+	} else {
+		vec![char_keysym(ch)]
+	}
+}
+
+impl KeyboardLayout {
+	/// The key presses which type the given character. Each press is a list of
+	/// keysyms which are held down together.
+	pub fn presses(&self, ch: char) -> Result<Vec<Vec<u32>>, Box<dyn Error>> {
+		let table = match (ch, self) {
+			('\n', _) => return Ok(vec![vec![RETURN]]),
+			('\t', _) => return Ok(vec![vec![TAB]]),
+			(_, KeyboardLayout::Us) => return Ok(vec![us_keysyms(ch)]),
+			(_, KeyboardLayout::De) => DE,
+			(_, KeyboardLayout::Fr) => FR,
+		};
+
+		let position = match table.iter().find(|(c, _)| *c == ch) {
+			Some((_, position)) => *position,
+			None if ch.is_ascii_alphanumeric() || ch == ' ' => key(ch),
+			None => bail!("Can't type '{}' with the {:?} keyboard layout", ch, self),
+		};
+
+		let mut press = us_keysyms(position.us);
+		if position.altgr {
+			press.insert(0, ALTGR);
+		}
+		let mut presses = vec![press];
+		if position.dead {
+			presses.push(vec![char_keysym(' ')]);
+		}
+		Ok(presses)
+	}
+}
+
+/// Named keys where the first name for each keysym is the one displayed.
+const NAMES: &[(&str, u32)] = &[
+	("Enter", RETURN),
+	("Return", RETURN),
+	("Tab", TAB),
+	("Space", 0x0020),
+	("Escape", 0xff1b),
+	("Esc", 0xff1b),
+	("Backspace", 0xff08),
+	("Delete", 0xffff),
+	("Del", 0xffff),
+	("Insert", 0xff63),
+	("Home", 0xff50),
+	("End", 0xff57),
+	("PageUp", 0xff55),
+	("PageDown", 0xff56),
+	("Left", 0xff51),
+	("Up", 0xff52),
+	("Right", 0xff53),
+	("Down", 0xff54),
+	("Menu", 0xff67),
+	("PrintScreen", 0xff61),
+	("Pause", 0xff13),
+	("CapsLock", 0xffe5),
+	("Shift", SHIFT),
+	("LeftShift", SHIFT),
+	("RightShift", 0xffe2),
+	("Ctrl", 0xffe3),
+	("LeftCtrl", 0xffe3),
+	("RightCtrl", 0xffe4),
+	("Alt", 0xffe9),
+	("LeftAlt", 0xffe9),
+	("AltGr", ALTGR),
+	("RightAlt", ALTGR),
+	("Super", 0xffeb),
+	("LeftSuper", 0xffeb),
+	("RightSuper", 0xffec),
+];
+
+/// Look up the keysym of a named key (i.e. "Ctrl" or "F12"). Single characters
+/// name themselves.
+pub fn keysym(name: &str) -> Result<u32, Box<dyn Error>> {
+	let mut chars = name.chars();
+	if let (Some(ch), None) = (chars.next(), chars.next()) {
+		return Ok(char_keysym(ch));
+	}
+	if name.eq_ignore_ascii_case("plus") {
+		return Ok(char_keysym('+'));
+	}
+
+	let lowercase = name.to_lowercase();
+	if let Some(n) = lowercase
+		.strip_prefix('f')
+		.and_then(|n| n.parse::<u32>().ok())
+	{
+		if (1..=24).contains(&n) {
+			return Ok(0xffbe + n - 1);
+		}
+	}
+
+	match NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+		Some((_, keysym)) => Ok(*keysym),
+		None => bail!("Unknown key: {}", name),
+	}
+}
+
+/// The character a keysym types, if any. VNC viewers send Latin-1 characters
+/// as their code points and others in the Unicode range.
+pub fn keysym_char(keysym: u32) -> Option<char> {
+	match keysym {
+		0x0020..=0x007e | 0x00a0..=0x00ff => char::from_u32(keysym),
+		0x01000000..=0x0110ffff => char::from_u32(keysym - 0x01000000),
+		_ => None,
+	}
+}
+
+/// The name of a key as accepted by `keysym`.
+pub fn name(keysym: u32) -> Option<String> {
+	match keysym_char(keysym) {
+		Some('+') => Some(String::from("Plus")),
+		Some(' ') => Some(String::from("Space")),
+		Some(ch) => Some(ch.to_string()),
+		None if (0xffbe..=0xffd5).contains(&keysym) => Some(format!("F{}", keysym - 0xffbe + 1)),
+		None => NAMES
+			.iter()
+			.find(|(_, k)| *k == keysym)
+			.map(|(n, _)| n.to_string()),
+	}
+}
+
+/// Look up the keysyms in a combination like "Ctrl+Alt+Delete".
+pub fn combo(keys: &str) -> Result<Vec<u32>, Box<dyn Error>> {
+	keys.split('+').map(keysym).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_presses() -> Result<(), Box<dyn Error>> {
+		let us = KeyboardLayout::Us;
+		assert_eq!(us.presses('a')?, vec![vec![char_keysym('a')]]);
+		assert_eq!(us.presses('@')?, vec![vec![SHIFT, char_keysym('@')]]);
+		assert_eq!(us.presses('\n')?, vec![vec![RETURN]]);
+
+		let de = KeyboardLayout::De;
+		assert_eq!(de.presses('z')?, vec![vec![char_keysym('y')]]);
+		assert_eq!(de.presses('@')?, vec![vec![ALTGR, char_keysym('q')]]);
+		assert_eq!(de.presses('/')?, vec![vec![SHIFT, char_keysym('&')]]);
+		assert_eq!(
+			de.presses('^')?,
+			vec![vec![char_keysym('`')], vec![char_keysym(' ')]]
+		);
+		assert!(de.presses('<').is_err());
+
+		let fr = KeyboardLayout::Fr;
+		assert_eq!(fr.presses('1')?, vec![vec![SHIFT, char_keysym('!')]]);
+		assert_eq!(fr.presses('a')?, vec![vec![char_keysym('q')]]);
+		Ok(())
+	}
+
+	#[test]
+	fn test_combo() -> Result<(), Box<dyn Error>> {
+		assert_eq!(combo("Ctrl+Alt+Delete")?, vec![0xffe3, 0xffe9, 0xffff]);
+		assert_eq!(combo("F12")?, vec![0xffc9]);
+		assert_eq!(combo("Alt+Plus")?, vec![0xffe9, char_keysym('+')]);
+		assert!(combo("Ctrl+Nope").is_err());
+
+		assert_eq!(name(0xffe9), Some(String::from("Alt")));
+		assert_eq!(name(0xffc9), Some(String::from("F12")));
+		assert_eq!(name(0x63), Some(String::from("c")));
+		Ok(())
+	}
+}
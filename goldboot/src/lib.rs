@@ -4,40 +4,46 @@
 use crate::build::BuildConfig;
 use log::{debug, info};
 use rand::Rng;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use simple_error::bail;
-use std::{default::Default, error::Error, net::TcpListener, process::Command};
+use std::{default::Default, error::Error, process::Command};
 use strum::{Display, EnumIter};
 use validator::Validate;
 
+pub mod authoring;
 pub mod build;
 pub mod cache;
+pub mod cancel;
 pub mod cmd;
+pub mod events;
+#[cfg(test)]
+pub mod harness;
+pub mod hardware;
+pub mod hooks;
 pub mod http;
 pub mod image;
+pub mod keyboard;
 pub mod library;
+pub mod network;
 pub mod progress;
 pub mod provisioners;
 pub mod qcow;
 pub mod qemu;
+pub mod recording;
 pub mod registry;
+pub mod resources;
+pub mod run;
+pub mod sbom;
+pub mod serial;
+pub mod shares;
 pub mod ssh;
 pub mod templates;
+pub mod tpm;
+pub mod uefi;
+pub mod variables;
 pub mod vnc;
 
-/// Find a random open TCP port in the given range.
-pub fn find_open_port(lower: u16, upper: u16) -> u16 {
-	let mut rand = rand::thread_rng();
-
-	loop {
-		let port = rand.gen_range(lower..upper);
-		match TcpListener::bind(format!("0.0.0.0:{port}")) {
-			Ok(_) => break port,
-			Err(_) => continue,
-		}
-	}
-}
-
 /// Generate a random password
 pub fn random_password() -> String {
 	// TODO check for a dictionary to generate something memorable
@@ -55,7 +61,19 @@ pub fn is_interactive() -> bool {
 }
 
 /// Represents a system architecture.
-#[derive(Clone, Copy, Serialize, Deserialize, Debug, Default, PartialEq, Eq, EnumIter, Display)]
+#[derive(
+	Clone,
+	Copy,
+	Serialize,
+	Deserialize,
+	JsonSchema,
+	Debug,
+	Default,
+	PartialEq,
+	Eq,
+	EnumIter,
+	Display,
+)]
 #[serde(tag = "arch")]
 #[allow(non_camel_case_types)]
 pub enum Architecture {
@@ -88,16 +106,3 @@ pub trait Promptable {
 		theme: &dialoguer::theme::ColorfulTheme,
 	) -> Result<(), Box<dyn Error>>;
 }
-
-#[cfg(test)]
-mod tests {
-	use super::*;
-
-	#[test]
-	fn test_find_open_port() {
-		let port = find_open_port(9000, 9999);
-
-		assert!(port < 9999);
-		assert!(port >= 9000);
-	}
-}
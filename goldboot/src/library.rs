@@ -34,8 +34,8 @@ fn library_path() -> PathBuf {
 
 impl ImageLibrary {
 	/// Add an image to the library. The image will be hashed and copied to the
-	/// library with the appropriate name.
-	pub fn add(image_path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+	/// library with the appropriate name. The image's new path is returned.
+	pub fn add(image_path: impl AsRef<Path>) -> Result<PathBuf, Box<dyn Error>> {
 		info!("Saving image to library");
 
 		let mut hasher = Sha256::new();
@@ -46,8 +46,9 @@ impl ImageLibrary {
 		)?;
 		let hash = hex::encode(hasher.finalize());
 
-		std::fs::copy(&image_path, library_path().join(format!("{hash}.gb")))?;
-		Ok(())
+		let path = library_path().join(format!("{hash}.gb"));
+		std::fs::copy(&image_path, &path)?;
+		Ok(path)
 	}
 
 	/// Download a goldboot image over HTTP.
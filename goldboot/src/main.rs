@@ -40,10 +40,16 @@ pub fn main() -> Result<(), Box<dyn Error>> {
 		let default_filter = match &command_line.command {
 			Commands::Build {
 				record,
+				timeline,
+				artifacts,
 				debug,
 				read_password,
 				output,
 				config,
+				events,
+				variables,
+				var_file,
+				jobs,
 			} => {
 				if *debug {
 					"debug"
@@ -62,7 +68,12 @@ pub fn main() -> Result<(), Box<dyn Error>> {
 		Commands::Init { .. } => crate::cmd::init::run(command_line.command),
 		Commands::Build { .. } => crate::cmd::build::run(command_line.command),
 		Commands::Image { .. } => crate::cmd::image::run(command_line.command),
+		Commands::Record { .. } => crate::cmd::record::run(command_line.command),
 		Commands::Registry { .. } => crate::cmd::registry::run(command_line.command),
+		Commands::Run { .. } => crate::cmd::run::run(command_line.command),
+		Commands::Test { .. } => crate::cmd::run::run(command_line.command),
+		Commands::Schema { .. } => crate::cmd::schema::run(command_line.command),
+		Commands::Validate { .. } => crate::cmd::validate::run(command_line.command),
 		Commands::Write { .. } => crate::cmd::write::run(command_line.command),
 	}
 }
@@ -0,0 +1,224 @@
+//! Build VM networking. Every worker gets a user-mode network which forwards
+//! the SSH port; the `network` config section adds a proxy, isolation, extra
+//! port forwards and access to a package mirror on the host.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// The address where the guest reaches the host.
+pub const HOST_ADDRESS: &str = "10.0.2.2";
+
+/// The address where the guest reaches the host's package mirror.
+pub const MIRROR_ADDRESS: &str = "10.0.2.100";
+
+/// The address where an isolated guest reaches HTTP servers on the host
+/// (which serve files like preseeds).
+pub const SERVER_ADDRESS: &str = "10.0.2.101";
+
+/// Addresses which are always reached without the proxy: the host, the mirror
+/// and the servers.
+const NO_PROXY: &str = "localhost,127.0.0.1,10.0.2.2,10.0.2.100,10.0.2.101";
+
+#[derive(Clone, Copy, Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+	#[default]
+	Tcp,
+	Udp,
+}
+
+/// Forwards a port on the host to the guest.
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, PartialEq, Eq)]
+pub struct PortForward {
+	#[serde(default)]
+	pub protocol: Protocol,
+
+	/// The port on the host (which can't be shared by parallel workers)
+	pub host: u16,
+
+	/// The port in the guest
+	pub guest: u16,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, Default)]
+pub struct NetworkConfig {
+	/// An HTTP proxy used by installers and provisioners in the guest (i.e.
+	/// "http://proxy.example.com:3128")
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub proxy: Option<String>,
+
+	/// Additional hosts which bypass the proxy
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub no_proxy: Vec<String>,
+
+	/// Cut the guest off from the host's network. Only forwarded ports, the
+	/// mirror and goldboot's own servers remain reachable, so installers must
+	/// not need the internet.
+	#[serde(default)]
+	pub isolated: bool,
+
+	/// Extra ports to forward from the host
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub forwards: Vec<PortForward>,
+
+	/// The address of a package mirror on the host (i.e. "127.0.0.1:8080")
+	/// which the guest reaches at http://10.0.2.100
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub mirror: Option<String>,
+}
+
+impl NetworkConfig {
+	/// The value for QEMU's `-netdev` option.
+	pub fn netdev(&self, ssh_port: u16) -> String {
+		let mut netdev = format!("user,id=user.0,hostfwd=tcp::{}-:22", ssh_port);
+		for forward in &self.forwards {
+			let protocol = match forward.protocol {
+				Protocol::Tcp => "tcp",
+				Protocol::Udp => "udp",
+			};
+			netdev.push_str(&format!(
+				",hostfwd={}::{}-:{}",
+				protocol, forward.host, forward.guest
+			));
+		}
+		if let Some(mirror) = &self.mirror {
+			netdev.push_str(&format!(
+				",guestfwd=tcp:{}:80-tcp:{}",
+				MIRROR_ADDRESS, mirror
+			));
+		}
+		if self.isolated {
+			netdev.push_str(",restrict=on");
+		}
+		netdev
+	}
+
+	/// Where the guest reaches an HTTP server on the given host port, along
+	/// with the `-netdev` option which forwards it there if the host itself
+	/// isn't reachable.
+	pub fn server(&self, port: u16) -> (String, Option<String>) {
+		if self.isolated {
+			(
+				format!("{}:{}", SERVER_ADDRESS, port),
+				Some(format!(
+					",guestfwd=tcp:{}:{}-tcp:127.0.0.1:{}",
+					SERVER_ADDRESS, port, port
+				)),
+			)
+		} else {
+			(format!("{}:{}", HOST_ADDRESS, port), None)
+		}
+	}
+
+	/// Environment variables for commands run in the guest.
+	pub fn env(&self) -> Vec<(String, String)> {
+		let mut env = Vec::new();
+		if let Some(proxy) = &self.proxy {
+			let mut no_proxy = vec![String::from(NO_PROXY)];
+			no_proxy.extend(self.no_proxy.iter().cloned());
+			let no_proxy = no_proxy.join(",");
+
+			for name in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+				env.push((String::from(name), proxy.clone()));
+			}
+			for name in ["no_proxy", "NO_PROXY"] {
+				env.push((String::from(name), no_proxy.clone()));
+			}
+		}
+		if self.mirror.is_some() {
+			env.push((
+				String::from("GOLDBOOT_MIRROR"),
+				format!("http://{}", MIRROR_ADDRESS),
+			));
+		}
+		env
+	}
+
+	/// Configure the proxy in a Debian preseed file.
+	pub fn preseed(&self, preseed: Vec<u8>) -> Vec<u8> {
+		match &self.proxy {
+			Some(proxy) => String::from_utf8_lossy(&preseed)
+				.replace(
+					"d-i mirror/http/proxy string\n",
+					&format!("d-i mirror/http/proxy string {}\n", proxy),
+				)
+				.into_bytes(),
+			None => preseed,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::http::HttpServer;
+	use std::{
+		error::Error,
+		io::{Read, Write},
+		net::TcpStream,
+	};
+
+	#[test]
+	fn test_netdev() {
+		let network = NetworkConfig {
+			proxy: Some(String::from("http://proxy:3128")),
+			isolated: true,
+			forwards: vec![PortForward {
+				protocol: Protocol::Udp,
+				host: 5353,
+				guest: 53,
+			}],
+			mirror: Some(String::from("127.0.0.1:8080")),
+			..Default::default()
+		};
+		assert_eq!(
+			network.netdev(10022),
+			"user,id=user.0,hostfwd=tcp::10022-:22,hostfwd=udp::5353-:53,guestfwd=tcp:10.0.2.100:80-tcp:127.0.0.1:8080,restrict=on"
+		);
+		assert!(network.env().contains(&(
+			String::from("https_proxy"),
+			String::from("http://proxy:3128")
+		)));
+		assert_eq!(
+			network.preseed(b"d-i mirror/http/proxy string\n".to_vec()),
+			b"d-i mirror/http/proxy string http://proxy:3128\n"
+		);
+	}
+
+	#[test]
+	fn test_isolated_server() -> Result<(), Box<dyn Error>> {
+		let http = HttpServer::serve_file(b"d-i debconf/priority critical\n".to_vec())?;
+
+		// Without isolation the guest reaches the server on the host
+		assert_eq!(
+			NetworkConfig::default().server(http.port),
+			(format!("10.0.2.2:{}", http.port), None)
+		);
+
+		let network = NetworkConfig {
+			isolated: true,
+			..Default::default()
+		};
+		let (address, netdev) = network.server(http.port);
+		assert_eq!(address, format!("10.0.2.101:{}", http.port));
+
+		// The server must answer at the forward's target
+		let netdev = netdev.unwrap();
+		let target = netdev.rsplit_once("-tcp:").unwrap().1;
+		let mut stream = TcpStream::connect(target)?;
+		stream.write_all(b"GET /preseed.cfg HTTP/1.1\r\n\r\n")?;
+		let mut response = String::new();
+		stream.read_to_string(&mut response)?;
+		assert!(response.ends_with("d-i debconf/priority critical\n"));
+
+		let netdev = format!("{}{}", network.netdev(10022), netdev);
+		assert_eq!(
+			netdev,
+			format!(
+				"user,id=user.0,hostfwd=tcp::10022-:22,restrict=on,guestfwd=tcp:10.0.2.101:{0}-tcp:127.0.0.1:{0}",
+				http.port
+			)
+		);
+		Ok(())
+	}
+}
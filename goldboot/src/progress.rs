@@ -1,9 +1,12 @@
+use crate::events::{self, BuildEvent};
 use std::{
+	cell::Cell,
 	cmp::min,
 	error::Error,
 	io::{Read, Write},
 };
 
+#[derive(Clone, Copy)]
 pub enum ProgressBar {
 	/// A hashing operation
 	Hash,
@@ -48,18 +51,52 @@ impl ProgressBar {
 		}
 	}
 
+	/// Build an event describing the progress of the operation if it's one
+	/// that's reported to the event stream.
+	fn event(&self, bytes: u64, total: u64) -> Option<BuildEvent> {
+		match self {
+			ProgressBar::Convert => Some(BuildEvent::ConvertProgress { bytes, total }),
+			ProgressBar::Download => Some(BuildEvent::DownloadProgress { bytes, total }),
+			_ => None,
+		}
+	}
+
+	/// Report progress to the event stream, but only when the completed
+	/// percentage changes so the stream isn't flooded.
+	fn report(&self, previous: u64, current: u64, total: u64) {
+		if total == 0 || (previous != 0 && previous * 100 / total == current * 100 / total) {
+			return;
+		}
+		if let Some(event) = self.event(current, total) {
+			events::emit(event);
+		}
+	}
+
 	pub fn new(&self, len: u64) -> Box<dyn Fn(u64)> {
-		if !crate::is_interactive() {
+		if !crate::is_interactive() && !events::enabled() {
 			// No progress bar
 			return Box::new(|_| {});
 		}
 
-		let progress = self.create_progressbar(len);
+		let progress = if crate::is_interactive() {
+			Some(self.create_progressbar(len))
+		} else {
+			None
+		};
+
+		let kind = *self;
+		let position = Cell::new(0);
 		Box::new(move |v| {
-			if progress.position() + v >= len {
-				progress.finish_and_clear();
-			} else {
-				progress.inc(v);
+			let previous = position.get();
+			position.set(min(previous + v, len));
+			kind.report(previous, position.get(), len);
+
+			if let Some(progress) = &progress {
+				if progress.position() + v >= len {
+					progress.finish_and_clear();
+				} else {
+					progress.inc(v);
+				}
 			}
 		})
 	}
@@ -72,13 +109,17 @@ impl ProgressBar {
 		writer: &mut dyn Write,
 		len: u64,
 	) -> Result<(), Box<dyn Error>> {
-		if !crate::is_interactive() {
+		if !crate::is_interactive() && !events::enabled() {
 			// No progress bar
 			std::io::copy(reader, writer)?;
 			return Ok(());
 		}
 
-		let progress = self.create_progressbar(len);
+		let progress = if crate::is_interactive() {
+			Some(self.create_progressbar(len))
+		} else {
+			None
+		};
 
 		let mut buffer = [0u8; 1024 * 1024];
 		let mut copied: u64 = 0;
@@ -90,14 +131,19 @@ impl ProgressBar {
 				}
 				writer.write(&buffer[0..size])?;
 				let new = min(copied + (size as u64), len);
+				self.report(copied, new, len);
 				copied = new;
-				progress.set_position(new);
+				if let Some(progress) = &progress {
+					progress.set_position(new);
+				}
 			} else {
 				break;
 			}
 		}
 
-		progress.finish_and_clear();
+		if let Some(progress) = &progress {
+			progress.finish_and_clear();
+		}
 		Ok(())
 	}
 }
@@ -7,14 +7,15 @@
 use crate::{build::BuildConfig, ssh::SshConnection, Promptable};
 use dialoguer::{theme::ColorfulTheme, Confirm, Password};
 use log::{debug, info};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use simple_error::bail;
-use std::{default::Default, error::Error, path::Path, process::Command};
+use std::{default::Default, error::Error, path::Path, process::Command, time::Duration};
 use strum::{Display, EnumIter};
-use validator::Validate;
+use validator::{Validate, ValidationError};
 
 /// This provisioner loads an ISO install media from a URL.
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct IsoProvisioner {
 	/// The installation media URL (http, https, or file)
 	pub url: String,
@@ -39,7 +40,7 @@ impl Promptable for IsoProvisioner {
 }
 
 /// This provisioner runs an Ansible playbook on the image remotely.
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct AnsibleProvisioner {
 	/// The playbook file
 	pub playbook: String,
@@ -52,10 +53,24 @@ pub struct AnsibleProvisioner {
 }
 
 impl AnsibleProvisioner {
-	pub fn run(&self, ssh: &mut SshConnection) -> Result<(), Box<dyn Error>> {
+	/// Check that the playbook exists locally.
+	pub fn check(&self) -> Result<(), Box<dyn Error>> {
+		if !Path::new(&self.playbook).exists() {
+			bail!("Playbook not found: {}", self.playbook);
+		}
+		if let Some(inventory) = &self.inventory {
+			if !Path::new(inventory).exists() {
+				bail!("Inventory not found: {}", inventory);
+			}
+		}
+		Ok(())
+	}
+
+	/// Run the playbook and return its exit code.
+	pub fn run(&self, ssh: &mut SshConnection) -> Result<i32, Box<dyn Error>> {
 		info!("Running ansible provisioner");
 
-		if let Some(code) = Command::new("ansible-playbook")
+		let mut child = Command::new("ansible-playbook")
 			.arg("--ssh-common-args")
 			.arg("-o StrictHostKeyChecking=no")
 			.arg("-e")
@@ -67,16 +82,22 @@ impl AnsibleProvisioner {
 			.arg("-e")
 			.arg("ansible_connection=ssh")
 			.arg(&self.playbook)
-			.status()
-			.expect("Failed to launch ansible-playbook")
-			.code()
-		{
-			if code != 0 {
-				bail!("Provisioning failed");
+			.spawn()?;
+
+		// Poll so ansible can be stopped if the build is cancelled
+		let status = loop {
+			if let Some(status) = child.try_wait()? {
+				break status;
 			}
-		}
+			if let Err(error) = ssh.cancel.sleep(Duration::from_secs(1)) {
+				child.kill().unwrap_or_default();
+				child.wait()?;
+				return Err(error);
+			}
+		};
 
-		Ok(())
+		// No exit code means ansible was killed by a signal
+		Ok(status.code().unwrap_or(-1))
 	}
 }
 
@@ -105,7 +126,7 @@ impl Promptable for AnsibleProvisioner {
 }
 
 /// This provisioner runs an inline shell command.
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct ShellProvisioner {
 	/// The inline command to run
 	pub command: String,
@@ -123,18 +144,16 @@ impl ShellProvisioner {
 		}
 	}
 
-	pub fn run(&self, ssh: &mut SshConnection) -> Result<(), Box<dyn Error>> {
+	/// Run the command and return its exit code.
+	pub fn run(&self, ssh: &mut SshConnection) -> Result<i32, Box<dyn Error>> {
 		info!("Running shell provisioner");
 
-		if ssh.exec(&self.command)? != 0 {
-			bail!("Provisioner failed");
-		}
-		Ok(())
+		ssh.exec(&self.command)
 	}
 }
 
 /// This provisioner runs an executable file on the image.
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct ExecutableProvisioner {
 	/// The path to the executable
 	pub path: String,
@@ -144,14 +163,20 @@ pub struct ExecutableProvisioner {
 }
 
 impl ExecutableProvisioner {
-	pub fn run(&self, ssh: &mut SshConnection) -> Result<(), Box<dyn Error>> {
-		info!("Running executable provisioner");
-
-		if ssh.upload_exec(std::fs::read(self.path.clone())?, vec![])? != 0 {
-			bail!("Provisioner failed");
+	/// Check that the executable exists locally.
+	pub fn check(&self) -> Result<(), Box<dyn Error>> {
+		if !Path::new(&self.path).exists() {
+			bail!("Executable not found: {}", self.path);
 		}
 		Ok(())
 	}
+
+	/// Run the executable and return its exit code.
+	pub fn run(&self, ssh: &mut SshConnection) -> Result<i32, Box<dyn Error>> {
+		info!("Running executable provisioner");
+
+		ssh.upload_exec(std::fs::read(self.path.clone())?, vec![])
+	}
 }
 
 impl Promptable for ExecutableProvisioner {
@@ -179,7 +204,7 @@ impl Promptable for ExecutableProvisioner {
 }
 
 /// This provisioner changes the network hostname.
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct HostnameProvisioner {
 	// TODO validate
 	pub hostname: String,
@@ -209,7 +234,7 @@ impl Promptable for HostnameProvisioner {
 	}
 }
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct TimezoneProvisioner {
 	// TODO
 }
@@ -225,7 +250,7 @@ impl Promptable for TimezoneProvisioner {
 }
 
 /// This provisioner configures a UNIX-like user account.
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct UnixAccountProvisioner {
 	#[validate(length(max = 64))]
 	pub password: String,
@@ -255,7 +280,7 @@ impl Default for UnixAccountProvisioner {
 }
 
 /// This provisioner configures a LUKS encrypted root filesystem
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct LuksProvisoner {
 	/// The LUKS passphrase
 	pub passphrase: String,
@@ -286,14 +311,44 @@ impl Promptable for LuksProvisoner {
 
 pub struct SshProvisioner {}
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct PartitionProvisioner {
+	#[validate(custom(function = "validate_storage_size"))]
 	pub total_size: String,
 	// TODO
 }
 
 impl PartitionProvisioner {
-	pub fn storage_size_bytes(&self) -> u64 {
-		self.total_size.parse::<ubyte::ByteUnit>().unwrap().as_u64()
+	pub fn storage_size_bytes(&self) -> Result<u64, Box<dyn Error>> {
+		match self.total_size.parse::<ubyte::ByteUnit>() {
+			Ok(size) => Ok(size.as_u64()),
+			Err(_) => bail!("Invalid storage size: {}", self.total_size),
+		}
+	}
+}
+
+/// Check that a storage size (i.e. "10 GiB") can be parsed.
+pub fn validate_storage_size(size: &str) -> Result<(), ValidationError> {
+	match size.parse::<ubyte::ByteUnit>() {
+		Ok(size) if size.as_u64() > 0 => Ok(()),
+		_ => Err(ValidationError::new("storage_size")),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_storage_size() {
+		let mut partitions = PartitionProvisioner {
+			total_size: String::from("10 GiB"),
+		};
+		assert!(partitions.validate().is_ok());
+		assert_eq!(partitions.storage_size_bytes().unwrap(), 10737418240);
+
+		partitions.total_size = String::from("10 Gallons");
+		assert!(partitions.validate().is_err());
+		assert!(partitions.storage_size_bytes().is_err());
 	}
 }
@@ -1,13 +1,36 @@
-use crate::{build::BuildWorker, ssh::SshConnection, vnc::VncConnection, Architecture};
-use log::{debug, info};
+use crate::{
+	build::BuildWorker,
+	cancel::CancelToken,
+	events::{self, BuildEvent},
+	hardware::{DiskBus, Firmware},
+	hooks::{HookStage, Hooks},
+	network::NetworkConfig,
+	recording::Recorder,
+	serial::SerialConnection,
+	shares::{self, ShareConfig, ShareDriver, Virtiofsd},
+	ssh::SshConnection,
+	tpm::{self, Swtpm},
+	vnc::VncConnection,
+	Architecture,
+};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
 use simple_error::bail;
 use std::error::Error;
 
 use std::{
+	io::{BufRead, BufReader, ErrorKind, Write},
+	os::unix::net::UnixStream,
+	path::{Path, PathBuf},
 	process::{Child, Command},
-	time::Duration,
+	sync::Arc,
+	time::{Duration, Instant},
 };
 
+/// How long to wait for the guest to power off before intervening.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(300);
+
 /// Get the QEMU system binary for the current platform.
 pub fn current_qemu_binary() -> &'static str {
 	if cfg!(target_arch = "x86_64") {
@@ -19,9 +42,28 @@ pub fn current_qemu_binary() -> &'static str {
 	}
 }
 
-/// Detect the best acceleration type for the current hardware.
-pub fn detect_accel() -> String {
-	if std::env::var("CI").is_ok() {
+/// Get the QEMU system binary for the given guest architecture.
+pub fn qemu_binary(arch: Architecture) -> &'static str {
+	match arch {
+		Architecture::arm64 => "qemu-system-aarch64",
+		Architecture::i386 => "qemu-system-i386",
+		_ => "qemu-system-x86_64",
+	}
+}
+
+/// Whether the given guest architecture can run natively on the current host.
+pub fn is_native(arch: Architecture) -> bool {
+	match arch {
+		Architecture::amd64 | Architecture::i386 => cfg!(target_arch = "x86_64"),
+		Architecture::arm64 => cfg!(target_arch = "aarch64"),
+		_ => false,
+	}
+}
+
+/// Detect the best acceleration type for the given guest architecture on the
+/// current hardware. Guests of a foreign architecture are always emulated.
+pub fn detect_accel(arch: Architecture) -> String {
+	if std::env::var("CI").is_ok() || !is_native(arch) {
 		return String::from("tcg");
 	}
 	if cfg!(target_arch = "x86_64") {
@@ -48,21 +90,217 @@ pub fn detect_accel() -> String {
 		} else {
 			String::from("tcg")
 		}
+	} else if cfg!(target_os = "linux") && Path::new("/dev/kvm").exists() {
+		String::from("kvm")
 	} else {
 		String::from("tcg")
 	}
 }
 
-pub fn mimic_hardware() {}
+/// Choose a CPU model for the given guest architecture and acceleration type
+/// (or `None` for QEMU's default).
+pub fn cpu_model(arch: Architecture, accel: &str) -> Option<String> {
+	match (arch, accel) {
+		(Architecture::arm64, "kvm") => Some(String::from("host")),
+		// The virt machine's default CPU is 32-bit
+		(Architecture::arm64, _) => Some(String::from("cortex-a72")),
+		// The default models lack features that modern guests expect
+		(Architecture::amd64 | Architecture::i386, "tcg") if !is_native(arch) => {
+			Some(String::from("max"))
+		}
+		_ => None,
+	}
+}
+
+/// The run state of a VM as reported by `query-status`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct QmpStatus {
+	pub running: bool,
+
+	/// i.e. "running", "paused", "shutdown" or "guest-panicked"
+	pub status: String,
+}
+
+/// A connection to QEMU's machine protocol socket.
+pub struct QmpConnection {
+	stream: UnixStream,
+	reader: BufReader<UnixStream>,
+
+	/// Holds a partially received message between reads
+	line: String,
+
+	/// Asynchronous events which haven't been consumed yet
+	events: Vec<Value>,
+}
+
+impl QmpConnection {
+	pub fn new(path: &Path) -> Result<QmpConnection, Box<dyn Error>> {
+		debug!("Attempting QMP connection to: {}", path.display());
+
+		let stream = UnixStream::connect(path)?;
+		let mut qmp = QmpConnection {
+			reader: BufReader::new(stream.try_clone()?),
+			stream,
+			line: String::new(),
+			events: Vec::new(),
+		};
+
+		// The server sends a greeting and then waits for capabilities
+		// negotiation before accepting other commands
+		let greeting = qmp.read(Some(Duration::from_secs(10)))?;
+		if greeting.get("QMP").is_none() {
+			bail!("Unexpected QMP greeting: {}", greeting);
+		}
+		qmp.execute("qmp_capabilities", json!({}))?;
+
+		debug!("Connected to QMP");
+		Ok(qmp)
+	}
+
+	/// Read the next message, waiting up to the given timeout (or forever).
+	fn read(&mut self, timeout: Option<Duration>) -> Result<Value, Box<dyn Error>> {
+		self.stream.set_read_timeout(timeout)?;
+		loop {
+			match self.reader.read_line(&mut self.line) {
+				// The connection closes when QEMU exits
+				Ok(0) => return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into()),
+				Ok(_) => {
+					let message = serde_json::from_str(&self.line)?;
+					self.line.clear();
+					return Ok(message);
+				}
+				Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+				Err(error) => return Err(error.into()),
+			}
+		}
+	}
+
+	/// Run a command and return its result.
+	pub fn execute(&mut self, command: &str, arguments: Value) -> Result<Value, Box<dyn Error>> {
+		debug!("Executing QMP command: {}", command);
+		let mut request = serde_json::to_vec(&json!({
+			"execute": command,
+			"arguments": arguments,
+		}))?;
+		request.push(b'\n');
+		self.stream.write_all(&request)?;
+
+		loop {
+			let mut message = self.read(Some(Duration::from_secs(30)))?;
+			if let Some(value) = message.get_mut("return") {
+				return Ok(value.take());
+			}
+			if let Some(error) = message.get("error") {
+				bail!(
+					"QMP command '{}' failed: {}",
+					command,
+					error["desc"].as_str().unwrap_or("unknown error")
+				);
+			}
+			if message.get("event").is_some() {
+				self.events.push(message);
+			}
+		}
+	}
+
+	/// Take the names of all events received so far.
+	pub fn events(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+		loop {
+			match self.read(Some(Duration::from_millis(10))) {
+				Ok(message) => {
+					if message.get("event").is_some() {
+						self.events.push(message);
+					}
+				}
+				Err(error) => match error.downcast_ref::<std::io::Error>() {
+					// Nothing more to read
+					Some(error)
+						if matches!(
+							error.kind(),
+							ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::UnexpectedEof
+						) =>
+					{
+						break
+					}
+					_ => return Err(error),
+				},
+			}
+		}
+
+		Ok(self
+			.events
+			.drain(..)
+			.filter_map(|event| event["event"].as_str().map(|name| name.to_string()))
+			.collect())
+	}
+
+	/// Press the guest's power button (ACPI shutdown).
+	pub fn system_powerdown(&mut self) -> Result<(), Box<dyn Error>> {
+		info!("Sending system_powerdown");
+		self.execute("system_powerdown", json!({}))?;
+		Ok(())
+	}
+
+	/// Save the guest's display to the given path in PPM format.
+	pub fn screendump(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+		self.execute("screendump", json!({ "filename": path }))?;
+		Ok(())
+	}
+
+	/// Press the given keys (QEMU key codes like "ctrl" or "alt") together.
+	pub fn send_key(&mut self, keys: &[&str]) -> Result<(), Box<dyn Error>> {
+		let keys: Vec<Value> = keys
+			.iter()
+			.map(|key| json!({ "type": "qcode", "data": key }))
+			.collect();
+		self.execute("send-key", json!({ "keys": keys }))?;
+		Ok(())
+	}
+
+	/// Query the VM's run state.
+	pub fn status(&mut self) -> Result<QmpStatus, Box<dyn Error>> {
+		Ok(serde_json::from_value(
+			self.execute("query-status", json!({}))?,
+		)?)
+	}
+}
+
+/// Starts a VM from its arguments.
+pub type Launcher = Arc<dyn Fn(&QemuArgs) -> Result<QemuProcess, Box<dyn Error>> + Send + Sync>;
 
 pub struct QemuProcess {
 	pub process: Child,
 	pub vnc: VncConnection,
+	pub qmp: QmpConnection,
+	pub serial: SerialConnection,
+
+	/// The emulated TPM (stopped after QEMU)
+	pub tpm: Option<Swtpm>,
+
+	/// Environment variables for commands run over SSH
+	pub env: Vec<(String, String)>,
+
+	/// Hooks which run before shutdown and their environment
+	pub hooks: Hooks,
+	pub hook_env: Vec<(&'static str, String)>,
+
+	/// Daemons serving virtiofs shares (stopped after QEMU)
+	pub shares: Vec<Virtiofsd>,
+
+	/// Records the session until QEMU stops
+	pub recorder: Option<Recorder>,
+
+	pub cancel: CancelToken,
 }
 
 impl Drop for QemuProcess {
 	fn drop(&mut self) {
-		self.process.kill().unwrap_or_default();
+		// Make sure the VM doesn't outlive the build
+		if let Ok(None) = self.process.try_wait() {
+			debug!("Stopping QEMU process: {}", self.process.id());
+			self.process.kill().unwrap_or_default();
+			self.process.wait().unwrap_or_default();
+		}
 	}
 }
 
@@ -73,16 +311,39 @@ impl QemuProcess {
 		let cmdline = args.to_cmdline();
 		debug!("QEMU arguments: {:?}", &cmdline);
 
+		// The TPM must be listening before QEMU starts
+		let tpm = match &args.tpm {
+			Some(state) => Some(Swtpm::start(Path::new(state), &args.cancel)?),
+			None => None,
+		};
+
+		let mut shares = Vec::new();
+		for (share, socket) in &args.virtiofs {
+			shares.push(Virtiofsd::start(share, Path::new(socket), &args.cancel)?);
+		}
+
 		// Start the VM
-		let mut process = Command::new(&args.exe)
-			.args(cmdline.iter())
-			.spawn()
-			.unwrap();
+		let process = Command::new(&args.exe).args(cmdline.iter()).spawn()?;
+
+		let mut qemu = QemuProcess::connect(args, process)?;
+		qemu.tpm = tpm;
+		qemu.shares = shares;
+		Ok(qemu)
+	}
 
+	/// Connect to a VM which was started from the given arguments.
+	pub fn connect(args: &QemuArgs, mut process: Child) -> Result<QemuProcess, Box<dyn Error>> {
 		// Connect to VNC
-		let vnc = loop {
-			match VncConnection::new("localhost", args.vnc_port, args.record, args.debug) {
-				Ok(vnc) => break Ok(vnc),
+		let mut vnc = loop {
+			match VncConnection::new(
+				"localhost",
+				args.vnc_port,
+				args.worker,
+				args.record,
+				args.debug,
+				args.cancel.clone(),
+			) {
+				Ok(vnc) => break vnc,
 				Err(_) => {
 					// Check process
 					match process.try_wait() {
@@ -91,15 +352,90 @@ impl QemuProcess {
 						}
 						Ok(None) => {
 							// Wait before trying again
-							std::thread::sleep(Duration::from_secs(5));
+							if let Err(error) = args.cancel.sleep(Duration::from_secs(5)) {
+								process.kill().unwrap_or_default();
+								process.wait().unwrap_or_default();
+								return Err(error);
+							}
 						}
-						Err(e) => break Err(e),
+						Err(e) => return Err(e.into()),
 					}
 				}
 			}
-		}?;
+		};
+
+		vnc.artifacts = args.artifacts.clone();
 
-		Ok(Self { process, vnc })
+		// A failed recording shouldn't fail the build
+		let recorder = if args.timeline {
+			match Recorder::start(
+				args.vnc_port,
+				args.worker,
+				vnc.artifacts_dir().join("timeline.png"),
+				args.cancel.clone(),
+			) {
+				Ok(recorder) => Some(recorder),
+				Err(error) => {
+					warn!("Failed to start recording: {}", error);
+					None
+				}
+			}
+		} else {
+			None
+		};
+
+		// QMP is available once the VM is accepting VNC connections
+		let qmp = match QmpConnection::new(Path::new(&args.qmp)) {
+			Ok(qmp) => qmp,
+			Err(error) => {
+				process.kill().unwrap_or_default();
+				process.wait().unwrap_or_default();
+				return Err(error);
+			}
+		};
+
+		let mut serial = match SerialConnection::new(
+			Path::new(&args.serial),
+			args.worker,
+			args.cancel.clone(),
+		) {
+			Ok(serial) => serial,
+			Err(error) => {
+				process.kill().unwrap_or_default();
+				process.wait().unwrap_or_default();
+				return Err(error);
+			}
+		};
+
+		if let Some(timeout) = args.wait_timeout {
+			vnc.timeout = Duration::from_secs(timeout);
+			serial.timeout = Duration::from_secs(timeout);
+		}
+
+		Ok(Self {
+			process,
+			vnc,
+			qmp,
+			serial,
+			tpm: None,
+			env: args.env.clone(),
+			hooks: args.hooks.clone(),
+			hook_env: args.hook_env.clone(),
+			shares: Vec::new(),
+			recorder,
+			cancel: args.cancel.clone(),
+		})
+	}
+
+	/// Check for guest events which mean the build can't succeed.
+	pub fn check(&mut self) -> Result<(), Box<dyn Error>> {
+		for event in self.qmp.events()? {
+			debug!("Received QMP event: {}", event);
+			if event == "GUEST_PANICKED" {
+				bail!("The guest kernel panicked");
+			}
+		}
+		Ok(())
 	}
 
 	pub fn ssh_wait(
@@ -114,10 +450,19 @@ impl QemuProcess {
 
 		Ok(loop {
 			i += 1;
-			std::thread::sleep(Duration::from_secs(5));
+			self.cancel.sleep(Duration::from_secs(5))?;
+
+			self.check()?;
 
-			match SshConnection::new(port, &username, &password) {
-				Ok(ssh) => break ssh,
+			match SshConnection::new(port, &username, &password, self.cancel.clone()) {
+				Ok(mut ssh) => {
+					ssh.env = self.env.clone();
+					events::emit(BuildEvent::SshConnected {
+						worker: self.vnc.worker,
+						port,
+					});
+					break ssh;
+				}
 				Err(error) => debug!("{}", error),
 			}
 
@@ -127,19 +472,72 @@ impl QemuProcess {
 		})
 	}
 
-	pub fn shutdown_wait(&mut self) -> Result<(), Box<dyn Error>> {
+	/// Run the `post_worker` hooks, then send the shutdown command over SSH
+	/// (falling back to an ACPI shutdown if that fails) and wait for the VM to
+	/// exit.
+	pub fn shutdown(&mut self, ssh: &SshConnection, command: &str) -> Result<(), Box<dyn Error>> {
+		// The worker is done, but its VM is still up for hooks to reach
+		self.hooks
+			.run(HookStage::PostWorker, self.hook_env.clone(), &self.cancel)?;
+
+		// Events from before the shutdown (i.e. a reboot during provisioning)
+		// don't count against it
+		self.check()?;
+
+		if let Err(error) = ssh.shutdown(command) {
+			warn!("Failed to send shutdown command: {}", error);
+			self.qmp.system_powerdown()?;
+		}
+		self.wait_exit(Some(SHUTDOWN_TIMEOUT))
+	}
+
+	/// Wait for the VM to exit. After the timeout an ACPI shutdown is sent and
+	/// after twice the timeout the wait fails. Without a timeout, the wait is
+	/// only limited by the build's timeout.
+	pub fn shutdown_wait(&mut self, timeout: Option<Duration>) -> Result<(), Box<dyn Error>> {
+		self.check()?;
+		self.wait_exit(timeout)
+	}
+
+	/// Wait for the VM to exit, failing on a reboot since the last check.
+	fn wait_exit(&mut self, timeout: Option<Duration>) -> Result<(), Box<dyn Error>> {
 		info!("Waiting for shutdown");
 
+		let start = Instant::now();
+		let mut powerdown = false;
+
 		// Wait for QEMU to exit
-		self.process.wait()?;
+		while self.process.try_wait()?.is_none() {
+			self.cancel.sleep(Duration::from_secs(1))?;
+
+			for event in self.qmp.events()? {
+				debug!("Received QMP event: {}", event);
+				match event.as_str() {
+					"GUEST_PANICKED" => bail!("The guest kernel panicked during shutdown"),
+					"RESET" => bail!("The guest rebooted instead of shutting down"),
+					_ => {}
+				}
+			}
+
+			if let Some(timeout) = timeout {
+				if start.elapsed() > timeout * 2 {
+					bail!("The guest did not shut down");
+				} else if start.elapsed() > timeout && !powerdown {
+					warn!("The guest is taking too long to shut down");
+					self.qmp.system_powerdown()?;
+					powerdown = true;
+				}
+			}
+		}
 		debug!("Shutdown complete");
 		Ok(())
 	}
 }
 
 pub struct QemuArgs {
-	pub bios: String,
+	pub bios: Option<String>,
 	pub boot: String,
+	pub chardev: Vec<String>,
 	pub cpu: Option<String>,
 	pub device: Vec<String>,
 	pub drive: Vec<String>,
@@ -149,57 +547,266 @@ pub struct QemuArgs {
 	pub memory: String,
 	pub name: String,
 	pub netdev: Vec<String>,
+	pub object: Vec<String>,
+	pub qmp: String,
+
+	/// The socket which serves the first serial port
+	pub serial: String,
+
 	pub vnc: Vec<String>,
 	pub smp: String,
-	pub smbios: Option<String>,
+	pub smbios: Vec<String>,
+	pub tpmdev: Vec<String>,
 	pub usbdevice: Vec<String>,
+	pub virtfs: Vec<String>,
+
+	/// Shares served by virtiofsd and the sockets the daemons listen on
+	pub virtiofs: Vec<(ShareConfig, String)>,
+
+	/// The state directory for an emulated TPM (if any)
+	pub tpm: Option<String>,
+
+	/// The bus used by `add_disk`
+	pub disk: DiskBus,
+
+	/// Environment variables for commands run over SSH
+	pub env: Vec<(String, String)>,
+
+	/// The worker's `post_worker` hooks and their environment
+	pub hooks: Hooks,
+	pub hook_env: Vec<(&'static str, String)>,
+
+	/// The network the `user.0` netdev was built from
+	pub network: NetworkConfig,
+
+	/// The default timeout for boot command screen waits in seconds
+	pub wait_timeout: Option<u64>,
+
+	/// Where diagnostics and timelines are written
+	pub artifacts: PathBuf,
+
+	/// Starts the VM in place of QEMU (i.e. a test harness)
+	pub launcher: Option<Launcher>,
 
 	pub exe: String,
 	pub vnc_port: u16,
+	pub worker: usize,
 	pub record: bool,
+	pub timeline: bool,
 	pub debug: bool,
+	pub cancel: CancelToken,
 }
 
 impl QemuArgs {
 	pub fn new(context: &BuildWorker) -> Self {
-		// Choose an appropriate amount of memory or use the configured amount
-		let memory = match &context.config.memory {
-			Some(memory) => memory.clone(),
-			None => String::from("4G"),
-		};
+		let arch = context.config.arch;
+		let accel = detect_accel(arch);
+		let network = context.config.network.clone().unwrap_or_default();
 
-		Self {
-			bios: context.ovmf_path.clone(),
+		let mut args = Self {
+			bios: None,
 			boot: String::from("once=d"),
-			cpu: None,
-			smbios: None,
+			chardev: vec![],
+			cpu: cpu_model(arch, &accel),
+			smbios: vec![],
+			tpmdev: vec![],
+			tpm: None,
 			device: vec![String::from("virtio-net,netdev=user.0")],
 			drive: vec![],
-			// This seems to be necessary for the EFI variables to persist
-			global: vec![String::from("driver=cfi.pflash01,property=secure,value=on")],
-			machine: format!("type=pc,accel={}", detect_accel()),
+			global: vec![],
+			machine: format!("type=pc,accel={}", accel),
 			display: if context.debug && cfg!(target_os = "linux") {
 				String::from("gtk")
 			} else {
 				String::from("none")
 			},
-			memory,
+			memory: format!("{}M", context.memory / 1024 / 1024),
 			name: context.config.name.clone(),
-			smp: String::from("4,sockets=1,cores=4,threads=1"),
-			netdev: vec![format!(
-				"user,id=user.0,hostfwd=tcp::{}-:22",
-				context.ssh_port
-			)],
+			smp: format!("{0},sockets=1,cores={0},threads=1", context.cpus),
+			netdev: vec![network.netdev(context.ssh_port)],
+			qmp: context.tmp.join("qmp.sock").to_string_lossy().to_string(),
+			serial: context
+				.tmp
+				.join("serial.sock")
+				.to_string_lossy()
+				.to_string(),
 			vnc: vec![format!("127.0.0.1:{}", context.vnc_port % 5900)],
 			vnc_port: context.vnc_port,
-			exe: match &context.config.arch {
-				Architecture::amd64 => String::from("qemu-system-x86_64"),
-				Architecture::arm64 => String::from("qemu-system-aarch64"),
-				_ => String::from("qemu-system-x86_64"),
-			},
+			worker: context.id,
+			launcher: context.launcher.clone(),
+			exe: String::from(qemu_binary(arch)),
 			usbdevice: vec![],
+			object: vec![],
+			virtfs: vec![],
+			virtiofs: vec![],
+			disk: match context.config.nvme {
+				Some(true) => DiskBus::Nvme,
+				_ => DiskBus::Virtio,
+			},
+			env: network.env(),
+			hooks: context.config.hooks.clone().unwrap_or_default(),
+			hook_env: context.hook_env(),
+			network,
+			wait_timeout: context.config.wait_timeout,
+			artifacts: context.artifacts.clone(),
 			record: context.record,
+			timeline: context.timeline,
 			debug: context.debug,
+			cancel: context.cancel.clone(),
+		};
+
+		match arch {
+			Architecture::arm64 => {
+				// The virt machine has no display or keyboard by default
+				args.machine = format!("type=virt,gic-version=max,accel={}", accel);
+				args.device.extend([
+					String::from("virtio-gpu-pci"),
+					String::from("qemu-xhci"),
+					String::from("usb-kbd"),
+					String::from("usb-tablet"),
+					// Reports guest panics to QMP
+					String::from("pvpanic-pci"),
+				]);
+			}
+			// Reports guest panics to QMP
+			_ => args.device.push(String::from("pvpanic")),
+		}
+
+		if context.config.tpm.is_some() {
+			let state = context.tpm_path();
+			args.chardev.push(format!(
+				"socket,id=chrtpm,path={}",
+				tpm::socket_path(&state).display()
+			));
+			args.tpmdev
+				.push(String::from("emulator,id=tpm0,chardev=chrtpm"));
+			args.device.push(match arch {
+				Architecture::arm64 => String::from("tpm-tis-device,tpmdev=tpm0"),
+				_ => String::from("tpm-tis,tpmdev=tpm0"),
+			});
+			args.tpm = Some(state.to_string_lossy().to_string());
+		}
+
+		for (index, share) in context.config.shares.iter().flatten().enumerate() {
+			let tag = shares::tag(index);
+			match share.driver {
+				ShareDriver::NineP => args.virtfs.push(format!(
+					"local,path={},mount_tag={},security_model=none,id={}{}",
+					share.source,
+					tag,
+					tag,
+					if share.readonly { ",readonly=on" } else { "" }
+				)),
+				ShareDriver::Virtiofs => {
+					// vhost-user devices need the guest's memory to be shared
+					if args.virtiofs.is_empty() {
+						args.object.push(format!(
+							"memory-backend-memfd,id=mem,size={},share=on",
+							args.memory
+						));
+						args.machine.push_str(",memory-backend=mem");
+					}
+
+					let socket = context.tmp.join(format!("{}.sock", tag));
+					args.chardev
+						.push(format!("socket,id={},path={}", tag, socket.display()));
+					args.device
+						.push(format!("vhost-user-fs-pci,chardev={},tag={}", tag, tag));
+					args.virtiofs
+						.push((share.clone(), socket.to_string_lossy().to_string()));
+				}
+			}
+		}
+
+		// Resemble the target hardware
+		if let Some(hardware) = &context.config.hardware {
+			args.smbios = hardware.smbios.to_qemu();
+			args.disk = hardware.disk;
+			if let Some(nic) = &hardware.nic {
+				if let Some(device) = args
+					.device
+					.iter_mut()
+					.find(|device| device.ends_with(",netdev=user.0"))
+				{
+					*device = format!("{},netdev=user.0", nic);
+				}
+			}
+
+			if arch != Architecture::arm64 {
+				if hardware.cpu_vendor.is_some() || hardware.cpu_model.is_some() {
+					let mut cpu = args.cpu.clone().unwrap_or_else(|| match accel.as_str() {
+						"kvm" => String::from("host"),
+						_ => String::from("max"),
+					});
+					// KVM always reports the host's vendor
+					if let Some(vendor) = &hardware.cpu_vendor {
+						if accel != "kvm" {
+							cpu.push_str(&format!(",vendor={}", vendor));
+						}
+					}
+					if let Some(model) = &hardware.cpu_model {
+						cpu.push_str(&format!(",model-id={}", model.replace(',', ",,")));
+					}
+					args.cpu = Some(cpu);
+				}
+			}
+		}
+
+		// The firmware code and the worker's EFI variables are loaded from a
+		// pair of flash devices. Otherwise QEMU boots with SeaBIOS.
+		if context.config.firmware() == Firmware::Uefi {
+			args.drive.push(format!(
+				"if=pflash,format=raw,unit=0,readonly=on,file={}",
+				context.ovmf_path
+			));
+			args.drive.push(format!(
+				"if=pflash,format=raw,unit=1,file={}",
+				context.ovmf_vars_path
+			));
+		}
+
+		args
+	}
+
+	/// Make an HTTP server on the given host port reachable from the guest and
+	/// return its address there.
+	pub fn serve(&mut self, port: u16) -> String {
+		let (address, netdev) = self.network.server(port);
+		if let Some(netdev) = netdev {
+			self.netdev[0].push_str(&netdev);
+		}
+		address
+	}
+
+	/// Attach the given image as the VM's primary disk.
+	pub fn add_disk(&mut self, path: &str) {
+		self.add_disk_format(path, "qcow2");
+	}
+
+	/// Attach the given image in the given format as the VM's primary disk.
+	pub fn add_disk_format(&mut self, path: &str, format: &str) {
+		match self.disk {
+			DiskBus::Virtio => self.drive.push(format!(
+				"file={},if=virtio,cache=writeback,discard=ignore,format={}",
+				path, format
+			)),
+			DiskBus::Sata => {
+				self.drive.push(format!(
+					"file={},if=none,cache=writeback,discard=ignore,format={},id=disk0",
+					path, format
+				));
+				self.device.push(String::from("ahci,id=ahci"));
+				self.device
+					.push(String::from("ide-hd,drive=disk0,bus=ahci.0"));
+			}
+			DiskBus::Nvme => {
+				self.drive.push(format!(
+					"file={},if=none,cache=writeback,discard=ignore,format={},id=disk0",
+					path, format
+				));
+				self.device
+					.push(String::from("nvme,serial=goldboot,drive=disk0"));
+			}
 		}
 	}
 
@@ -207,8 +814,6 @@ impl QemuArgs {
 		let mut cmdline = vec![
 			String::from("-name"),
 			self.name.clone(),
-			String::from("-bios"),
-			self.bios.clone(),
 			String::from("-m"),
 			self.memory.clone(),
 			String::from("-boot"),
@@ -221,14 +826,25 @@ impl QemuArgs {
 			self.machine.clone(),
 			String::from("-rtc"),
 			String::from("base=utc"),
+			String::from("-qmp"),
+			format!("unix:{},server=on,wait=off", self.qmp),
+			String::from("-chardev"),
+			format!("socket,id=serial0,path={},server=on,wait=off", self.serial),
+			String::from("-serial"),
+			String::from("chardev:serial0"),
 		];
 
+		if let Some(bios) = &self.bios {
+			cmdline.push(String::from("-bios"));
+			cmdline.push(bios.clone());
+		}
+
 		if let Some(cpu) = &self.cpu {
 			cmdline.push(String::from("-cpu"));
 			cmdline.push(cpu.clone());
 		}
 
-		if let Some(smbios) = &self.smbios {
+		for smbios in &self.smbios {
 			cmdline.push(String::from("-smbios"));
 			cmdline.push(smbios.clone());
 		}
@@ -238,6 +854,26 @@ impl QemuArgs {
 			cmdline.push(usbdevice.clone());
 		}
 
+		for object in &self.object {
+			cmdline.push(String::from("-object"));
+			cmdline.push(object.to_string());
+		}
+
+		for virtfs in &self.virtfs {
+			cmdline.push(String::from("-virtfs"));
+			cmdline.push(virtfs.to_string());
+		}
+
+		for chardev in &self.chardev {
+			cmdline.push(String::from("-chardev"));
+			cmdline.push(chardev.to_string());
+		}
+
+		for tpmdev in &self.tpmdev {
+			cmdline.push(String::from("-tpmdev"));
+			cmdline.push(tpmdev.to_string());
+		}
+
 		for global in &self.global {
 			cmdline.push(String::from("-global"));
 			cmdline.push(global.to_string());
@@ -267,6 +903,216 @@ impl QemuArgs {
 	}
 
 	pub fn start_process(&self) -> Result<QemuProcess, Box<dyn Error>> {
-		QemuProcess::new(self)
+		match &self.launcher {
+			Some(launcher) => launcher(self),
+			None => QemuProcess::new(self),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		build::BuildConfig,
+		hardware::HardwareProfile,
+		harness::{blank_screen, FakeQmpServer, FakeSerialServer, Harness},
+		resources::Schedule,
+		templates::windows::windows_10::Windows10Template,
+	};
+	use std::{
+		os::unix::net::UnixListener,
+		process::{ChildStdin, Stdio},
+	};
+
+	/// Start a stand-in VM whose QMP server sends events on demand.
+	fn fake_vm(
+		harness: &Harness,
+		tmp: &Path,
+		config: BuildConfig,
+	) -> Result<(QemuProcess, FakeQmpServer, Option<ChildStdin>), Box<dyn Error>> {
+		let schedule = Schedule {
+			parallel: 1,
+			cpus: 1,
+			memory: 1 << 30,
+		};
+		let worker = BuildWorker {
+			ssh_port: harness.ssh.port,
+			vnc_port: harness.vnc.port,
+			..BuildWorker::plan(
+				0,
+				config,
+				Box::new(Windows10Template::default()),
+				&schedule,
+				tmp.to_path_buf(),
+			)
+		};
+		let args = QemuArgs::new(&worker);
+		let qmp = FakeQmpServer::start(Path::new(&args.qmp))?;
+		FakeSerialServer::start(Path::new(&args.serial))?;
+
+		// Closing its input ends the process like a powered off VM
+		let mut process = Command::new("cat")
+			.stdin(Stdio::piped())
+			.stdout(Stdio::null())
+			.spawn()?;
+		let stdin = process.stdin.take();
+		Ok((QemuProcess::connect(&args, process)?, qmp, stdin))
+	}
+
+	#[test]
+	fn test_hardware() {
+		let config = BuildConfig {
+			hardware: Some(HardwareProfile {
+				disk: DiskBus::Sata,
+				nic: Some(String::from("e1000e")),
+				..Default::default()
+			}),
+			..Default::default()
+		};
+		let schedule = Schedule {
+			parallel: 1,
+			cpus: 1,
+			memory: 1 << 30,
+		};
+		let args = QemuArgs::new(&BuildWorker::plan(
+			0,
+			config,
+			Box::new(Windows10Template::default()),
+			&schedule,
+			PathBuf::from("/tmp"),
+		));
+
+		assert_eq!(args.disk.device(), "/dev/sda");
+		assert!(args.device.contains(&String::from("e1000e,netdev=user.0")));
+		assert!(!args
+			.device
+			.iter()
+			.any(|device| device.starts_with("virtio-net")));
+	}
+
+	#[test]
+	fn test_shutdown_reset() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![blank_screen(640, 480)])?;
+		let ssh = harness.ssh_connection()?;
+
+		// A reboot during provisioning doesn't fail the shutdown
+		let tmp = tempfile::tempdir()?;
+		let (mut qemu, qmp, stdin) = fake_vm(&harness, tmp.path(), BuildConfig::default())?;
+		qmp.event("RESET")?;
+		harness.ssh.on_exec("poweroff", move || drop(stdin));
+		qemu.shutdown(&ssh, "poweroff")?;
+
+		// But a reboot in response to the shutdown command does
+		let tmp = tempfile::tempdir()?;
+		let (mut qemu, qmp, _stdin) = fake_vm(&harness, tmp.path(), BuildConfig::default())?;
+		harness
+			.ssh
+			.on_exec("reboot", move || qmp.event("RESET").unwrap());
+		assert_eq!(
+			qemu.shutdown(&ssh, "reboot").unwrap_err().to_string(),
+			"The guest rebooted instead of shutting down"
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_post_worker_hook() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![blank_screen(640, 480)])?;
+		let ssh = harness.ssh_connection()?;
+
+		let tmp = tempfile::tempdir()?;
+		let output = tmp.path().join("hook");
+		let config = BuildConfig {
+			hooks: Some(Hooks {
+				post_worker: vec![format!(
+					"echo \"$GOLDBOOT_WORKER $GOLDBOOT_SSH_PORT\" >{}",
+					output.display()
+				)],
+				..Default::default()
+			}),
+			..Default::default()
+		};
+		let (mut qemu, _qmp, stdin) = fake_vm(&harness, tmp.path(), config)?;
+		harness.ssh.on_exec("poweroff", move || drop(stdin));
+		qemu.shutdown(&ssh, "poweroff")?;
+		assert_eq!(
+			std::fs::read_to_string(&output)?,
+			format!("0 {}\n", harness.ssh.port)
+		);
+
+		// A failing hook stops the build before the shutdown command
+		let tmp = tempfile::tempdir()?;
+		let config = BuildConfig {
+			hooks: Some(Hooks {
+				post_worker: vec![String::from("exit 1")],
+				..Default::default()
+			}),
+			..Default::default()
+		};
+		let (mut qemu, _qmp, _stdin) = fake_vm(&harness, tmp.path(), config)?;
+		assert!(qemu.shutdown(&ssh, "halt").is_err());
+		assert!(!harness
+			.ssh
+			.executions()
+			.iter()
+			.any(|execution| execution.command == "halt"));
+		Ok(())
+	}
+
+	#[test]
+	fn test_cpu_model() {
+		// Emulated arm64 needs a 64-bit CPU model
+		assert_eq!(
+			cpu_model(Architecture::arm64, "tcg"),
+			Some(String::from("cortex-a72"))
+		);
+		assert_eq!(
+			cpu_model(Architecture::arm64, "kvm"),
+			Some(String::from("host"))
+		);
+		assert_eq!(cpu_model(Architecture::amd64, "kvm"), None);
+
+		if cfg!(target_arch = "x86_64") {
+			assert_eq!(detect_accel(Architecture::arm64), "tcg");
+			assert_eq!(cpu_model(Architecture::i386, "tcg"), None);
+		}
+	}
+
+	#[test]
+	fn test_qmp() -> Result<(), Box<dyn Error>> {
+		let tmp = tempfile::tempdir()?;
+		let path = tmp.path().join("qmp.sock");
+		let listener = UnixListener::bind(&path)?;
+
+		// Answer each command in order, sending an event before the last
+		let server = std::thread::spawn(move || -> std::io::Result<Vec<String>> {
+			let (mut stream, _) = listener.accept()?;
+			let mut reader = BufReader::new(stream.try_clone()?);
+			stream.write_all(b"{\"QMP\": {\"version\": {}, \"capabilities\": []}}\n")?;
+
+			let mut commands = Vec::new();
+			for response in [
+				"{\"return\": {}}\n",
+				"{\"return\": {\"running\": true, \"status\": \"running\"}}\n",
+				"{\"event\": \"GUEST_PANICKED\", \"data\": {}}\n{\"error\": {\"class\": \"GenericError\", \"desc\": \"Invalid parameter\"}}\n",
+			] {
+				let mut line = String::new();
+				reader.read_line(&mut line)?;
+				commands.push(line);
+				stream.write_all(response.as_bytes())?;
+			}
+			Ok(commands)
+		});
+
+		let mut qmp = QmpConnection::new(&path)?;
+		assert!(qmp.status()?.running);
+		assert!(qmp.send_key(&["ctrl", "alt", "delete"]).is_err());
+		assert_eq!(qmp.events()?, vec![String::from("GUEST_PANICKED")]);
+
+		let commands = server.join().unwrap()?;
+		assert!(commands[0].contains("qmp_capabilities"));
+		assert!(commands[2].contains("\"data\":\"delete\""));
+		Ok(())
 	}
 }
@@ -0,0 +1,324 @@
+//! Continuous recordings of build VMs. A recorder watches the screen over its
+//! own VNC connection for the whole session and saves it as an animated PNG
+//! with the current boot command step or provisioner drawn in the corner.
+
+use crate::{
+	cancel::CancelToken,
+	events::{self, BuildEvent},
+	vnc::{VncConnection, VncScreenshot},
+};
+use log::{info, warn};
+use simple_error::bail;
+use std::{
+	error::Error,
+	fs::File,
+	io::BufWriter,
+	path::{Path, PathBuf},
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc,
+	},
+	thread::JoinHandle,
+	time::{Duration, Instant},
+};
+
+/// How often the screen is captured.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many pixels each pixel of a label glyph covers.
+const SCALE: usize = 3;
+
+/// Glyphs for labels where each row is three bits wide.
+#[rustfmt::skip]
+const FONT: &[(char, [u8; 5])] = &[
+	('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+	('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+	('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+	('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+	('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+	('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+	('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+	('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+	('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+	('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+	('E', [0b111, 0b100, 0b111, 0b100, 0b111]),
+	('O', [0b111, 0b101, 0b101, 0b101, 0b111]),
+	('P', [0b111, 0b101, 0b111, 0b100, 0b100]),
+	('R', [0b111, 0b101, 0b110, 0b101, 0b101]),
+	('S', [0b111, 0b100, 0b111, 0b001, 0b111]),
+	('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+	('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+	('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+];
+
+/// The label for events which mark a new part of the session.
+fn label(event: &BuildEvent, worker: usize) -> Option<String> {
+	match event {
+		BuildEvent::BootCommandStep {
+			worker: w,
+			step,
+			total,
+		} if *w == worker => Some(format!("STEP {}/{}", step, total)),
+		BuildEvent::ProvisionerStarted {
+			worker: w, index, ..
+		} if *w == worker => Some(format!("PROV {}", index)),
+		_ => None,
+	}
+}
+
+/// Draw a label in the top left corner of the given framebuffer.
+fn draw_label(data: &mut [u8], width: usize, label: &str) {
+	let glyph_width = 4 * SCALE;
+	let box_width = (label.len() * glyph_width + SCALE).min(width);
+	let box_height = (7 * SCALE).min(data.len() / width);
+
+	for y in 0..box_height {
+		data[y * width..y * width + box_width].fill(0);
+	}
+	for (i, ch) in label.chars().enumerate() {
+		let rows = match FONT.iter().find(|(c, _)| *c == ch) {
+			Some((_, rows)) => rows,
+			None => continue,
+		};
+		for (row, bits) in rows.iter().enumerate() {
+			for column in 0..3 {
+				if bits & (0b100 >> column) == 0 {
+					continue;
+				}
+				let left = SCALE + i * glyph_width + column * SCALE;
+				let top = SCALE + row * SCALE;
+				for y in top..top + SCALE {
+					for x in left..left + SCALE {
+						if x < box_width && y < box_height {
+							data[y * width + x] = 255;
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
+/// A captured screen.
+struct Frame {
+	time: Duration,
+	hash: String,
+	png: Vec<u8>,
+	label: String,
+}
+
+/// A recorded session. Frames are kept compressed until the timeline is
+/// written.
+#[derive(Default)]
+pub struct Timeline {
+	frames: Vec<Frame>,
+
+	/// The largest screen size in the session
+	width: usize,
+	height: usize,
+}
+
+impl Timeline {
+	/// Add a frame unless nothing changed since the last one.
+	pub fn push(
+		&mut self,
+		time: Duration,
+		screenshot: &VncScreenshot,
+		label: &str,
+	) -> Result<(), Box<dyn Error>> {
+		let hash = screenshot.hash();
+		if let Some(last) = self.frames.last() {
+			if last.hash == hash && last.label == label {
+				return Ok(());
+			}
+		}
+
+		let mut png = Vec::new();
+		screenshot.encode_png(&mut png)?;
+		self.width = self.width.max(screenshot.width as usize);
+		self.height = self.height.max(screenshot.height as usize);
+		self.frames.push(Frame {
+			time,
+			hash,
+			png,
+			label: label.to_string(),
+		});
+		Ok(())
+	}
+
+	pub fn len(&self) -> usize {
+		self.frames.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.frames.is_empty()
+	}
+
+	/// Encode the timeline as an animated PNG which plays in real time. Frames
+	/// are padded to the largest screen size in the session.
+	pub fn write_apng(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+		if self.frames.is_empty() {
+			bail!("Nothing was recorded");
+		}
+
+		let (width, height) = (self.width, self.height);
+
+		std::fs::create_dir_all(path.parent().unwrap())?;
+		let mut encoder = png::Encoder::new(
+			BufWriter::new(File::create(path)?),
+			width as u32,
+			height as u32,
+		);
+		encoder.set_color(png::ColorType::Grayscale);
+		encoder.set_depth(png::BitDepth::Eight);
+		encoder.set_animated(self.frames.len() as u32, 0)?;
+		let mut writer = encoder.write_header()?;
+
+		// Frames are decoded one at a time to keep memory use down
+		for (i, frame) in self.frames.iter().enumerate() {
+			let screen = VncScreenshot::read_png(&frame.png)?;
+			let delay = match self.frames.get(i + 1) {
+				Some(next) => next.time.saturating_sub(frame.time),
+				None => INTERVAL,
+			};
+			writer.set_frame_delay(delay.as_millis().min(u16::MAX as u128) as u16, 1000)?;
+
+			let mut data = vec![0u8; width * height];
+			for y in 0..screen.height as usize {
+				let src = y * screen.width as usize;
+				data[y * width..y * width + screen.width as usize]
+					.copy_from_slice(&screen.data[src..src + screen.width as usize]);
+			}
+			draw_label(&mut data, width, &frame.label);
+			writer.write_image_data(&data)?;
+		}
+		writer.finish()?;
+		Ok(())
+	}
+}
+
+/// Records a VM's screen in the background until it's dropped, when the
+/// timeline is written out.
+pub struct Recorder {
+	pub path: PathBuf,
+	stop: Arc<AtomicBool>,
+	thread: Option<JoinHandle<Timeline>>,
+}
+
+impl Drop for Recorder {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::Relaxed);
+		if let Some(thread) = self.thread.take() {
+			match thread.join() {
+				Ok(timeline) => match timeline.write_apng(&self.path) {
+					Ok(()) => info!(
+						"Saved recording of {} frames to: {}",
+						timeline.len(),
+						self.path.display()
+					),
+					Err(error) => warn!("Failed to save recording: {}", error),
+				},
+				Err(_) => warn!("Recording stopped unexpectedly"),
+			}
+		}
+	}
+}
+
+impl Recorder {
+	/// Start recording the VNC server on the given port.
+	pub fn start(
+		port: u16,
+		worker: usize,
+		path: PathBuf,
+		cancel: CancelToken,
+	) -> Result<Recorder, Box<dyn Error>> {
+		let mut vnc = VncConnection::new("localhost", port, worker, false, false, cancel)?;
+		let events = events::subscribe();
+		let stop = Arc::new(AtomicBool::new(false));
+
+		let thread = {
+			let stop = stop.clone();
+			std::thread::spawn(move || {
+				let start = Instant::now();
+				let mut timeline = Timeline::default();
+				let mut current = String::new();
+
+				while !stop.load(Ordering::Relaxed) {
+					for event in events.try_iter() {
+						if let Some(label) = label(&event, worker) {
+							current = label;
+						}
+					}
+
+					// The connection fails once the VM is gone
+					let screenshot = match vnc.screenshot() {
+						Ok(screenshot) => screenshot,
+						Err(_) => break,
+					};
+					if let Err(error) = timeline.push(start.elapsed(), &screenshot, &current) {
+						warn!("Failed to record frame: {}", error);
+						break;
+					}
+					std::thread::sleep(INTERVAL);
+				}
+				timeline
+			})
+		};
+
+		Ok(Recorder {
+			path,
+			stop,
+			thread: Some(thread),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::harness::{blank_screen, Harness};
+
+	#[test]
+	fn test_recording() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![blank_screen(640, 480)])?;
+		let tmp = tempfile::tempdir()?;
+		let path = tmp.path().join("timeline.png");
+
+		let recorder = Recorder::start(harness.vnc.port, 7, path.clone(), harness.cancel.clone())?;
+		events::emit(BuildEvent::BootCommandStep {
+			worker: 7,
+			step: 1,
+			total: 2,
+		});
+		std::thread::sleep(Duration::from_secs(2));
+		drop(recorder);
+
+		let reader = png::Decoder::new(std::io::BufReader::new(File::open(&path)?)).read_info()?;
+		let info = reader.info();
+		assert_eq!((info.width, info.height), (640, 480));
+		assert!(info.animation_control.unwrap().num_frames >= 1);
+
+		let mut data = vec![0u8; 100 * 30];
+		draw_label(&mut data, 100, "STEP 1/2");
+		assert!(data.contains(&255));
+		Ok(())
+	}
+
+	#[test]
+	fn test_timeline_sizes() -> Result<(), Box<dyn Error>> {
+		let tmp = tempfile::tempdir()?;
+		let path = tmp.path().join("timeline.png");
+
+		// The screen grows when the installer changes resolution
+		let mut timeline = Timeline::default();
+		timeline.push(Duration::from_secs(0), &blank_screen(320, 240), "")?;
+		timeline.push(Duration::from_secs(1), &blank_screen(640, 200), "STEP 1/1")?;
+		timeline.write_apng(&path)?;
+
+		let reader = png::Decoder::new(std::io::BufReader::new(File::open(&path)?)).read_info()?;
+		let info = reader.info();
+		assert_eq!((info.width, info.height), (640, 240));
+		assert_eq!(info.animation_control.unwrap().num_frames, 2);
+		Ok(())
+	}
+}
@@ -0,0 +1,235 @@
+//! Host resource management for build workers. Ports are reserved so parallel
+//! workers (and other builds in this process) never receive the same one, and
+//! the number of concurrent workers is limited by the host's CPUs and memory.
+
+use log::debug;
+use rand::Rng;
+use simple_error::bail;
+use std::{collections::BTreeSet, error::Error, net::TcpListener, sync::Mutex};
+use validator::ValidationError;
+
+/// The number of vCPUs given to a worker when the host has plenty.
+const DEFAULT_CPUS: usize = 4;
+
+/// The amount of memory given to a worker if the config doesn't specify one.
+const DEFAULT_MEMORY: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Ports which are currently held by a `PortLease`.
+static RESERVED_PORTS: Mutex<BTreeSet<u16>> = Mutex::new(BTreeSet::new());
+
+/// A port reserved for exclusive use until the lease is dropped.
+#[derive(Debug)]
+pub struct PortLease {
+	pub port: u16,
+}
+
+impl Drop for PortLease {
+	fn drop(&mut self) {
+		RESERVED_PORTS.lock().unwrap().remove(&self.port);
+	}
+}
+
+/// Reserve a random port in the given range which is open on the host and not
+/// already leased.
+pub fn reserve_port(lower: u16, upper: u16) -> Result<PortLease, Box<dyn Error>> {
+	if upper <= lower {
+		bail!("Invalid port range: {}-{}", lower, upper);
+	}
+
+	let mut reserved = RESERVED_PORTS.lock().unwrap();
+
+	// Start at a random offset so concurrent goldboot processes are unlikely
+	// to race for the same port
+	let range = upper - lower;
+	let start = rand::thread_rng().gen_range(0..range);
+
+	for offset in 0..range {
+		let port = lower + (start + offset) % range;
+		if reserved.contains(&port) {
+			continue;
+		}
+		if TcpListener::bind(format!("0.0.0.0:{port}")).is_ok() {
+			reserved.insert(port);
+			debug!("Reserved port: {}", port);
+			return Ok(PortLease { port });
+		}
+	}
+	bail!("No open ports available in range: {}-{}", lower, upper);
+}
+
+/// Parse a memory size as QEMU does (i.e. "4G" or "512M"). Suffixes are binary
+/// and a bare number is in megabytes.
+pub fn parse_memory(memory: &str) -> Result<u64, Box<dyn Error>> {
+	let memory = memory.trim();
+	let (number, multiplier) = match memory.chars().last() {
+		Some('K' | 'k') => (&memory[..memory.len() - 1], 1024),
+		Some('M' | 'm') => (&memory[..memory.len() - 1], 1024 * 1024),
+		Some('G' | 'g') => (&memory[..memory.len() - 1], 1024 * 1024 * 1024),
+		Some('T' | 't') => (&memory[..memory.len() - 1], 1024 * 1024 * 1024 * 1024),
+		_ => (memory, 1024 * 1024),
+	};
+
+	match number.parse::<u64>() {
+		Ok(number) if number > 0 => Ok(number * multiplier),
+		_ => bail!("Invalid memory size: {}", memory),
+	}
+}
+
+/// Check that a memory size (i.e. "4G") can be parsed.
+pub fn validate_memory(memory: &str) -> Result<(), ValidationError> {
+	match parse_memory(memory) {
+		Ok(_) => Ok(()),
+		Err(_) => Err(ValidationError::new("memory")),
+	}
+}
+
+/// The resources available on the build host.
+#[derive(Clone, Debug)]
+pub struct HostResources {
+	/// The number of logical CPUs
+	pub cpus: usize,
+
+	/// The amount of available memory in bytes (if it can be determined)
+	pub memory: Option<u64>,
+}
+
+impl HostResources {
+	pub fn detect() -> Self {
+		Self {
+			cpus: std::thread::available_parallelism()
+				.map(|cpus| cpus.get())
+				.unwrap_or(1),
+			memory: available_memory(),
+		}
+	}
+}
+
+/// Read the amount of available memory from /proc/meminfo.
+fn available_memory() -> Option<u64> {
+	let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+	let line = meminfo
+		.lines()
+		.find(|line| line.starts_with("MemAvailable:"))?;
+
+	// Formatted as "MemAvailable:   12345678 kB"
+	let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+	Some(kilobytes * 1024)
+}
+
+/// Describes how a build job's workers will share the host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Schedule {
+	/// The maximum number of workers that run at the same time
+	pub parallel: usize,
+
+	/// The number of vCPUs given to each worker
+	pub cpus: usize,
+
+	/// The amount of memory given to each worker in bytes
+	pub memory: u64,
+}
+
+impl Schedule {
+	/// Plan the given number of workers. The configured memory is never
+	/// reduced; instead fewer workers run at once (the rest are queued).
+	pub fn new(
+		workers: usize,
+		jobs: Option<usize>,
+		memory: Option<u64>,
+		host: &HostResources,
+	) -> Self {
+		let memory = memory.unwrap_or(DEFAULT_MEMORY);
+
+		let mut parallel = workers.max(1);
+		if let Some(jobs) = jobs {
+			parallel = parallel.min(jobs.max(1));
+		}
+
+		// Each worker needs at least one CPU
+		parallel = parallel.min(host.cpus.max(1));
+
+		if let Some(available) = host.memory {
+			parallel = parallel.min((available / memory).max(1) as usize);
+		}
+
+		Self {
+			parallel,
+			cpus: (host.cpus / parallel).clamp(1, DEFAULT_CPUS),
+			memory,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_reserve_port() -> Result<(), Box<dyn Error>> {
+		let port = reserve_port(9000, 9999)?.port;
+
+		assert!(port < 9999);
+		assert!(port >= 9000);
+
+		// Empty ranges are rejected
+		assert!(reserve_port(9000, 9000).is_err());
+		assert!(reserve_port(9999, 9000).is_err());
+		Ok(())
+	}
+
+	#[test]
+	fn test_reserve_port_lease() -> Result<(), Box<dyn Error>> {
+		// Let the OS choose a port that's free on the host
+		let lower = TcpListener::bind("0.0.0.0:0")?.local_addr()?.port();
+
+		let lease = reserve_port(lower, lower + 1)?;
+		assert_eq!(lease.port, lower);
+
+		// A leased port isn't handed out again
+		assert!(reserve_port(lower, lower + 1).is_err());
+
+		// The port can be reserved again once released
+		drop(lease);
+		assert_eq!(reserve_port(lower, lower + 1)?.port, lower);
+		Ok(())
+	}
+
+	#[test]
+	fn test_parse_memory() {
+		assert_eq!(parse_memory("4G").unwrap(), 4294967296);
+		assert_eq!(parse_memory("512").unwrap(), 536870912);
+		assert!(parse_memory("4 gallons").is_err());
+		assert!(parse_memory("0M").is_err());
+	}
+
+	#[test]
+	fn test_schedule() {
+		let host = HostResources {
+			cpus: 8,
+			memory: Some(10 * 1024 * 1024 * 1024),
+		};
+
+		// Memory limits the number of parallel workers
+		assert_eq!(
+			Schedule::new(4, None, None, &host),
+			Schedule {
+				parallel: 2,
+				cpus: 4,
+				memory: DEFAULT_MEMORY,
+			}
+		);
+
+		// CPUs are divided between parallel workers
+		assert_eq!(
+			Schedule::new(4, None, Some(1024 * 1024 * 1024), &host),
+			Schedule {
+				parallel: 4,
+				cpus: 2,
+				memory: 1024 * 1024 * 1024,
+			}
+		);
+
+		// The jobs limit takes precedence
+		assert_eq!(Schedule::new(4, Some(1), None, &host).parallel, 1);
+	}
+}
@@ -0,0 +1,182 @@
+//! Boot library images to check that they work. The image is written to a
+//! temporary disk along with any saved EFI variables and TPM state, so nothing
+//! is kept after the VM exits.
+
+use crate::{
+	build::{BuildJob, BuildWorker},
+	image::ImageHandle,
+	qemu::{QemuArgs, QemuProcess},
+	ssh::SshConnection,
+	tpm,
+};
+use log::{error, info};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use simple_error::bail;
+use std::{error::Error, path::PathBuf, time::Duration};
+use validator::Validate;
+
+/// Checks for `goldboot test` which are run in the booted image.
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
+pub struct TestConfig {
+	/// The user to log in as over SSH
+	#[serde(default = "default_username")]
+	pub username: String,
+
+	/// The user's password
+	pub password: String,
+
+	/// Commands which must exit successfully
+	#[validate(length(min = 1))]
+	pub commands: Vec<String>,
+
+	/// The command which powers off the image after testing
+	#[serde(default = "default_shutdown")]
+	pub shutdown: String,
+}
+
+fn default_username() -> String {
+	String::from("root")
+}
+
+fn default_shutdown() -> String {
+	String::from("poweroff")
+}
+
+impl TestConfig {
+	/// Run each command and describe the ones that failed.
+	pub fn run(&self, ssh: &mut SshConnection) -> Result<Vec<String>, Box<dyn Error>> {
+		let mut failures = Vec::new();
+		for command in &self.commands {
+			info!("Testing: {}", command);
+			match ssh.exec(command)? {
+				0 => {}
+				code => {
+					error!("Test failed with exit code {}: {}", code, command);
+					failures.push(format!("{} (exit code {})", command, code));
+				}
+			}
+		}
+		Ok(failures)
+	}
+}
+
+/// Represents a temporary VM running a library image.
+pub struct RunJob {
+	pub worker: BuildWorker,
+
+	/// The image's raw disk
+	pub disk: PathBuf,
+}
+
+impl RunJob {
+	/// Prepare a VM for the given image which must already be loaded.
+	pub fn new(image: &ImageHandle) -> Result<Self, Box<dyn Error>> {
+		let mut config = match &image.config {
+			Some(config) => config.clone(),
+			None => bail!("Image not loaded"),
+		};
+
+		// Build hooks don't apply to test runs
+		config.hooks = None;
+
+		// The worker is set up like the one which built the image
+		let job = BuildJob::new(config.clone(), false, false, Some(1));
+		let template = match config.get_templates()?.into_iter().next() {
+			Some(template) => template,
+			None => bail!("Image config has no templates"),
+		};
+		let worker = job.new_worker(0, template, &job.schedule()?)?;
+
+		// Library images store compressed (and possibly encrypted) clusters
+		// which QEMU can't read, so they can't back a qcow2 overlay. Boot a
+		// temporary raw copy instead.
+		let disk = worker.tmp.join("disk.raw");
+		image.write(&disk)?;
+
+		// Restore the state the build saved for its first worker
+		let vars = PathBuf::from(format!("{}.vars", image.path.display())).join("0.fd");
+		if vars.is_file() {
+			info!("Loading EFI variables from: {}", vars.display());
+			std::fs::copy(&vars, &worker.ovmf_vars_path)?;
+		}
+
+		let state = PathBuf::from(format!("{}.tpm", image.path.display())).join("0");
+		if worker.config.tpm.is_some() && state.is_dir() {
+			tpm::restore_state(&state, &worker.tpm_path())?;
+		}
+
+		Ok(Self { worker, disk })
+	}
+
+	pub fn start(&self) -> Result<QemuProcess, Box<dyn Error>> {
+		let mut qemuargs = QemuArgs::new(&self.worker);
+		qemuargs.boot = String::from("c");
+		qemuargs.add_disk_format(&self.disk.to_string_lossy(), "raw");
+
+		let qemu = qemuargs.start_process()?;
+		info!(
+			"Image is running (VNC on 127.0.0.1:{}, SSH on 127.0.0.1:{})",
+			self.worker.vnc_port, self.worker.ssh_port
+		);
+		Ok(qemu)
+	}
+
+	/// Boot the image and wait for the VM to exit.
+	pub fn run(&self) -> Result<(), Box<dyn Error>> {
+		let mut qemu = self.start()?;
+		while qemu.process.try_wait()?.is_none() {
+			self.worker.cancel.sleep(Duration::from_secs(1))?;
+			qemu.check()?;
+		}
+		Ok(())
+	}
+
+	/// Boot the image and run the given tests over SSH.
+	pub fn test(&self, config: &TestConfig) -> Result<(), Box<dyn Error>> {
+		let mut qemu = self.start()?;
+		let mut ssh = qemu.ssh_wait(self.worker.ssh_port, &config.username, &config.password)?;
+
+		let failures = config.run(&mut ssh)?;
+		qemu.shutdown(&ssh, &config.shutdown)?;
+
+		if !failures.is_empty() {
+			bail!("{} test(s) failed: {}", failures.len(), failures.join(", "));
+		}
+		info!("All tests passed");
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::harness::{Harness, Response};
+
+	#[test]
+	fn test_run_commands() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![])?;
+		harness.ssh.respond(
+			"systemctl is-active sshd",
+			Response {
+				exit_code: 3,
+				..Default::default()
+			},
+		);
+
+		let config = TestConfig {
+			username: String::from("root"),
+			password: String::from("root"),
+			commands: vec![
+				String::from("test -f /etc/hostname"),
+				String::from("systemctl is-active sshd"),
+			],
+			shutdown: default_shutdown(),
+		};
+		assert_eq!(
+			config.run(&mut harness.ssh_connection()?)?,
+			vec![String::from("systemctl is-active sshd (exit code 3)")]
+		);
+		Ok(())
+	}
+}
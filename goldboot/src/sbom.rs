@@ -0,0 +1,216 @@
+//! Software inventory for built images. After provisioning, the guest's
+//! package manager is queried over SSH and the result is stored in the image
+//! as a CycloneDX document.
+
+use crate::ssh::SshConnection;
+use log::debug;
+use serde_json::json;
+use simple_error::bail;
+use std::error::Error;
+
+/// A package installed in the guest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Package {
+	pub name: String,
+	pub version: String,
+}
+
+/// The package managers that can be queried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PackageManager {
+	Pacman,
+	Dpkg,
+	Apk,
+	Rpm,
+}
+
+impl PackageManager {
+	/// Find the package manager installed in the guest (if any).
+	pub fn detect(ssh: &mut SshConnection) -> Result<Option<Self>, Box<dyn Error>> {
+		for (binary, manager) in [
+			("pacman", PackageManager::Pacman),
+			("dpkg-query", PackageManager::Dpkg),
+			("apk", PackageManager::Apk),
+			("rpm", PackageManager::Rpm),
+		] {
+			let (code, _) = ssh.exec_output(&format!("command -v {}", binary))?;
+			if code == 0 {
+				debug!("Detected package manager: {:?}", manager);
+				return Ok(Some(manager));
+			}
+		}
+		Ok(None)
+	}
+
+	/// The command which lists installed packages.
+	pub fn query(&self) -> &'static str {
+		match self {
+			PackageManager::Pacman => "pacman -Q",
+			PackageManager::Dpkg => "dpkg-query -W -f='${Package} ${Version}\\n'",
+			PackageManager::Apk => "apk info -v",
+			PackageManager::Rpm => "rpm -qa --qf '%{NAME} %{VERSION}-%{RELEASE}\\n'",
+		}
+	}
+
+	/// The package URL type for packages from this manager.
+	pub fn purl_type(&self) -> &'static str {
+		match self {
+			PackageManager::Pacman => "alpm",
+			PackageManager::Dpkg => "deb",
+			PackageManager::Apk => "apk",
+			PackageManager::Rpm => "rpm",
+		}
+	}
+
+	/// Parse the output of the query command.
+	pub fn parse(&self, output: &str) -> Vec<Package> {
+		output
+			.lines()
+			.map(|line| line.trim())
+			.filter(|line| line.len() > 0)
+			.filter_map(|line| match self {
+				// Formatted as "name-version-release"
+				PackageManager::Apk => {
+					let mut parts = line.rsplitn(3, '-');
+					let release = parts.next()?;
+					let version = parts.next()?;
+					Some(Package {
+						name: parts.next()?.to_string(),
+						version: format!("{}-{}", version, release),
+					})
+				}
+				_ => {
+					let (name, version) = line.split_once(' ')?;
+					Some(Package {
+						name: name.to_string(),
+						version: version.trim().to_string(),
+					})
+				}
+			})
+			.collect()
+	}
+}
+
+/// Query the guest's installed packages and produce a CycloneDX document. If
+/// no supported package manager is found, nothing is returned.
+pub fn capture(
+	ssh: &mut SshConnection,
+	name: &str,
+) -> Result<Option<serde_json::Value>, Box<dyn Error>> {
+	let manager = match PackageManager::detect(ssh)? {
+		Some(manager) => manager,
+		None => return Ok(None),
+	};
+
+	let (code, output) = ssh.exec_output(manager.query())?;
+	if code != 0 {
+		bail!("Package query failed with exit code: {}", code);
+	}
+
+	Ok(Some(cyclonedx(name, manager, &manager.parse(&output))))
+}
+
+/// Build a CycloneDX document for the given packages. The document contains no
+/// timestamp or serial number so identical package sets produce identical
+/// documents.
+pub fn cyclonedx(
+	name: &str,
+	manager: PackageManager,
+	packages: &Vec<Package>,
+) -> serde_json::Value {
+	json!({
+		"bomFormat": "CycloneDX",
+		"specVersion": "1.4",
+		"version": 1,
+		"metadata": {
+			"tools": [{
+				"vendor": "goldboot",
+				"name": "goldboot",
+				"version": env!("CARGO_PKG_VERSION"),
+			}],
+			"component": {
+				"type": "operating-system",
+				"name": name,
+			},
+		},
+		"components": packages.iter().map(|package| json!({
+			"type": "library",
+			"name": package.name,
+			"version": package.version,
+			"purl": format!("pkg:{}/{}@{}", manager.purl_type(), package.name, package.version),
+		})).collect::<Vec<serde_json::Value>>(),
+	})
+}
+
+/// Combine the components of several documents (i.e. from each multiboot
+/// worker) into the first one.
+pub fn merge(mut documents: Vec<serde_json::Value>) -> Option<serde_json::Value> {
+	if documents.len() == 0 {
+		return None;
+	}
+
+	let mut merged = documents.remove(0);
+	for document in documents {
+		if let (Some(components), Some(other)) = (
+			merged["components"].as_array_mut(),
+			document["components"].as_array(),
+		) {
+			components.extend(other.iter().cloned());
+		}
+	}
+	Some(merged)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(
+			PackageManager::Pacman.parse("linux 5.18.12.arch1-1\nzstd 1.5.2-7\n"),
+			vec![
+				Package {
+					name: String::from("linux"),
+					version: String::from("5.18.12.arch1-1"),
+				},
+				Package {
+					name: String::from("zstd"),
+					version: String::from("1.5.2-7"),
+				},
+			]
+		);
+		assert_eq!(
+			PackageManager::Apk.parse("alpine-base-3.16.0-r0\nmusl-1.2.3-r0\n"),
+			vec![
+				Package {
+					name: String::from("alpine-base"),
+					version: String::from("3.16.0-r0"),
+				},
+				Package {
+					name: String::from("musl"),
+					version: String::from("1.2.3-r0"),
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn test_cyclonedx() {
+		let document = cyclonedx(
+			"Test",
+			PackageManager::Dpkg,
+			&PackageManager::Dpkg.parse("bash 5.1-2+deb11u1\n"),
+		);
+
+		assert_eq!(document["bomFormat"], "CycloneDX");
+		assert_eq!(document["components"][0]["name"], "bash");
+		assert_eq!(
+			document["components"][0]["purl"],
+			"pkg:deb/bash@5.1-2+deb11u1"
+		);
+
+		let merged = merge(vec![document.clone(), document]).unwrap();
+		assert_eq!(merged["components"].as_array().unwrap().len(), 2);
+	}
+}
@@ -0,0 +1,253 @@
+//! Boot automation over a VM's serial console. Templates can script installs
+//! by waiting for text output instead of screen hashes, which don't survive
+//! changes to fonts or resolutions.
+
+use crate::{
+	cancel::CancelToken,
+	events::{self, BuildEvent},
+	vnc::DEFAULT_WAIT_TIMEOUT,
+};
+use log::{debug, info, trace};
+use regex::bytes::Regex;
+use simple_error::bail;
+use std::{
+	error::Error,
+	io::{Read, Write},
+	os::unix::net::UnixStream,
+	path::Path,
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+/// How much unmatched console output is kept for waits to search.
+const MAX_OUTPUT: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub enum SerialCmd {
+	/// Write the given text to the console.
+	Send(String),
+
+	/// Wait for the console output to match the given regular expression. The
+	/// last field overrides the connection's timeout in seconds.
+	WaitText(String, Option<u64>),
+
+	/// Wait the given amount of seconds.
+	Wait(u64),
+}
+
+/// Represents a connection to a running VM's serial console.
+pub struct SerialConnection {
+	stream: UnixStream,
+
+	/// Console output that hasn't been matched by a wait yet
+	output: Arc<Mutex<Vec<u8>>>,
+
+	pub worker: usize,
+	pub cancel: CancelToken,
+
+	/// How long waits last unless the command has its own timeout
+	pub timeout: Duration,
+}
+
+impl SerialConnection {
+	pub fn new(
+		path: &Path,
+		worker: usize,
+		cancel: CancelToken,
+	) -> Result<SerialConnection, Box<dyn Error>> {
+		debug!("Connecting to serial console: {}", path.display());
+		let stream = UnixStream::connect(path)?;
+		let output = Arc::new(Mutex::new(Vec::new()));
+
+		// Read continuously so the guest never blocks on a full console
+		let mut reader = stream.try_clone()?;
+		let buffer = output.clone();
+		std::thread::spawn(move || {
+			let mut chunk = [0u8; 4096];
+			loop {
+				match reader.read(&mut chunk) {
+					Ok(0) | Err(_) => break,
+					Ok(size) => {
+						trace!("Serial: {}", String::from_utf8_lossy(&chunk[..size]));
+						let mut output = buffer.lock().unwrap();
+						output.extend_from_slice(&chunk[..size]);
+
+						// Drop the oldest output nothing has waited for
+						if output.len() > MAX_OUTPUT {
+							let excess = output.len() - MAX_OUTPUT;
+							output.drain(..excess);
+						}
+					}
+				}
+			}
+		});
+
+		Ok(Self {
+			stream,
+			output,
+			worker,
+			cancel,
+			timeout: DEFAULT_WAIT_TIMEOUT,
+		})
+	}
+
+	/// Write the given text to the console.
+	pub fn send(&mut self, text: &str) -> Result<(), Box<dyn Error>> {
+		self.stream.write_all(text.as_bytes())?;
+		Ok(())
+	}
+
+	/// Wait for the console output to match the given regular expression and
+	/// return the matching text. Output up to the end of the match is consumed
+	/// so it can't satisfy later waits.
+	pub fn wait_text(
+		&mut self,
+		pattern: &str,
+		timeout: Option<u64>,
+	) -> Result<String, Box<dyn Error>> {
+		debug!("Waiting for serial output to match: {}", pattern);
+		let regex = Regex::new(pattern)?;
+		let timeout = timeout.map(Duration::from_secs).unwrap_or(self.timeout);
+		let start = Instant::now();
+		loop {
+			{
+				let mut output = self.output.lock().unwrap();
+				if let Some(found) = regex.find(&output) {
+					let text = String::from_utf8_lossy(found.as_bytes()).to_string();
+					let end = found.end();
+					output.drain(..end);
+					return Ok(text);
+				}
+
+				if start.elapsed() >= timeout {
+					let tail = &output[output.len().saturating_sub(200)..];
+					bail!(
+						"Timed out after {} seconds waiting for serial output to match '{}' (last output: {:?})",
+						timeout.as_secs(),
+						pattern,
+						String::from_utf8_lossy(tail)
+					);
+				}
+			}
+			self.cancel.sleep(Duration::from_millis(100))?;
+		}
+	}
+
+	pub fn boot_command(&mut self, command: Vec<Vec<SerialCmd>>) -> Result<(), Box<dyn Error>> {
+		info!("Running bootstrap sequence over serial");
+
+		let total = command.iter().map(|step| step.len()).sum();
+		let mut step_number = 0;
+		for step in command {
+			for item in step {
+				self.cancel.check()?;
+				step_number += 1;
+				events::emit(BuildEvent::BootCommandStep {
+					worker: self.worker,
+					step: step_number,
+					total,
+				});
+
+				match item {
+					SerialCmd::Send(text) => self.send(&text)?,
+					SerialCmd::WaitText(pattern, timeout) => {
+						self.wait_text(&pattern, timeout)?;
+					}
+					SerialCmd::Wait(duration) => {
+						debug!("Waiting {} seconds", &duration);
+						self.cancel.sleep(Duration::from_secs(duration))?;
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+pub mod serialcmds {
+
+	#[macro_export]
+	macro_rules! send {
+		($text:expr) => {
+			vec![crate::serial::SerialCmd::Send(format!("{}\n", $text))]
+		};
+	}
+
+	#[macro_export]
+	macro_rules! wait_text {
+		($pattern:expr) => {
+			vec![crate::serial::SerialCmd::WaitText(
+				$pattern.to_string(),
+				None,
+			)]
+		};
+		($pattern:expr, timeout = $timeout:expr) => {
+			vec![crate::serial::SerialCmd::WaitText(
+				$pattern.to_string(),
+				Some($timeout),
+			)]
+		};
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{send, wait_text};
+	use std::{io::BufRead, os::unix::net::UnixListener};
+
+	#[test]
+	fn test_boot_command() -> Result<(), Box<dyn Error>> {
+		let tmp = tempfile::tempdir()?;
+		let path = tmp.path().join("serial.sock");
+		let listener = UnixListener::bind(&path)?;
+
+		// A console which asks for a login and echoes the response
+		let guest = std::thread::spawn(move || -> std::io::Result<String> {
+			let (mut stream, _) = listener.accept()?;
+			stream.write_all(b"Welcome to Alpine Linux\r\nlocalhost login: ")?;
+			let mut line = String::new();
+			std::io::BufReader::new(stream.try_clone()?).read_line(&mut line)?;
+			stream.write_all(format!("{}localhost:~# ", line).as_bytes())?;
+			Ok(line)
+		});
+
+		let mut serial = SerialConnection::new(&path, 0, CancelToken::new(None))?;
+		serial.boot_command(vec![
+			wait_text!(r"login: $"),
+			send!("root"),
+			wait_text!(r"root\s+localhost:~# "),
+		])?;
+
+		assert_eq!(guest.join().unwrap()?, "root\n");
+		Ok(())
+	}
+
+	#[test]
+	fn test_wait_timeout() -> Result<(), Box<dyn Error>> {
+		let tmp = tempfile::tempdir()?;
+		let path = tmp.path().join("serial.sock");
+		let listener = UnixListener::bind(&path)?;
+
+		// A console which never shows a login prompt
+		let guest = std::thread::spawn(move || -> std::io::Result<()> {
+			let (mut stream, _) = listener.accept()?;
+			stream.write_all(b"boot: ")
+		});
+
+		let mut serial = SerialConnection::new(&path, 0, CancelToken::new(None))?;
+		serial.timeout = Duration::from_secs(1);
+		let error = serial
+			.boot_command(vec![wait_text!(r"login: $")])
+			.unwrap_err();
+		assert!(error.to_string().contains("boot: "), "{}", error);
+
+		// The command's own timeout takes precedence
+		let start = Instant::now();
+		assert!(serial.wait_text("never", Some(0)).is_err());
+		assert!(start.elapsed() < Duration::from_secs(1));
+
+		guest.join().unwrap()?;
+		Ok(())
+	}
+}
@@ -0,0 +1,192 @@
+//! Host directories shared into build VMs. Large assets like installers or
+//! offline package repositories can then be used by provisioners without
+//! uploading them over SSH.
+
+use crate::{
+	cancel::CancelToken,
+	ssh::{self, SshConnection},
+};
+use log::{debug, info};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use simple_error::bail;
+use std::{
+	error::Error,
+	path::{Path, PathBuf},
+	process::{Child, Command},
+	time::Duration,
+};
+
+/// How a directory is exposed to the guest.
+#[derive(Clone, Copy, Serialize, Deserialize, JsonSchema, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShareDriver {
+	/// Faster, but requires virtiofsd on the host
+	Virtiofs,
+
+	/// Built into QEMU
+	#[default]
+	#[serde(rename = "9p")]
+	NineP,
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug)]
+pub struct ShareConfig {
+	/// The directory on the host
+	pub source: String,
+
+	/// Where the directory is mounted in the guest
+	pub target: String,
+
+	#[serde(default)]
+	pub readonly: bool,
+
+	#[serde(default)]
+	pub driver: ShareDriver,
+}
+
+impl ShareConfig {
+	/// Check that the shared directory exists.
+	pub fn check(&self) -> Result<(), Box<dyn Error>> {
+		if !Path::new(&self.source).is_dir() {
+			bail!("Shared directory not found: {}", self.source);
+		}
+		Ok(())
+	}
+
+	/// The command which mounts the share in the guest.
+	pub fn mount_command(&self, index: usize) -> String {
+		let options = match (self.driver, self.readonly) {
+			(ShareDriver::Virtiofs, false) => String::new(),
+			(ShareDriver::Virtiofs, true) => String::from("-o ro "),
+			(ShareDriver::NineP, readonly) => format!(
+				"-o trans=virtio,version=9p2000.L{} ",
+				if readonly { ",ro" } else { "" }
+			),
+		};
+		let fstype = match self.driver {
+			ShareDriver::Virtiofs => "virtiofs",
+			ShareDriver::NineP => "9p",
+		};
+		format!(
+			"mkdir -p {target} && mount -t {} {}{} {target}",
+			fstype,
+			options,
+			tag(index),
+			target = ssh::quote(&self.target)
+		)
+	}
+}
+
+/// The tag which identifies a share to the guest.
+pub fn tag(index: usize) -> String {
+	format!("share{}", index)
+}
+
+/// Mount all shares in the guest.
+pub fn mount(shares: &[ShareConfig], ssh: &mut SshConnection) -> Result<(), Box<dyn Error>> {
+	for (index, share) in shares.iter().enumerate() {
+		info!("Mounting shared directory: {}", share.target);
+		match ssh.exec(&share.mount_command(index))? {
+			0 => {}
+			code => bail!("Failed to mount {} (exit code {})", share.target, code),
+		}
+	}
+	Ok(())
+}
+
+/// A running virtiofsd process.
+pub struct Virtiofsd {
+	pub process: Child,
+	pub socket: PathBuf,
+}
+
+impl Drop for Virtiofsd {
+	fn drop(&mut self) {
+		if let Ok(None) = self.process.try_wait() {
+			debug!("Stopping virtiofsd process: {}", self.process.id());
+			self.process.kill().unwrap_or_default();
+			self.process.wait().unwrap_or_default();
+		}
+	}
+}
+
+impl Virtiofsd {
+	/// Serve the share on the given socket and wait for it to accept
+	/// connections.
+	pub fn start(
+		share: &ShareConfig,
+		socket: &Path,
+		cancel: &CancelToken,
+	) -> Result<Virtiofsd, Box<dyn Error>> {
+		let mut command = Command::new("virtiofsd");
+		command
+			.arg(format!("--socket-path={}", socket.display()))
+			.arg(format!("--shared-dir={}", share.source))
+			// Allows running without privileges
+			.arg("--sandbox=none");
+		if share.readonly {
+			command.arg("--readonly");
+		}
+
+		let process = match command.spawn() {
+			Ok(process) => process,
+			Err(error) => bail!("Failed to start virtiofsd (is it installed?): {}", error),
+		};
+
+		let mut virtiofsd = Virtiofsd {
+			process,
+			socket: socket.to_path_buf(),
+		};
+		for _ in 0..50 {
+			if virtiofsd.socket.exists() {
+				return Ok(virtiofsd);
+			}
+			if let Some(status) = virtiofsd.process.try_wait()? {
+				bail!("virtiofsd exited early: {}", status);
+			}
+			cancel.sleep(Duration::from_millis(100))?;
+		}
+		bail!("virtiofsd did not start in a reasonable amount of time");
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::harness::Harness;
+
+	#[test]
+	fn test_mount() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![])?;
+		let shares = vec![
+			ShareConfig {
+				source: String::from("/srv/repo"),
+				target: String::from("/mnt/repo"),
+				readonly: true,
+				driver: ShareDriver::NineP,
+			},
+			ShareConfig {
+				source: String::from("/srv/data"),
+				target: String::from("/data/it's"),
+				readonly: false,
+				driver: ShareDriver::Virtiofs,
+			},
+		];
+
+		mount(&shares, &mut harness.ssh_connection()?)?;
+		assert_eq!(
+			harness
+				.ssh
+				.executions()
+				.iter()
+				.map(|execution| execution.command.as_str())
+				.collect::<Vec<&str>>(),
+			vec![
+				"mkdir -p '/mnt/repo' && mount -t 9p -o trans=virtio,version=9p2000.L,ro share0 '/mnt/repo'",
+				"mkdir -p '/data/it'\\''s' && mount -t virtiofs share1 '/data/it'\\''s'",
+			]
+		);
+		Ok(())
+	}
+}
@@ -1,8 +1,9 @@
+use crate::cancel::CancelToken;
 use log::{debug, info};
 use simple_error::bail;
 use std::{
 	error::Error,
-	io::{BufRead, BufReader, Cursor},
+	io::{BufRead, BufReader, Cursor, ErrorKind, Read},
 	net::TcpStream,
 	path::Path,
 	time::Duration,
@@ -14,10 +15,20 @@ pub struct SshConnection {
 	pub password: String,
 	pub port: u16,
 	pub session: ssh2::Session,
+
+	/// Environment variables exported for every command
+	pub env: Vec<(String, String)>,
+
+	pub cancel: CancelToken,
 }
 
 impl SshConnection {
-	pub fn new(port: u16, username: &str, password: &str) -> Result<SshConnection, Box<dyn Error>> {
+	pub fn new(
+		port: u16,
+		username: &str,
+		password: &str,
+		cancel: CancelToken,
+	) -> Result<SshConnection, Box<dyn Error>> {
 		debug!("Trying SSH: {}@localhost:{}", username, port);
 
 		let mut session = ssh2::Session::new()?;
@@ -32,6 +43,8 @@ impl SshConnection {
 			password: password.to_string(),
 			port,
 			session,
+			env: Vec::new(),
+			cancel,
 		})
 	}
 
@@ -83,32 +96,56 @@ impl SshConnection {
 			channel.setenv(&var, &val)?;
 		}
 
-		channel.exec(cmdline)?;
+		// Exported rather than set on the channel because sshd only accepts
+		// variables listed in its AcceptEnv
+		if self.env.is_empty() {
+			channel.exec(cmdline)?;
+		} else {
+			channel.exec(&format!("{}{}", export(&self.env), cmdline))?;
+		}
+
+		// Read with a timeout so the command can be abandoned if the build is
+		// cancelled
+		self.session.set_timeout(1000);
 
 		let mut stdout = BufReader::new(channel.stderr());
+		let mut line = String::new();
 
 		loop {
-			let mut line = String::new();
 			match stdout.read_line(&mut line) {
 				Ok(0) => break,
-				Ok(_) => debug!(
-					"(provisioner) {}",
-					line.strip_suffix("\r\n")
-						.or(line.strip_suffix("\n"))
-						.unwrap_or(&line)
-				),
+				Ok(_) => {
+					debug!(
+						"(provisioner) {}",
+						line.strip_suffix("\r\n")
+							.or(line.strip_suffix("\n"))
+							.unwrap_or(&line)
+					);
+					line.clear();
+				}
+				Err(error) if error.kind() == ErrorKind::TimedOut => {
+					if let Err(error) = self.cancel.check() {
+						self.session.set_timeout(0);
+						return Err(error);
+					}
+				}
 				Err(_) => {
 					// The VM is probably rebooting, wait for SSH to come back up
 					info!("SSH disconnected; waiting for it to come back");
-					std::thread::sleep(Duration::from_secs(10));
+					self.cancel.sleep(Duration::from_secs(10))?;
 					for _ in 0..5 {
-						match SshConnection::new(self.port, &self.username, &self.password) {
+						match SshConnection::new(
+							self.port,
+							&self.username,
+							&self.password,
+							self.cancel.clone(),
+						) {
 							Ok(ssh) => {
 								// Steal the session
 								self.session = ssh.session;
 								return Ok(0);
 							}
-							Err(_) => std::thread::sleep(Duration::from_secs(50)),
+							Err(_) => self.cancel.sleep(Duration::from_secs(50))?,
 						}
 					}
 					bail!("SSH did not come back in a reasonable amount of time");
@@ -116,6 +153,7 @@ impl SshConnection {
 			}
 		}
 
+		self.session.set_timeout(0);
 		channel.wait_close()?;
 		let exit = channel.exit_status()?;
 		debug!("Exit code: {}", exit);
@@ -126,4 +164,45 @@ impl SshConnection {
 	pub fn exec(&mut self, cmdline: &str) -> Result<i32, Box<dyn Error>> {
 		self.exec_env(cmdline, Vec::new())
 	}
+
+	/// Run a command on the VM and capture its standard output.
+	pub fn exec_output(&mut self, cmdline: &str) -> Result<(i32, String), Box<dyn Error>> {
+		debug!("Executing command: '{}'", cmdline);
+
+		let mut channel = self.session.channel_session()?;
+		channel.exec(cmdline)?;
+
+		let mut output = String::new();
+		channel.read_to_string(&mut output)?;
+
+		channel.wait_close()?;
+		let exit = channel.exit_status()?;
+		debug!("Exit code: {}", exit);
+		Ok((exit, output))
+	}
+}
+
+/// Quote a value for the guest's shell.
+pub fn quote(value: &str) -> String {
+	format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// A shell prefix which exports the given variables.
+fn export(env: &[(String, String)]) -> String {
+	env.iter()
+		.map(|(name, value)| format!("export {}={}; ", name, quote(value)))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_export() {
+		assert_eq!(
+			export(&[(String::from("http_proxy"), String::from("http://it's:3128"))]),
+			"export http_proxy='http://it'\\''s:3128'; "
+		);
+	}
 }
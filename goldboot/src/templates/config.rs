@@ -1,15 +1,19 @@
 use crate::{
-	build::BuildConfig,
+	build::{BuildConfig, BuildWorker},
+	events::{self, BuildEvent},
 	provisioners::{AnsibleProvisioner, ScriptProvisioner, ShellProvisioner},
+	shares,
 	ssh::SshConnection,
 	Promptable,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use simple_error::bail;
 use std::{error::Error, path::Path};
 use validator::Validate;
 
 ///
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct IsoContainer {
 	/// The installation media URL
 	pub url: String,
@@ -34,33 +38,91 @@ impl Promptable for IsoContainer {
 	}
 }
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug, Default)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug, Default)]
 pub struct ProvisionersContainer {
 	#[serde(skip_serializing_if = "Option::is_none")]
 	pub provisioners: Option<Vec<serde_json::Value>>,
 }
 
 impl ProvisionersContainer {
-	pub fn run(&self, ssh: &mut SshConnection) -> Result<(), Box<dyn Error>> {
+	/// Check that every provisioner is well-formed and that the local files it
+	/// needs exist.
+	pub fn check(&self) -> Result<(), Box<dyn Error>> {
 		if let Some(provisioners) = &self.provisioners {
 			for provisioner in provisioners {
-				match provisioner.get("type").unwrap().as_str().unwrap() {
+				match provisioner.get("type").and_then(|t| t.as_str()) {
+					Some("ansible") => {
+						let provisioner: AnsibleProvisioner =
+							serde_json::from_value(provisioner.to_owned())?;
+						provisioner.validate()?;
+						provisioner.check()?;
+					}
+					Some("shell") => {
+						let provisioner: ShellProvisioner =
+							serde_json::from_value(provisioner.to_owned())?;
+						provisioner.validate()?;
+					}
+					Some("script") => {
+						let provisioner: ScriptProvisioner =
+							serde_json::from_value(provisioner.to_owned())?;
+						provisioner.validate()?;
+						provisioner.check()?;
+					}
+					Some(other) => bail!("Unknown provisioner type: {}", other),
+					None => bail!("Provisioner is missing a type"),
+				}
+			}
+		}
+		Ok(())
+	}
+
+	pub fn run(
+		&self,
+		context: &BuildWorker,
+		ssh: &mut SshConnection,
+	) -> Result<(), Box<dyn Error>> {
+		if let Some(shares) = &context.config.shares {
+			shares::mount(shares, ssh)?;
+		}
+
+		if let Some(provisioners) = &self.provisioners {
+			for (index, provisioner) in provisioners.iter().enumerate() {
+				let provisioner_type = provisioner.get("type").unwrap().as_str().unwrap();
+
+				events::emit(BuildEvent::ProvisionerStarted {
+					worker: context.id,
+					index,
+					provisioner: provisioner_type.to_string(),
+				});
+
+				let exit_code = match provisioner_type {
 					"ansible" => {
 						let provisioner: AnsibleProvisioner =
 							serde_json::from_value(provisioner.to_owned())?;
-						provisioner.run(ssh)?;
+						provisioner.run(ssh)?
 					}
 					"shell" => {
 						let provisioner: ShellProvisioner =
 							serde_json::from_value(provisioner.to_owned())?;
-						provisioner.run(ssh)?;
+						provisioner.run(ssh)?
 					}
 					"script" => {
 						let provisioner: ScriptProvisioner =
 							serde_json::from_value(provisioner.to_owned())?;
-						provisioner.run(ssh)?;
+						provisioner.run(ssh)?
 					}
-					_ => {}
+					other => bail!("Unknown provisioner type: {}", other),
+				};
+
+				events::emit(BuildEvent::ProvisionerFinished {
+					worker: context.id,
+					index,
+					provisioner: provisioner_type.to_string(),
+					exit_code,
+				});
+
+				if exit_code != 0 {
+					bail!("Provisioner failed with exit code: {}", exit_code);
 				}
 			}
 		}
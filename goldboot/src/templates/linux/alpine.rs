@@ -1,12 +1,13 @@
 use crate::{build::BuildWorker, cache::*, provisioners::*, qemu::QemuArgs, templates::*};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
-use validator::Validate;
 use strum::{Display, EnumIter, IntoEnumIterator};
+use validator::Validate;
 
 const DEFAULT_MIRROR: &str = "https://dl-cdn.alpinelinux.org/alpine";
 
-#[derive(Clone, Serialize, Deserialize, Debug, EnumIter, Display)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, EnumIter, Display)]
 pub enum AlpineEdition {
 	Standard,
 	Extended,
@@ -14,7 +15,7 @@ pub enum AlpineEdition {
 	Xen,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, EnumIter)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, EnumIter)]
 pub enum AlpineRelease {
 	Edge,
 	#[serde(rename = "v3.16")]
@@ -38,7 +39,7 @@ impl Display for AlpineRelease {
 }
 
 /// Template for Alpine Linux images (https://www.alpinelinux.org).
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct AlpineTemplate {
 	pub id: TemplateId,
 	pub edition: AlpineEdition,
@@ -77,10 +78,7 @@ impl Template for AlpineTemplate {
 	fn build(&self, context: &BuildWorker) -> Result<(), Box<dyn Error>> {
 		let mut qemuargs = QemuArgs::new(&context);
 
-		qemuargs.drive.push(format!(
-			"file={},if=virtio,cache=writeback,discard=ignore,format=qcow2",
-			context.image_path
-		));
+		qemuargs.add_disk(&context.image_path);
 		qemuargs.drive.push(format!(
 			"file={},media=cdrom",
 			MediaCache::get(self.iso.url.clone(), &self.iso.checksum, MediaFormat::Iso)?
@@ -89,17 +87,17 @@ impl Template for AlpineTemplate {
 		// Start VM
 		let mut qemu = qemuargs.start_process()?;
 
-		// Send boot command
+		// The installer's console is mirrored to the serial port
 		#[rustfmt::skip]
-		qemu.vnc.boot_command(vec![
-			// Initial wait
-			wait!(30),
+		qemu.serial.boot_command(vec![
 			// Root login
-			enter!("root"),
+			wait_text!(r"login: $"),
+			send!("root"),
+			wait_text!(r"~# $"),
 			// Configure install
-			enter!("export KEYMAPOPTS='us us'"),
-			enter!("export HOSTNAMEOPTS='-n goldboot'"),
-			enter!("export INTERFACESOPTS='
+			send!("export KEYMAPOPTS='us us'"),
+			send!("export HOSTNAMEOPTS='-n goldboot'"),
+			send!("export INTERFACESOPTS='
 auto lo
 iface lo inet loopback
 
@@ -107,33 +105,45 @@ auto eth0
 iface eth0 inet dhcp
     hostname alpine-test'"
 			),
-			enter!("export DNSOPTS='1.1.1.1'"),
-			enter!("export TIMEZONEOPTS='-z UTC'"),
-			enter!("export PROXYOPTS='none'"),
-			enter!("export APKREPOSOPTS='-r'"),
-			enter!("export SSHDOPTS='-c openssh'"),
-			enter!("export NTPOPTS='-c openntpd'"),
-			enter!("export DISKOPTS='-m sys /dev/vda'"),
+			send!("export DNSOPTS='1.1.1.1'"),
+			send!("export TIMEZONEOPTS='-z UTC'"),
+			send!("export PROXYOPTS='none'"),
+			send!("export APKREPOSOPTS='-r'"),
+			send!("export SSHDOPTS='-c openssh'"),
+			send!("export NTPOPTS='-c openntpd'"),
+			send!(format!("export DISKOPTS='-m sys {}'", qemuargs.disk.device())),
 			// Start install
-			enter!("echo -e 'root\nroot\ny' | setup-alpine"),
-			wait_screen_rect!("6d7b9fc9229c4f4ae8bc84f0925d8479ccd3e7d2", 668, 0, 1024, 100),
+			send!("echo -e 'root\nroot\ny' | setup-alpine"),
+			wait_text!(r"Installation is complete"),
+			wait_text!(r"~# $"),
 			// Remount root partition
-			enter!("mount -t ext4 /dev/vda3 /mnt"),
+			send!(format!("mount -t ext4 {} /mnt", qemuargs.disk.partition(3))),
 			// Configure SSH
-			enter!("echo 'PermitRootLogin yes' >>/mnt/etc/ssh/sshd_config"),
+			send!("echo 'PermitRootLogin yes' >>/mnt/etc/ssh/sshd_config"),
 			// Reboot into installation
-			enter!("apk add efibootmgr; efibootmgr -n 0003; reboot"),
+			send!("apk add efibootmgr; efibootmgr -n 0003; reboot"),
 		])?;
 
 		// Wait for SSH
 		let mut ssh = qemu.ssh_wait(context.ssh_port, "root", &self.root_password)?;
 
 		// Run provisioners
-		self.provisioners.run(&mut ssh)?;
+		self.provisioners.run(context, &mut ssh)?;
+
+		// Record installed packages
+		context.capture_sbom(&mut ssh);
 
 		// Shutdown
-		ssh.shutdown("poweroff")?;
-		qemu.shutdown_wait()?;
+		qemu.shutdown(&ssh, "poweroff")?;
+		Ok(())
+	}
+
+	fn check(&self) -> Result<(), Box<dyn Error>> {
+		self.partitions.validate()?;
+		for ansible in self.ansible.iter().flatten() {
+			ansible.validate()?;
+			ansible.check()?;
+		}
 		Ok(())
 	}
 }
@@ -163,3 +173,63 @@ impl Promptable for AlpineTemplate {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{build::BuildConfig, harness::Harness, resources::Schedule};
+
+	#[test]
+	fn test_build() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![])?;
+		harness
+			.serial
+			.greet("Welcome to Alpine Linux\r\nlocalhost login: ");
+		harness.serial.respond("root", "localhost:~# ");
+		harness.serial.respond(
+			"y' | setup-alpine",
+			"Installation is complete. Please reboot.\r\nlocalhost:~# ",
+		);
+		harness.serial.respond("mount", "localhost:~# ");
+		let iso = tempfile::NamedTempFile::new()?;
+
+		let template = AlpineTemplate {
+			iso: IsoProvisioner {
+				url: iso.path().to_string_lossy().to_string(),
+				checksum: String::from("none"),
+			},
+			..Default::default()
+		};
+
+		let tmp = tempfile::tempdir()?;
+		let schedule = Schedule {
+			parallel: 1,
+			cpus: 1,
+			memory: 1 << 30,
+		};
+		let worker = BuildWorker {
+			ssh_port: harness.ssh.port,
+			vnc_port: harness.vnc.port,
+			launcher: Some(harness.launcher("poweroff")),
+			..BuildWorker::plan(
+				0,
+				BuildConfig::default(),
+				Box::new(template.clone()),
+				&schedule,
+				tmp.path().to_path_buf(),
+			)
+		};
+		template.build(&worker)?;
+
+		let lines = harness.serial.lines();
+		assert_eq!(lines[0], "root");
+		assert!(lines.contains(&String::from("export DISKOPTS='-m sys /dev/vda'")));
+		assert!(lines.contains(&String::from("mount -t ext4 /dev/vda3 /mnt")));
+		assert_eq!(
+			lines.last().unwrap(),
+			"apk add efibootmgr; efibootmgr -n 0003; reboot"
+		);
+		assert_eq!(harness.ssh.executions().last().unwrap().command, "poweroff");
+		Ok(())
+	}
+}
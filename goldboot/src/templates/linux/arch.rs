@@ -1,10 +1,12 @@
 use crate::{
 	build::BuildWorker,
 	cache::{MediaCache, MediaFormat},
+	keyboard::KeyboardLayout,
 	qemu::QemuArgs,
 	templates::*,
 };
 use log::info;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use simple_error::bail;
 use std::{
@@ -25,7 +27,7 @@ const MIRRORLIST: &'static [&'static str] = &[
 #[folder = "res/Arch/"]
 struct Resources;
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct ArchTemplate {
 	pub id: TemplateId,
 
@@ -43,6 +45,10 @@ pub struct ArchTemplate {
 	//pub luks: LuksContainer,
 	#[serde(flatten)]
 	pub provisioners: ProvisionersContainer,
+
+	/// The keyboard layout used by the installer
+	#[serde(default)]
+	pub keyboard: KeyboardLayout,
 }
 
 impl ArchTemplate {
@@ -93,6 +99,7 @@ impl Default for ArchTemplate {
 				..Default::default()
 			},
 			provisioners: ProvisionersContainer::default(),
+			keyboard: KeyboardLayout::default(),
 		}
 	}
 }
@@ -103,10 +110,7 @@ impl Template for ArchTemplate {
 
 		let mut qemuargs = QemuArgs::new(&context);
 
-		qemuargs.drive.push(format!(
-			"file={},if=virtio,cache=writeback,discard=ignore,format=qcow2",
-			context.image_path
-		));
+		qemuargs.add_disk(&context.image_path);
 		qemuargs.drive.push(format!(
 			"file={},media=cdrom",
 			MediaCache::get(self.iso.url.clone(), &self.iso.checksum, MediaFormat::Iso)?
@@ -114,6 +118,7 @@ impl Template for ArchTemplate {
 
 		// Start VM
 		let mut qemu = qemuargs.start_process()?;
+		qemu.vnc.layout = self.keyboard;
 
 		// Send boot command
 		#[rustfmt::skip]
@@ -142,6 +147,8 @@ impl Template for ArchTemplate {
 				vec![
 					("GB_MIRRORLIST", &self.format_mirrorlist()),
 					("GB_ROOT_PASSWORD", &self.root_password),
+					("GB_FIRMWARE", &context.config.firmware().to_string()),
+					("GB_DISK", qemuargs.disk.device()),
 				],
 			) {
 				Ok(0) => debug!("Installation completed successfully"),
@@ -150,13 +157,23 @@ impl Template for ArchTemplate {
 		}
 
 		// Run provisioners
-		self.provisioners.run(&mut ssh)?;
+		self.provisioners.run(context, &mut ssh)?;
+
+		// Record installed packages
+		context.capture_sbom(&mut ssh);
 
 		// Shutdown
-		ssh.shutdown("poweroff")?;
-		qemu.shutdown_wait()?;
+		qemu.shutdown(&ssh, "poweroff")?;
 		Ok(())
 	}
+
+	fn check(&self) -> Result<(), Box<dyn Error>> {
+		self.provisioners.check()
+	}
+
+	fn supports_bios(&self) -> bool {
+		true
+	}
 }
 
 impl Promptable for ArchTemplate {
@@ -186,10 +203,79 @@ impl Promptable for ArchTemplate {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::{build::BuildConfig, harness::Harness, resources::Schedule};
 
 	#[test]
 	fn test_fetch_latest_iso() -> Result<(), Box<dyn Error>> {
 		fetch_latest_iso()?;
 		Ok(())
 	}
+
+	#[test]
+	fn test_build() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![])?;
+		let iso = tempfile::NamedTempFile::new()?;
+
+		let template = ArchTemplate {
+			id: TemplateId::Arch,
+			root_password: String::from("root"),
+			mirrorlist: vec![String::from("https://mirror.example.com/$repo/os/$arch")],
+			iso: IsoContainer {
+				url: iso.path().to_string_lossy().to_string(),
+				checksum: String::from("none"),
+			},
+			general: GeneralContainer::default(),
+			provisioners: ProvisionersContainer {
+				provisioners: Some(vec![serde_json::json!({
+					"type": "shell",
+					"command": "echo test",
+				})]),
+			},
+			keyboard: KeyboardLayout::default(),
+		};
+
+		let tmp = tempfile::tempdir()?;
+		let schedule = Schedule {
+			parallel: 1,
+			cpus: 1,
+			memory: 1 << 30,
+		};
+		let worker = BuildWorker {
+			ssh_port: harness.ssh.port,
+			vnc_port: harness.vnc.port,
+			launcher: Some(harness.launcher("poweroff")),
+			..BuildWorker::plan(
+				0,
+				BuildConfig::default(),
+				Box::new(template.clone()),
+				&schedule,
+				tmp.path().to_path_buf(),
+			)
+		};
+		template.build(&worker)?;
+
+		assert_eq!(
+			harness.vnc.typed(),
+			"passwd\nroot\nroot\necho 'AcceptEnv *' >>/etc/ssh/sshd_config\necho 'PermitRootLogin yes' >>/etc/ssh/sshd_config\nsystemctl restart sshd\n"
+		);
+
+		// The install script runs first and the shutdown comes last
+		let executions = harness.ssh.executions();
+		assert_eq!(
+			executions[0].script.as_deref(),
+			Some(Resources::get("install.sh").unwrap().data.as_ref())
+		);
+		assert!(executions[0].env.contains(&(
+			String::from("GB_MIRRORLIST"),
+			String::from("Server = https://mirror.example.com/$repo/os/$arch")
+		)));
+		assert!(executions[0]
+			.env
+			.contains(&(String::from("GB_DISK"), String::from("/dev/vda"))));
+		assert!(executions
+			.iter()
+			.any(|execution| execution.command == "echo test"));
+		assert_eq!(executions.last().unwrap().command, "poweroff");
+		Ok(())
+	}
 }
@@ -2,9 +2,11 @@ use crate::{
 	build::BuildWorker,
 	cache::{MediaCache, MediaFormat},
 	http::HttpServer,
+	keyboard::KeyboardLayout,
 	qemu::QemuArgs,
 	templates::*,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::{
 	error::Error,
@@ -16,7 +18,7 @@ use validator::Validate;
 #[folder = "res/Debian/"]
 struct Resources;
 
-#[derive(Clone, Serialize, Deserialize, Debug, Default)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, Default)]
 pub enum DebianEdition {
 	#[default]
 	Bullseye,
@@ -60,7 +62,7 @@ pub fn fetch_debian_iso(
 	bail!("Failed to request latest ISO");
 }
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct DebianTemplate {
 	pub id: TemplateId,
 	pub root_password: String,
@@ -75,6 +77,10 @@ pub struct DebianTemplate {
 
 	#[serde(flatten)]
 	pub provisioners: ProvisionersContainer,
+
+	/// The keyboard layout used by the installer
+	#[serde(default)]
+	pub keyboard: KeyboardLayout,
 }
 
 impl Default for DebianTemplate {
@@ -89,6 +95,7 @@ impl Default for DebianTemplate {
 			},
 			edition: DebianEdition::default(),
 			provisioners: ProvisionersContainer::default(),
+			keyboard: KeyboardLayout::default(),
 		}
 	}
 }
@@ -98,13 +105,16 @@ impl Template for DebianTemplate {
 		let mut qemuargs = QemuArgs::new(&context);
 
 		// Start HTTP
-		let http =
-			HttpServer::serve_file(Resources::get("default/preseed.cfg").unwrap().data.to_vec())?;
+		let mut preseed = qemuargs
+			.disk
+			.preseed(Resources::get("default/preseed.cfg").unwrap().data.to_vec());
+		if let Some(network) = &context.config.network {
+			preseed = network.preseed(preseed);
+		}
+		let http = HttpServer::serve_file(preseed)?;
+		let preseed_address = qemuargs.serve(http.port);
 
-		qemuargs.drive.push(format!(
-			"file={},if=virtio,cache=writeback,discard=ignore,format=qcow2",
-			context.image_path
-		));
+		qemuargs.add_disk(&context.image_path);
 		qemuargs.drive.push(format!(
 			"file={},media=cdrom",
 			MediaCache::get(self.iso.url.clone(), &self.iso.checksum, MediaFormat::Iso)?
@@ -112,6 +122,7 @@ impl Template for DebianTemplate {
 
 		// Start VM
 		let mut qemu = qemuargs.start_process()?;
+		qemu.vnc.layout = self.keyboard;
 
 		// Send boot command
 		#[rustfmt::skip]
@@ -119,7 +130,7 @@ impl Template for DebianTemplate {
 			wait!(10),
 			input!("aa"),
 			wait_screen!("53471d73e98f0109ce3262d9c45c522d7574366b"),
-			enter!(format!("http://10.0.2.2:{}/preseed.cfg", http.port)),
+			enter!(format!("http://{}/preseed.cfg", preseed_address)),
 			wait_screen!("97354165fd270a95fd3da41ef43c35bf24b7c09b"),
 			enter!(&self.root_password),
 			enter!(&self.root_password),
@@ -130,14 +141,20 @@ impl Template for DebianTemplate {
 		let mut ssh = qemu.ssh_wait(context.ssh_port, "root", &self.root_password)?;
 
 		// Run provisioners
-		self.provisioners.run(&mut ssh)?;
+		self.provisioners.run(context, &mut ssh)?;
+
+		// Record installed packages
+		context.capture_sbom(&mut ssh);
 
 		// Shutdown
-		ssh.shutdown("poweroff")?;
-		qemu.shutdown_wait()?;
+		qemu.shutdown(&ssh, "poweroff")?;
 		Ok(())
 	}
 
+	fn check(&self) -> Result<(), Box<dyn Error>> {
+		self.provisioners.check()
+	}
+
 	fn general(&self) -> GeneralContainer {
 		self.general.clone()
 	}
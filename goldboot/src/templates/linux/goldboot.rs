@@ -3,10 +3,12 @@ use crate::{
 	build::BuildWorker,
 	cache::{MediaCache, MediaFormat},
 	http::HttpServer,
+	keyboard::KeyboardLayout,
 	qemu::QemuArgs,
 	templates::*,
 };
 use log::info;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use simple_error::bail;
 use std::error::Error;
@@ -16,12 +18,16 @@ use validator::Validate;
 #[folder = "res/Goldboot/"]
 struct Resources;
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct GoldbootTemplate {
 	pub id: TemplateId,
 
 	/// The goldboot-linux executable to embed
 	pub executable: String,
+
+	/// The keyboard layout used by the installer
+	#[serde(default)]
+	pub keyboard: KeyboardLayout,
 }
 
 impl Default for GoldbootTemplate {
@@ -33,6 +39,7 @@ impl Default for GoldbootTemplate {
 				..Default::default()
 			},
 			executable: String::from(""),
+			keyboard: KeyboardLayout::default(),
 		}
 	}
 }
@@ -43,20 +50,25 @@ impl Template for GoldbootTemplate {
 
 		let mut qemuargs = QemuArgs::new(&context);
 
-		qemuargs.drive.push(format!(
-			"file={},if=virtio,cache=writeback,discard=ignore,format=qcow2",
-			context.image_path
-		));
+		qemuargs.add_disk(&context.image_path);
 		qemuargs.drive.push(format!(
 			"file={},media=cdrom",
 			MediaCache::get("https://cdimage.debian.org/cdimage/weekly-builds/amd64/iso-cd/debian-testing-amd64-netinst.iso".to_string(), "none", MediaFormat::Iso)?
 		));
 
 		// Start HTTP
-		let http = HttpServer::serve_file(Resources::get("preseed.cfg").unwrap().data.to_vec())?;
+		let mut preseed = qemuargs
+			.disk
+			.preseed(Resources::get("preseed.cfg").unwrap().data.to_vec());
+		if let Some(network) = &context.config.network {
+			preseed = network.preseed(preseed);
+		}
+		let http = HttpServer::serve_file(preseed)?;
+		let preseed_address = qemuargs.serve(http.port);
 
 		// Start VM
 		let mut qemu = qemuargs.start_process()?;
+		qemu.vnc.layout = self.keyboard;
 
 		// Temporary root password for the run
 		let temp_password = crate::random_password();
@@ -67,7 +79,7 @@ impl Template for GoldbootTemplate {
 			wait!(10),
 			input!("aa"),
 			wait_screen!("a5263becea998337f06070678e4bf3db2d437195"),
-			enter!(format!("http://10.0.2.2:{}/preseed.cfg", http.port)),
+			enter!(format!("http://{}/preseed.cfg", preseed_address)),
 			wait_screen!("97354165fd270a95fd3da41ef43c35bf24b7c09b"),
 			enter!(&temp_password),
 			enter!(&temp_password),
@@ -84,8 +96,7 @@ impl Template for GoldbootTemplate {
 		)?;
 
 		// Shutdown
-		ssh.shutdown("poweroff")?;
-		qemu.shutdown_wait()?;
+		qemu.shutdown(&ssh, "poweroff")?;
 		Ok(())
 	}
 }
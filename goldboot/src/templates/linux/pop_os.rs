@@ -1,21 +1,23 @@
 use crate::{
 	build::BuildWorker,
 	cache::{MediaCache, MediaFormat},
+	keyboard::KeyboardLayout,
 	qemu::QemuArgs,
 	templates::*,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use validator::Validate;
 
-#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Default, Debug)]
 pub enum PopOsEdition {
 	#[default]
 	Amd,
 	Nvidia,
 }
 
-#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Default, Debug)]
 pub enum PopOsRelease {
 	#[serde(rename = "21.10")]
 	#[default]
@@ -25,7 +27,7 @@ pub enum PopOsRelease {
 	V22_04,
 }
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct PopOsTemplate {
 	pub id: TemplateId,
 	pub edition: PopOsEdition,
@@ -45,6 +47,10 @@ pub struct PopOsTemplate {
 
 	#[serde(flatten)]
 	pub provisioners: ProvisionersContainer,
+
+	/// The keyboard layout used by the installer
+	#[serde(default)]
+	pub keyboard: KeyboardLayout,
 }
 
 impl Default for PopOsTemplate {
@@ -66,6 +72,7 @@ impl Default for PopOsTemplate {
 				.. Default::default()
 			},
 			provisioners: ProvisionersContainer::default(),
+			keyboard: KeyboardLayout::default(),
         }
 	}
 }
@@ -74,10 +81,7 @@ impl Template for PopOsTemplate {
 	fn build(&self, context: &BuildWorker) -> Result<(), Box<dyn Error>> {
 		let mut qemuargs = QemuArgs::new(&context);
 
-		qemuargs.drive.push(format!(
-			"file={},if=virtio,cache=writeback,discard=ignore,format=qcow2",
-			context.image_path
-		));
+		qemuargs.add_disk(&context.image_path);
 		qemuargs.drive.push(format!(
 			"file={},media=cdrom",
 			MediaCache::get(self.iso.url.clone(), &self.iso.checksum, MediaFormat::Iso)?
@@ -85,6 +89,7 @@ impl Template for PopOsTemplate {
 
 		// Start VM
 		let mut qemu = qemuargs.start_process()?;
+		qemu.vnc.layout = self.keyboard;
 
 		// Send boot command
 		qemu.vnc.boot_command(vec![
@@ -151,14 +156,20 @@ impl Template for PopOsTemplate {
 		let mut ssh = qemu.ssh_wait(context.ssh_port, "root", &self.root_password)?;
 
 		// Run provisioners
-		self.provisioners.run(&mut ssh)?;
+		self.provisioners.run(context, &mut ssh)?;
+
+		// Record installed packages
+		context.capture_sbom(&mut ssh);
 
 		// Shutdown
-		ssh.shutdown("poweroff")?;
-		qemu.shutdown_wait()?;
+		qemu.shutdown(&ssh, "poweroff")?;
 		Ok(())
 	}
 
+	fn check(&self) -> Result<(), Box<dyn Error>> {
+		self.provisioners.check()
+	}
+
 	fn general(&self) -> GeneralContainer {
 		self.general.clone()
 	}
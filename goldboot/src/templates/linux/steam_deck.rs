@@ -1,19 +1,25 @@
 use crate::{
 	build::BuildWorker,
 	cache::{MediaCache, MediaFormat},
+	keyboard::KeyboardLayout,
 	qemu::QemuArgs,
 	templates::*,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use validator::Validate;
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct SteamDeckTemplate {
 	pub id: TemplateId,
 	pub recovery_url: String,
 
 	pub recovery_checksum: String,
+
+	/// The keyboard layout used by the installer
+	#[serde(default)]
+	pub keyboard: KeyboardLayout,
 }
 
 impl Default for SteamDeckTemplate {
@@ -30,6 +36,7 @@ impl Default for SteamDeckTemplate {
 				storage_size: String::from("15 GiB"),
 				..Default::default()
 			},
+			keyboard: KeyboardLayout::default(),
 		}
 	}
 }
@@ -58,6 +65,7 @@ impl Template for SteamDeckTemplate {
 
 		// Start VM
 		let mut qemu = qemuargs.start_process()?;
+		qemu.vnc.layout = self.keyboard;
 
 		// Send boot command
 		#[rustfmt::skip]
@@ -76,8 +84,9 @@ impl Template for SteamDeckTemplate {
 			enter!("./tools/repair_reimage.sh"),
 		])?;
 
-		// Wait for shutdown
-		qemu.shutdown_wait()?;
+		// The reimage takes an unpredictable amount of time and powers off the
+		// VM when it's done
+		qemu.shutdown_wait(None)?;
 
 		Ok(())
 	}
@@ -1,19 +1,21 @@
 use crate::{
 	build::BuildWorker,
 	cache::{MediaCache, MediaFormat},
+	keyboard::KeyboardLayout,
 	qemu::QemuArgs,
 	templates::*,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use validator::Validate;
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug)]
 pub enum SteamOsVersion {
 	Brewmaster2_195,
 }
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct SteamOsTemplate {
 	pub id: TemplateId,
 	pub version: SteamOsVersion,
@@ -28,6 +30,10 @@ pub struct SteamOsTemplate {
 
 	#[serde(flatten)]
 	pub provisioners: ProvisionersContainer,
+
+	/// The keyboard layout used by the installer
+	#[serde(default)]
+	pub keyboard: KeyboardLayout,
 }
 
 impl Default for SteamOsTemplate {
@@ -47,6 +53,7 @@ impl Default for SteamOsTemplate {
 				.. Default::default()
 			},
 			provisioners: ProvisionersContainer::default(),
+			keyboard: KeyboardLayout::default(),
 		}
 	}
 }
@@ -55,10 +62,7 @@ impl Template for SteamOsTemplate {
 	fn build(&self, context: &BuildWorker) -> Result<(), Box<dyn Error>> {
 		let mut qemuargs = QemuArgs::new(&context);
 
-		qemuargs.drive.push(format!(
-			"file={},if=virtio,cache=writeback,discard=ignore,format=qcow2",
-			context.image_path
-		));
+		qemuargs.add_disk(&context.image_path);
 		qemuargs.drive.push(format!(
 			"file={},media=cdrom",
 			MediaCache::get(self.iso.url.clone(), &self.iso.checksum, MediaFormat::Iso)?
@@ -66,6 +70,7 @@ impl Template for SteamOsTemplate {
 
 		// Start VM
 		let mut qemu = qemuargs.start_process()?;
+		qemu.vnc.layout = self.keyboard;
 
 		// Send boot command
 		#[rustfmt::skip]
@@ -82,14 +87,20 @@ impl Template for SteamOsTemplate {
 		let mut ssh = qemu.ssh_wait(context.ssh_port, "root", &self.root_password)?;
 
 		// Run provisioners
-		self.provisioners.run(&mut ssh)?;
+		self.provisioners.run(context, &mut ssh)?;
+
+		// Record installed packages
+		context.capture_sbom(&mut ssh);
 
 		// Shutdown
-		ssh.shutdown("poweroff")?;
-		qemu.shutdown_wait()?;
+		qemu.shutdown(&ssh, "poweroff")?;
 		Ok(())
 	}
 
+	fn check(&self) -> Result<(), Box<dyn Error>> {
+		self.provisioners.check()
+	}
+
 	fn general(&self) -> GeneralContainer {
 		self.general.clone()
 	}
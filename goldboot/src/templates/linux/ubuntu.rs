@@ -1,15 +1,17 @@
 use crate::{
 	build::BuildWorker,
 	cache::{MediaCache, MediaFormat},
+	keyboard::KeyboardLayout,
 	qemu::QemuArgs,
 	templates::*,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use strum::{Display, EnumIter, IntoEnumIterator};
 use validator::Validate;
 
-#[derive(Clone, Serialize, Deserialize, Debug, EnumIter)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, EnumIter)]
 pub enum UbuntuRelease {
 	Jammy,
 	Impish,
@@ -38,13 +40,13 @@ impl Display for UbuntuRelease {
 	}
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, EnumIter, Display)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, EnumIter, Display)]
 pub enum UbuntuEdition {
 	Server,
 	Desktop,
 }
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct UbuntuTemplate {
 	pub id: TemplateId,
 	pub edition: UbuntuEdition,
@@ -60,6 +62,10 @@ pub struct UbuntuTemplate {
 
 	#[serde(flatten)]
 	pub provisioners: ProvisionersContainer,
+
+	/// The keyboard layout used by the installer
+	#[serde(default)]
+	pub keyboard: KeyboardLayout,
 }
 
 impl Default for UbuntuTemplate {
@@ -78,6 +84,7 @@ impl Default for UbuntuTemplate {
 				..Default::default()
 			},
 			provisioners: ProvisionersContainer::default(),
+			keyboard: KeyboardLayout::default(),
 		}
 	}
 }
@@ -86,10 +93,7 @@ impl Template for UbuntuTemplate {
 	fn build(&self, context: &BuildWorker) -> Result<(), Box<dyn Error>> {
 		let mut qemuargs = QemuArgs::new(&context);
 
-		qemuargs.drive.push(format!(
-			"file={},if=virtio,cache=writeback,discard=ignore,format=qcow2",
-			context.image_path
-		));
+		qemuargs.add_disk(&context.image_path);
 		qemuargs.drive.push(format!(
 			"file={},media=cdrom",
 			MediaCache::get(self.iso.url.clone(), &self.iso.checksum, MediaFormat::Iso)?
@@ -97,6 +101,7 @@ impl Template for UbuntuTemplate {
 
 		// Start VM
 		let mut qemu = qemuargs.start_process()?;
+		qemu.vnc.layout = self.keyboard;
 
 		// Send boot command
 		#[rustfmt::skip]
@@ -107,14 +112,20 @@ impl Template for UbuntuTemplate {
 		let mut ssh = qemu.ssh_wait(context.ssh_port, "root", &self.root_password)?;
 
 		// Run provisioners
-		self.provisioners.run(&mut ssh)?;
+		self.provisioners.run(context, &mut ssh)?;
+
+		// Record installed packages
+		context.capture_sbom(&mut ssh);
 
 		// Shutdown
-		ssh.shutdown("poweroff")?;
-		qemu.shutdown_wait()?;
+		qemu.shutdown(&ssh, "poweroff")?;
 		Ok(())
 	}
 
+	fn check(&self) -> Result<(), Box<dyn Error>> {
+		self.provisioners.check()
+	}
+
 	fn general(&self) -> GeneralContainer {
 		self.general.clone()
 	}
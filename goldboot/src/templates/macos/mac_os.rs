@@ -1,4 +1,7 @@
-use crate::{build::BuildWorker, cache::MediaCache, qemu::QemuArgs, templates::*};
+use crate::{
+	build::BuildWorker, cache::MediaCache, keyboard::KeyboardLayout, qemu::QemuArgs, templates::*,
+};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use validator::Validate;
@@ -7,14 +10,14 @@ use validator::Validate;
 //#[folder = "res/MacOs/"]
 //struct Resources;
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug)]
 pub enum MacOsRelease {
 	Catalina,
 	BigSur,
 	Monterey,
 }
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct MacOsTemplate {
 	pub id: TemplateId,
 	pub release: MacOsRelease,
@@ -24,6 +27,10 @@ pub struct MacOsTemplate {
 
 	#[serde(flatten)]
 	pub provisioners: ProvisionersContainer,
+
+	/// The keyboard layout used by the installer
+	#[serde(default)]
+	pub keyboard: KeyboardLayout,
 }
 
 impl Default for MacOsTemplate {
@@ -42,6 +49,7 @@ impl Default for MacOsTemplate {
 				storage_size: String::from("50 GiB"),
 				.. Default::default()
 			},
+			keyboard: KeyboardLayout::default(),
 		}
 	}
 }
@@ -52,7 +60,7 @@ impl Template for MacOsTemplate {
 
 		// Copy OpenCore partition
 		//if let Some(resource) = Resources::get("OpenCore.qcow2") {
-		//	std::fs::write(context.tmp.path().join("OpenCore.qcow2"), resource.data)?;
+		//	std::fs::write(context.tmp.join("OpenCore.qcow2"), resource.data)?;
 		//}
 
 		// Convert dmg to img
@@ -60,7 +68,7 @@ impl Template for MacOsTemplate {
 
 		qemuargs.cpu = Some(format!("Penryn,kvm=on,vendor=GenuineIntel,+invtsc,vmware-cpuid-freq=on,+ssse3,+sse4.2,+popcnt,+avx,+aes,+xsave,+xsaveopt,check"));
 		qemuargs.machine = format!("q35,accel=kvm");
-		qemuargs.smbios = Some(format!("type=2"));
+		qemuargs.smbios.push(format!("type=2"));
 		qemuargs.device.push(format!("ich9-ahci,id=sata"));
 		qemuargs.device.push(format!("usb-ehci,id=ehci"));
 		qemuargs.device.push(format!("nec-usb-xhci,id=xhci"));
@@ -74,7 +82,7 @@ impl Template for MacOsTemplate {
 		// Add boot partition
 		qemuargs.drive.push(format!(
 			"file={}/OpenCore.qcow2,id=OpenCore,if=none,format=qcow2",
-			context.tmp.path().to_string_lossy()
+			context.tmp.to_string_lossy()
 		));
 		qemuargs
 			.device
@@ -99,6 +107,7 @@ impl Template for MacOsTemplate {
 
 		// Start VM
 		let mut qemu = qemuargs.start_process()?;
+		qemu.vnc.layout = self.keyboard;
 
 		// Send boot command
 		match self.release {
@@ -125,11 +134,18 @@ impl Template for MacOsTemplate {
 		let mut ssh = qemu.ssh_wait(context.ssh_port, "root", "root")?;
 
 		// Run provisioners
-		self.provisioners.run(&mut ssh)?;
+		self.provisioners.run(context, &mut ssh)?;
 
 		// Shutdown
-		ssh.shutdown("shutdown -h now")?;
-		qemu.shutdown_wait()?;
+		qemu.shutdown(&ssh, "shutdown -h now")?;
 		Ok(())
 	}
+
+	fn check(&self) -> Result<(), Box<dyn Error>> {
+		self.provisioners.check()
+	}
+
+	fn supports_shares(&self) -> bool {
+		false
+	}
 }
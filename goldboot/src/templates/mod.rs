@@ -1,7 +1,9 @@
 use crate::{build::BuildWorker, *};
-use serde::{Deserialize, Serialize};
-
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use simple_error::bail;
 use std::{error::Error, fmt::Display, path::Path};
+use validator::Validate;
 
 pub mod linux;
 pub mod macos;
@@ -9,12 +11,28 @@ pub mod windows;
 
 /// Represents a "base configuration" that users can modify and use to build
 /// images.
-pub trait Template {
+pub trait Template: Validate {
 	/// Build an image from the template.
 	fn build(&self, context: &BuildWorker) -> Result<(), Box<dyn Error>>;
+
+	/// Check the template for problems that can be found without building it
+	/// (i.e. missing local files).
+	fn check(&self) -> Result<(), Box<dyn Error>> {
+		Ok(())
+	}
+
+	/// Whether the template can install to a VM booted with BIOS firmware.
+	fn supports_bios(&self) -> bool {
+		false
+	}
+
+	/// Whether shared directories can be mounted in the guest.
+	fn supports_shares(&self) -> bool {
+		true
+	}
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, Default, EnumIter)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, Default, EnumIter)]
 #[serde(tag = "id")]
 pub enum TemplateId {
 	#[default]
@@ -233,4 +251,105 @@ impl TemplateId {
 			TemplateId::Zorin => todo!(),
 		}
 	}
+
+	/// Deserialize a template's config.
+	pub fn parse(&self, value: serde_json::Value) -> Result<Box<dyn Template>, Box<dyn Error>> {
+		match &self {
+			TemplateId::Alpine => deserialize::<linux::alpine::AlpineTemplate>(value),
+			TemplateId::Arch => deserialize::<linux::arch::ArchTemplate>(value),
+			TemplateId::Artix => bail!("Template not implemented: {}", self),
+			TemplateId::Bedrock => bail!("Template not implemented: {}", self),
+			TemplateId::CentOs => bail!("Template not implemented: {}", self),
+			TemplateId::Debian => deserialize::<linux::debian::DebianTemplate>(value),
+			TemplateId::ElementaryOs => bail!("Template not implemented: {}", self),
+			TemplateId::Fedora => bail!("Template not implemented: {}", self),
+			TemplateId::FreeBsd => bail!("Template not implemented: {}", self),
+			TemplateId::Gentoo => bail!("Template not implemented: {}", self),
+			TemplateId::Goldboot => deserialize::<linux::goldboot::GoldbootTemplate>(value),
+			TemplateId::Haiku => bail!("Template not implemented: {}", self),
+			TemplateId::Kali => bail!("Template not implemented: {}", self),
+			TemplateId::Mint => bail!("Template not implemented: {}", self),
+			TemplateId::MacOs => deserialize::<macos::mac_os::MacOsTemplate>(value),
+			TemplateId::Manjaro => bail!("Template not implemented: {}", self),
+			TemplateId::NetBsd => bail!("Template not implemented: {}", self),
+			TemplateId::NixOs => bail!("Template not implemented: {}", self),
+			TemplateId::OpenBsd => bail!("Template not implemented: {}", self),
+			TemplateId::OpenSuse => bail!("Template not implemented: {}", self),
+			TemplateId::Oracle => bail!("Template not implemented: {}", self),
+			TemplateId::Parrot => bail!("Template not implemented: {}", self),
+			TemplateId::PopOs => deserialize::<linux::pop_os::PopOsTemplate>(value),
+			TemplateId::Qubes => bail!("Template not implemented: {}", self),
+			TemplateId::RedHat => bail!("Template not implemented: {}", self),
+			TemplateId::Rocky => bail!("Template not implemented: {}", self),
+			TemplateId::Slackware => bail!("Template not implemented: {}", self),
+			TemplateId::SteamDeck => deserialize::<linux::steam_deck::SteamDeckTemplate>(value),
+			TemplateId::SteamOs => deserialize::<linux::steam_os::SteamOsTemplate>(value),
+			TemplateId::Tails => bail!("Template not implemented: {}", self),
+			TemplateId::TrueNas => bail!("Template not implemented: {}", self),
+			TemplateId::Ubuntu => deserialize::<linux::ubuntu::UbuntuTemplate>(value),
+			TemplateId::Void => bail!("Template not implemented: {}", self),
+			TemplateId::Windows10 => deserialize::<windows::windows_10::Windows10Template>(value),
+			TemplateId::Windows11 => bail!("Template not implemented: {}", self),
+			TemplateId::Windows7 => bail!("Template not implemented: {}", self),
+			TemplateId::Zorin => bail!("Template not implemented: {}", self),
+		}
+	}
+
+	/// Generate a JSON Schema for the template's config (if it's implemented).
+	pub fn schema(&self, generator: &mut SchemaGenerator) -> Option<Schema> {
+		match &self {
+			TemplateId::Alpine => Some(generator.subschema_for::<linux::alpine::AlpineTemplate>()),
+			TemplateId::Arch => Some(generator.subschema_for::<linux::arch::ArchTemplate>()),
+			TemplateId::Artix => None,
+			TemplateId::Bedrock => None,
+			TemplateId::CentOs => None,
+			TemplateId::Debian => Some(generator.subschema_for::<linux::debian::DebianTemplate>()),
+			TemplateId::ElementaryOs => None,
+			TemplateId::Fedora => None,
+			TemplateId::FreeBsd => None,
+			TemplateId::Gentoo => None,
+			TemplateId::Goldboot => {
+				Some(generator.subschema_for::<linux::goldboot::GoldbootTemplate>())
+			}
+			TemplateId::Haiku => None,
+			TemplateId::Kali => None,
+			TemplateId::Mint => None,
+			TemplateId::MacOs => Some(generator.subschema_for::<macos::mac_os::MacOsTemplate>()),
+			TemplateId::Manjaro => None,
+			TemplateId::NetBsd => None,
+			TemplateId::NixOs => None,
+			TemplateId::OpenBsd => None,
+			TemplateId::OpenSuse => None,
+			TemplateId::Oracle => None,
+			TemplateId::Parrot => None,
+			TemplateId::PopOs => Some(generator.subschema_for::<linux::pop_os::PopOsTemplate>()),
+			TemplateId::Qubes => None,
+			TemplateId::RedHat => None,
+			TemplateId::Rocky => None,
+			TemplateId::Slackware => None,
+			TemplateId::SteamDeck => {
+				Some(generator.subschema_for::<linux::steam_deck::SteamDeckTemplate>())
+			}
+			TemplateId::SteamOs => {
+				Some(generator.subschema_for::<linux::steam_os::SteamOsTemplate>())
+			}
+			TemplateId::Tails => None,
+			TemplateId::TrueNas => None,
+			TemplateId::Ubuntu => Some(generator.subschema_for::<linux::ubuntu::UbuntuTemplate>()),
+			TemplateId::Void => None,
+			TemplateId::Windows10 => {
+				Some(generator.subschema_for::<windows::windows_10::Windows10Template>())
+			}
+			TemplateId::Windows11 => None,
+			TemplateId::Windows7 => None,
+			TemplateId::Zorin => None,
+		}
+	}
+}
+
+fn deserialize<T>(value: serde_json::Value) -> Result<Box<dyn Template>, Box<dyn Error>>
+where
+	T: Template + DeserializeOwned + 'static,
+{
+	Ok(Box::new(serde_json::from_value::<T>(value)?))
 }
@@ -1,9 +1,11 @@
 use crate::{
 	build::BuildWorker,
 	cache::{MediaCache, MediaFormat},
+	keyboard::KeyboardLayout,
 	qemu::QemuArgs,
 	templates::*,
 };
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::error::Error;
 use validator::Validate;
@@ -14,7 +16,7 @@ use super::*;
 #[folder = "res/Windows10/"]
 struct Resources;
 
-#[derive(Clone, Serialize, Deserialize, Validate, Debug)]
+#[derive(Clone, Serialize, Deserialize, Validate, JsonSchema, Debug)]
 pub struct Windows10Template {
 	pub id: TemplateId,
 
@@ -32,6 +34,10 @@ pub struct Windows10Template {
 
 	#[serde(flatten)]
 	pub provisioners: ProvisionersContainer,
+
+	/// The keyboard layout used by the installer
+	#[serde(default)]
+	pub keyboard: KeyboardLayout,
 }
 
 impl Default for Windows10Template {
@@ -51,6 +57,7 @@ impl Default for Windows10Template {
 				..Default::default()
 			},
 			provisioners: ProvisionersContainer::default(),
+			keyboard: KeyboardLayout::default(),
 		}
 	}
 }
@@ -101,6 +108,7 @@ impl Template for Windows10Template {
 
 		// Start VM
 		let mut qemu = qemuargs.start_process()?;
+		qemu.vnc.layout = self.keyboard;
 
 		// Send boot command
 		#[rustfmt::skip]
@@ -112,14 +120,24 @@ impl Template for Windows10Template {
 		// Wait for SSH
 		let mut ssh = qemu.ssh_wait(context.ssh_port, &self.username, &self.password)?;
 
+		// The shell can't export variables, so the proxy isn't applied
+		ssh.env.clear();
+
 		// Run provisioners
-		self.provisioners.run(&mut ssh)?;
+		self.provisioners.run(context, &mut ssh)?;
 
 		// Shutdown
-		ssh.shutdown("shutdown /s /t 0 /f /d p:4:1")?;
-		qemu.shutdown_wait()?;
+		qemu.shutdown(&ssh, "shutdown /s /t 0 /f /d p:4:1")?;
 		Ok(())
 	}
+
+	fn check(&self) -> Result<(), Box<dyn Error>> {
+		self.provisioners.check()
+	}
+
+	fn supports_shares(&self) -> bool {
+		false
+	}
 }
 
 impl Promptable for Windows10Template {
@@ -146,3 +164,66 @@ impl Promptable for Windows10Template {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		harness::{blank_screen, Harness},
+		resources::Schedule,
+	};
+
+	#[test]
+	fn test_build() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![blank_screen(640, 480)])?;
+		let iso = tempfile::NamedTempFile::new()?;
+
+		let template = Windows10Template {
+			username: String::from("root"),
+			password: String::from("root"),
+			iso: IsoContainer {
+				url: iso.path().to_string_lossy().to_string(),
+				checksum: String::from("none"),
+			},
+			provisioners: ProvisionersContainer {
+				provisioners: Some(vec![serde_json::json!({
+					"type": "shell",
+					"command": "echo test",
+				})]),
+			},
+			..Default::default()
+		};
+
+		let tmp = tempfile::tempdir()?;
+		let schedule = Schedule {
+			parallel: 1,
+			cpus: 1,
+			memory: 1 << 30,
+		};
+		let worker = BuildWorker {
+			ssh_port: harness.ssh.port,
+			vnc_port: harness.vnc.port,
+			launcher: Some(harness.launcher("shutdown")),
+			..BuildWorker::plan(
+				0,
+				BuildConfig::default(),
+				Box::new(template.clone()),
+				&schedule,
+				tmp.path().to_path_buf(),
+			)
+		};
+		template.build(&worker)?;
+
+		assert_eq!(harness.vnc.typed(), "\n");
+		assert_eq!(
+			harness
+				.ssh
+				.executions()
+				.iter()
+				.map(|execution| execution.command.as_str())
+				.collect::<Vec<&str>>(),
+			vec!["echo test", "shutdown /s /t 0 /f /d p:4:1"]
+		);
+		Ok(())
+	}
+}
@@ -0,0 +1,130 @@
+//! Emulated TPM 2.0 devices for build VMs. Each worker gets its own `swtpm`
+//! process which QEMU connects to over a unix socket.
+
+use crate::cancel::CancelToken;
+use log::{debug, info};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use simple_error::bail;
+use std::{
+	error::Error,
+	path::{Path, PathBuf},
+	process::{Child, Command},
+	time::Duration,
+};
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Default, Debug)]
+pub struct TpmConfig {
+	/// Keep each worker's TPM state next to the output image (i.e. to unseal
+	/// keys that were enrolled during the build)
+	#[serde(default)]
+	pub keep_state: bool,
+}
+
+/// The socket which serves the TPM with the given state directory.
+pub fn socket_path(state: &Path) -> PathBuf {
+	state.with_extension("sock")
+}
+
+/// A running swtpm process.
+pub struct Swtpm {
+	pub process: Child,
+	pub socket: PathBuf,
+}
+
+impl Drop for Swtpm {
+	fn drop(&mut self) {
+		if let Ok(None) = self.process.try_wait() {
+			debug!("Stopping swtpm process: {}", self.process.id());
+			self.process.kill().unwrap_or_default();
+			self.process.wait().unwrap_or_default();
+		}
+	}
+}
+
+impl Swtpm {
+	/// Start a TPM which keeps its state in the given directory and wait for
+	/// it to accept connections.
+	pub fn start(state: &Path, cancel: &CancelToken) -> Result<Swtpm, Box<dyn Error>> {
+		info!("Starting emulated TPM");
+		std::fs::create_dir_all(state)?;
+
+		let socket = socket_path(state);
+		let process = match Command::new("swtpm")
+			.arg("socket")
+			.arg("--tpm2")
+			.arg("--tpmstate")
+			.arg(format!("dir={}", state.display()))
+			.arg("--ctrl")
+			.arg(format!("type=unixio,path={}", socket.display()))
+			// Exit when QEMU disconnects
+			.arg("--terminate")
+			.spawn()
+		{
+			Ok(process) => process,
+			Err(error) => bail!("Failed to start swtpm (is it installed?): {}", error),
+		};
+
+		let mut swtpm = Swtpm { process, socket };
+		for _ in 0..50 {
+			if swtpm.socket.exists() {
+				return Ok(swtpm);
+			}
+			if let Some(status) = swtpm.process.try_wait()? {
+				bail!("swtpm exited early: {}", status);
+			}
+			cancel.sleep(Duration::from_millis(100))?;
+		}
+		bail!("swtpm did not start in a reasonable amount of time");
+	}
+}
+
+/// Copy the files in a TPM state directory to the given destination.
+fn copy_state(state: &Path, dest: &Path) -> Result<(), Box<dyn Error>> {
+	std::fs::create_dir_all(dest)?;
+	for entry in std::fs::read_dir(state)? {
+		let entry = entry?;
+		if entry.file_type()?.is_file() {
+			std::fs::copy(entry.path(), dest.join(entry.file_name()))?;
+		}
+	}
+	Ok(())
+}
+
+/// Copy a TPM state directory to the given destination.
+pub fn save_state(state: &Path, dest: &Path) -> Result<(), Box<dyn Error>> {
+	copy_state(state, dest)?;
+	info!("Saved TPM state to: {}", dest.display());
+	Ok(())
+}
+
+/// Restore a saved TPM state directory for a new VM.
+pub fn restore_state(saved: &Path, state: &Path) -> Result<(), Box<dyn Error>> {
+	copy_state(saved, state)?;
+	info!("Restored TPM state from: {}", saved.display());
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_save_state() -> Result<(), Box<dyn Error>> {
+		let tmp = tempfile::tempdir()?;
+		let state = tmp.path().join("tpm");
+		std::fs::create_dir_all(&state)?;
+		std::fs::write(state.join("tpm2-00.permall"), "state")?;
+
+		assert_eq!(socket_path(&state), tmp.path().join("tpm.sock"));
+
+		let dest = tmp.path().join("image.gb.tpm/0");
+		save_state(&state, &dest)?;
+		assert_eq!(std::fs::read(dest.join("tpm2-00.permall"))?, b"state");
+
+		let restored = tmp.path().join("run/tpm");
+		restore_state(&dest, &restored)?;
+		assert_eq!(std::fs::read(restored.join("tpm2-00.permall"))?, b"state");
+		Ok(())
+	}
+}
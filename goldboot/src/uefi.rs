@@ -0,0 +1,175 @@
+//! UEFI firmware handling. The bundled OVMF images are split into a read-only
+//! code volume and a writable variable store so each worker gets its own EFI
+//! variables, which also allows Secure Boot keys to be enrolled before the
+//! first boot.
+
+use log::info;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use simple_error::bail;
+use std::{error::Error, path::Path, process::Command};
+
+/// The GUID of the firmware volume which holds the variable store
+/// (fff12b8d-7696-4c8b-a985-2747075b4f50) in its on-disk byte order.
+const NV_DATA_GUID: [u8; 16] = [
+	0x8d, 0x2b, 0xf1, 0xff, 0x96, 0x76, 0x8b, 0x4c, 0xa9, 0x85, 0x27, 0x47, 0x07, 0x5b, 0x4f, 0x50,
+];
+
+/// The owner GUID recorded for keys enrolled by goldboot.
+const OWNER_GUID: &str = "a2b4a67e-4b76-4a4f-9d8e-676f6c64626f";
+
+/// Split a firmware image into its code and variable store. Unified images
+/// begin with the variable store volume. Code-only images get an empty store
+/// which the firmware formats on first boot.
+pub fn split_firmware(image: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+	if image.len() >= 40 && image[16..32] == NV_DATA_GUID {
+		// The volume length is stored after the GUID
+		let length = u64::from_le_bytes(image[32..40].try_into()?) as usize;
+		if length >= image.len() {
+			bail!("Invalid variable store length: {}", length);
+		}
+		return Ok((image[length..].to_vec(), image[..length].to_vec()));
+	}
+
+	// The code and variable store fill a flash device whose size is a power of
+	// two, unless the code fills the device by itself (i.e. on aarch64)
+	let vars = match image.len().next_power_of_two() - image.len() {
+		0 => image.len(),
+		size => size,
+	};
+	Ok((image.to_vec(), vec![0u8; vars]))
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug)]
+pub struct SecureBootConfig {
+	/// Enroll Microsoft's KEK and db certificates (needed by Windows and by
+	/// Linux distributions that boot with shim)
+	#[serde(default = "default_microsoft")]
+	pub microsoft: bool,
+
+	/// A PEM certificate to enroll as the platform key. If omitted, the Red
+	/// Hat platform key is used with Microsoft's KEK and db.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub pk: Option<String>,
+
+	/// Additional PEM certificates to enroll in the KEK
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub kek: Vec<String>,
+
+	/// Additional PEM certificates to enroll in the signature database
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	pub db: Vec<String>,
+}
+
+fn default_microsoft() -> bool {
+	true
+}
+
+impl SecureBootConfig {
+	/// Check that the configured certificates exist.
+	pub fn check(&self) -> Result<(), Box<dyn Error>> {
+		if self.pk.is_none() && !self.microsoft {
+			bail!("A platform key is required when Microsoft keys aren't enrolled");
+		}
+		for cert in self.pk.iter().chain(&self.kek).chain(&self.db) {
+			if !Path::new(cert).exists() {
+				bail!("Certificate not found: {}", cert);
+			}
+		}
+		Ok(())
+	}
+
+	/// The arguments for virt-fw-vars which enroll the keys and enable Secure
+	/// Boot.
+	fn args(&self, input: &Path, output: &Path) -> Vec<String> {
+		let mut args = vec![
+			String::from("--input"),
+			input.to_string_lossy().to_string(),
+			String::from("--output"),
+			output.to_string_lossy().to_string(),
+		];
+
+		match &self.pk {
+			Some(pk) => {
+				args.push(String::from("--enroll-cert"));
+				args.push(pk.clone());
+				if !self.microsoft {
+					args.push(String::from("--no-microsoft"));
+				}
+			}
+			None => args.push(String::from("--enroll-redhat")),
+		}
+
+		for (option, certs) in [("--add-kek", &self.kek), ("--add-db", &self.db)] {
+			for cert in certs {
+				args.push(String::from(option));
+				args.push(String::from(OWNER_GUID));
+				args.push(cert.clone());
+			}
+		}
+
+		args.push(String::from("--secure-boot"));
+		args
+	}
+
+	/// Enroll the keys into the given variable store.
+	pub fn enroll(&self, vars: &Path) -> Result<(), Box<dyn Error>> {
+		info!("Enrolling Secure Boot keys");
+		self.check()?;
+
+		let output = vars.with_extension("enrolled");
+		let status = match Command::new("virt-fw-vars")
+			.args(self.args(vars, &output))
+			.status()
+		{
+			Ok(status) => status,
+			Err(error) => bail!(
+				"Failed to run virt-fw-vars (is virt-firmware installed?): {}",
+				error
+			),
+		};
+		if !status.success() {
+			bail!("Failed to enroll Secure Boot keys: {}", status);
+		}
+
+		std::fs::rename(output, vars)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_split_firmware() -> Result<(), Box<dyn Error>> {
+		// A unified image with a 16 byte variable store
+		let mut image = vec![0u8; 64];
+		image[16..32].copy_from_slice(&NV_DATA_GUID);
+		image[32..40].copy_from_slice(&48u64.to_le_bytes());
+		let (code, vars) = split_firmware(&image)?;
+		assert_eq!((code.len(), vars.len()), (16, 48));
+
+		// Code-only images
+		assert_eq!(split_firmware(&[1u8; 48])?.1.len(), 16);
+		assert_eq!(split_firmware(&[1u8; 64])?.1.len(), 64);
+		Ok(())
+	}
+
+	#[test]
+	fn test_enroll_args() {
+		let config = SecureBootConfig {
+			microsoft: false,
+			pk: Some(String::from("pk.pem")),
+			kek: vec![],
+			db: vec![String::from("db.pem")],
+		};
+		assert_eq!(
+			config.args(Path::new("vars.fd"), Path::new("out.fd")).join(" "),
+			format!(
+				"--input vars.fd --output out.fd --enroll-cert pk.pem --no-microsoft --add-db {} db.pem --secure-boot",
+				OWNER_GUID
+			)
+		);
+	}
+}
@@ -0,0 +1,348 @@
+//! Build configs may declare variables which are substituted into the config
+//! before it's parsed. References take the form `${name}` for declared
+//! variables and `${env.NAME}` for environment variables. A literal `${` can be
+//! written as `$${`. Variable values themselves may only refer to environment
+//! variables.
+
+use crate::build::BuildConfig;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use simple_error::bail;
+use std::{collections::BTreeMap, error::Error};
+
+/// A variable declared in the `variables` section of a build config.
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Variable {
+	/// A plain value
+	Value(String),
+
+	/// A value with additional metadata
+	Definition(VariableDefinition),
+}
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema, Default, Debug, PartialEq, Eq)]
+pub struct VariableDefinition {
+	/// The variable's value which can be overridden from the command line
+	#[serde(alias = "default", skip_serializing_if = "Option::is_none")]
+	pub value: Option<String>,
+
+	/// Secret values are redacted from the config that gets stored in the
+	/// final image
+	#[serde(default, skip_serializing_if = "std::ops::Not::not")]
+	pub secret: bool,
+
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub description: Option<String>,
+}
+
+impl Variable {
+	pub fn value(&self) -> Option<&String> {
+		match self {
+			Variable::Value(value) => Some(value),
+			Variable::Definition(definition) => definition.value.as_ref(),
+		}
+	}
+
+	pub fn is_secret(&self) -> bool {
+		match self {
+			Variable::Value(_) => false,
+			Variable::Definition(definition) => definition.secret,
+		}
+	}
+}
+
+/// Parse a `key=value` pair given on the command line.
+pub fn parse_assignment(assignment: &str) -> Result<(String, String), Box<dyn Error>> {
+	match assignment.split_once('=') {
+		Some((key, value)) if key.len() > 0 => Ok((key.to_string(), value.to_string())),
+		_ => bail!(
+			"Expected a variable assignment like key=value: {}",
+			assignment
+		),
+	}
+}
+
+/// Resolve all variable references in the given raw config. Values in
+/// `overrides` take precedence over the ones declared in the config. The
+/// resolved values are written back into the `variables` section. Fields
+/// which refer to secret variables are returned by JSON pointer along with
+/// their value before the secrets were substituted, so they can be redacted
+/// later.
+pub fn interpolate(
+	mut config: serde_json::Value,
+	overrides: &BTreeMap<String, String>,
+) -> Result<(serde_json::Value, BTreeMap<String, String>), Box<dyn Error>> {
+	let mut variables: BTreeMap<String, Variable> = match config.get("variables") {
+		Some(variables) => serde_json::from_value(variables.to_owned())?,
+		None => BTreeMap::new(),
+	};
+
+	for (name, value) in overrides {
+		match variables.get_mut(name) {
+			Some(Variable::Definition(definition)) => definition.value = Some(value.clone()),
+			Some(variable) => *variable = Variable::Value(value.clone()),
+			None => bail!("Variable was not declared in the config: {}", name),
+		}
+	}
+
+	// Variable values may only refer to the environment
+	for variable in variables.values_mut() {
+		match variable {
+			Variable::Value(value) => *value = interpolate_str(value, &BTreeMap::new())?,
+			Variable::Definition(VariableDefinition {
+				value: Some(value), ..
+			}) => *value = interpolate_str(value, &BTreeMap::new())?,
+			_ => {}
+		}
+	}
+
+	let values: BTreeMap<String, String> = variables
+		.iter()
+		.filter_map(|(name, variable)| Some((name.clone(), variable.value()?.clone())))
+		.collect();
+
+	// The same values with secrets left as references
+	let redacted: BTreeMap<String, String> = variables
+		.iter()
+		.filter_map(|(name, variable)| match variable.is_secret() {
+			true => Some((name.clone(), format!("${{{}}}", name))),
+			false => Some((name.clone(), variable.value()?.clone())),
+		})
+		.collect();
+
+	let mut redactions = BTreeMap::new();
+	if let Some(object) = config.as_object_mut() {
+		for (key, value) in object.iter_mut() {
+			if key != "variables" {
+				interpolate_value(
+					value,
+					&pointer("", key),
+					&values,
+					&redacted,
+					&mut redactions,
+				)?;
+			}
+		}
+
+		if variables.len() > 0 {
+			object.insert(String::from("variables"), serde_json::to_value(&variables)?);
+		}
+	}
+
+	Ok((config, redactions))
+}
+
+/// Resolve variables in the given raw config and parse it.
+pub fn load(
+	config: serde_json::Value,
+	overrides: &BTreeMap<String, String>,
+) -> Result<BuildConfig, Box<dyn Error>> {
+	let (config, redactions) = interpolate(config, overrides)?;
+	let mut config: BuildConfig = serde_json::from_value(config)?;
+	config.redactions = redactions;
+	Ok(config)
+}
+
+/// Append a key to a JSON pointer.
+fn pointer(parent: &str, key: &str) -> String {
+	format!("{}/{}", parent, key.replace('~', "~0").replace('/', "~1"))
+}
+
+fn interpolate_value(
+	value: &mut serde_json::Value,
+	path: &str,
+	values: &BTreeMap<String, String>,
+	redacted: &BTreeMap<String, String>,
+	redactions: &mut BTreeMap<String, String>,
+) -> Result<(), Box<dyn Error>> {
+	match value {
+		serde_json::Value::String(text) => {
+			let redacted = interpolate_str(text, redacted)?;
+			*text = interpolate_str(text, values)?;
+			if *text != redacted {
+				redactions.insert(path.to_string(), redacted);
+			}
+		}
+		serde_json::Value::Array(array) => {
+			for (i, value) in array.iter_mut().enumerate() {
+				interpolate_value(
+					value,
+					&pointer(path, &i.to_string()),
+					values,
+					redacted,
+					redactions,
+				)?;
+			}
+		}
+		serde_json::Value::Object(object) => {
+			for (key, value) in object.iter_mut() {
+				interpolate_value(value, &pointer(path, key), values, redacted, redactions)?;
+			}
+		}
+		_ => {}
+	}
+	Ok(())
+}
+
+fn interpolate_str(
+	text: &str,
+	values: &BTreeMap<String, String>,
+) -> Result<String, Box<dyn Error>> {
+	let mut output = String::new();
+	let mut rest = text;
+
+	while let Some(start) = rest.find('$') {
+		output.push_str(&rest[..start]);
+		rest = &rest[start..];
+
+		if rest.starts_with("$${") {
+			output.push_str("${");
+			rest = &rest[3..];
+		} else if rest.starts_with("${") {
+			let end = match rest.find('}') {
+				Some(end) => end,
+				None => bail!("Unterminated variable reference: {}", text),
+			};
+			let name = &rest[2..end];
+
+			if let Some(env) = name.strip_prefix("env.") {
+				match std::env::var(env) {
+					Ok(value) => output.push_str(&value),
+					Err(_) => bail!("Environment variable is not set: {}", env),
+				}
+			} else {
+				match values.get(name) {
+					Some(value) => output.push_str(value),
+					None => bail!("Variable has no value: {}", name),
+				}
+			}
+			rest = &rest[end + 1..];
+		} else {
+			output.push('$');
+			rest = &rest[1..];
+		}
+	}
+
+	output.push_str(rest);
+	Ok(output)
+}
+
+/// Restore the fields which were interpolated from secret variables to
+/// references to them so the config can be stored (or logged) safely.
+pub fn redact(config: &BuildConfig) -> Result<BuildConfig, Box<dyn Error>> {
+	let mut value = serde_json::to_value(config)?;
+	for (path, redacted) in &config.redactions {
+		if let Some(field) = value.pointer_mut(path) {
+			*field = serde_json::Value::String(redacted.clone());
+		}
+	}
+
+	let mut config: BuildConfig = serde_json::from_value(value)?;
+	for variable in config.variables.iter_mut().flat_map(|v| v.values_mut()) {
+		if let Variable::Definition(definition) = variable {
+			if definition.secret {
+				definition.value = None;
+			}
+		}
+	}
+
+	Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	#[test]
+	fn test_interpolate() -> Result<(), Box<dyn Error>> {
+		std::env::set_var("GOLDBOOT_TEST_MIRROR", "https://example.com");
+		std::env::set_var("GOLDBOOT_TEST_USER", "admin");
+
+		let (config, redactions) = interpolate(
+			json!({
+				"name": "${name}",
+				"variables": {
+					"name": "Test",
+					"user": "${env.GOLDBOOT_TEST_USER}",
+					"password": {
+						"default": "1234",
+						"secret": true
+					}
+				},
+				"templates": [{
+					"base": "Arch",
+					"mirror": "${env.GOLDBOOT_TEST_MIRROR}/$${arch}",
+					"root_password": "${password}",
+					"username": "${user}"
+				}]
+			}),
+			&BTreeMap::from([(String::from("password"), String::from("5678"))]),
+		)?;
+
+		assert_eq!(config["name"], "Test");
+		assert_eq!(
+			config["templates"][0]["mirror"],
+			"https://example.com/${arch}"
+		);
+		assert_eq!(config["templates"][0]["root_password"], "5678");
+		assert_eq!(config["templates"][0]["username"], "admin");
+		assert_eq!(config["variables"]["password"]["value"], "5678");
+		assert_eq!(
+			redactions,
+			BTreeMap::from([(
+				String::from("/templates/0/root_password"),
+				String::from("${password}")
+			)])
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_interpolate_undefined() {
+		assert!(interpolate(json!({ "name": "${missing}" }), &BTreeMap::new()).is_err());
+		assert!(interpolate(
+			json!({ "name": "test" }),
+			&BTreeMap::from([(String::from("missing"), String::from("value"))])
+		)
+		.is_err());
+	}
+
+	#[test]
+	fn test_redact() -> Result<(), Box<dyn Error>> {
+		let config = load(
+			json!({
+				"name": "Test",
+				"arch": "amd64",
+				"variables": {
+					"password": {
+						"value": "1",
+						"secret": true
+					}
+				},
+				"templates": [{
+					"base": "Arch",
+					"hostname": "host1",
+					"root_password": "${password}"
+				}]
+			}),
+			&BTreeMap::new(),
+		)?;
+		assert_eq!(config.templates[0]["root_password"], "1");
+
+		// Only fields which referred to the secret are redacted
+		let config = redact(&config)?;
+		assert_eq!(config.templates[0]["root_password"], "${password}");
+		assert_eq!(config.templates[0]["hostname"], "host1");
+		assert_eq!(
+			config.variables.unwrap()["password"],
+			Variable::Definition(VariableDefinition {
+				value: None,
+				secret: true,
+				description: None,
+			})
+		);
+		Ok(())
+	}
+}
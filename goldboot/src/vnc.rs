@@ -1,16 +1,43 @@
+use crate::{
+	cancel::CancelToken,
+	events::{self, BuildEvent},
+	keyboard::{self, KeyboardLayout},
+};
 use log::{debug, info, trace};
 use rand::Rng;
+use serde_json::json;
 use sha1::{Digest, Sha1};
 use simple_error::bail;
-use std::{error::Error, fs::File, io::BufWriter, net::TcpStream, path::Path, time::Duration};
+use std::{
+	error::Error,
+	fmt,
+	fs::File,
+	io::{BufWriter, Cursor, Write},
+	net::TcpStream,
+	path::{Path, PathBuf},
+	time::{Duration, Instant},
+};
 use vnc::client::Event;
 
+#[derive(Clone)]
 pub struct VncScreenshot {
 	pub data: Vec<u8>,
 	pub width: u16,
 	pub height: u16,
 }
 
+impl fmt::Debug for VncScreenshot {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"VncScreenshot({} x {}, {})",
+			self.width,
+			self.height,
+			self.hash()
+		)
+	}
+}
+
 impl VncScreenshot {
 	pub fn hash(&self) -> String {
 		hex::encode(Sha1::new().chain_update(&self.data).finalize())
@@ -18,13 +45,7 @@ impl VncScreenshot {
 
 	pub fn write_png(&self, output_path: &Path) -> Result<(), Box<dyn Error>> {
 		std::fs::create_dir_all(output_path.parent().unwrap())?;
-		let ref mut w = BufWriter::new(File::create(output_path)?);
-
-		let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
-		encoder.set_color(png::ColorType::Grayscale);
-		encoder.set_depth(png::BitDepth::Eight);
-		let mut writer = encoder.write_header().unwrap();
-		writer.write_image_data(&self.data).unwrap();
+		self.encode_png(BufWriter::new(File::create(output_path)?))?;
 
 		debug!(
 			"Saved screenshot to: {:?}",
@@ -33,9 +54,56 @@ impl VncScreenshot {
 		Ok(())
 	}
 
-	/// Compute a percentage of how similar the given screenshot is to this one.
-	pub fn similarity(&self, _other: &VncScreenshot) -> f32 {
-		todo!();
+	/// Encode the screenshot as a grayscale PNG.
+	pub fn encode_png<W: Write>(&self, w: W) -> Result<(), Box<dyn Error>> {
+		let mut encoder = png::Encoder::new(w, self.width as u32, self.height as u32);
+		encoder.set_color(png::ColorType::Grayscale);
+		encoder.set_depth(png::BitDepth::Eight);
+		let mut writer = encoder.write_header()?;
+		writer.write_image_data(&self.data)?;
+		Ok(())
+	}
+
+	/// Read a screenshot from a PNG written by `write_png`.
+	pub fn read_png(data: &[u8]) -> Result<VncScreenshot, Box<dyn Error>> {
+		let mut reader = png::Decoder::new(Cursor::new(data)).read_info()?;
+		let info = reader.info();
+		if info.color_type != png::ColorType::Grayscale || info.bit_depth != png::BitDepth::Eight {
+			bail!("Screenshots must be 8-bit grayscale PNGs");
+		}
+
+		let mut data = vec![0u8; reader.output_buffer_size().unwrap_or_default()];
+		let frame = reader.next_frame(&mut data)?;
+		data.truncate(frame.buffer_size());
+
+		Ok(VncScreenshot {
+			data,
+			width: frame.width.try_into()?,
+			height: frame.height.try_into()?,
+		})
+	}
+
+	/// Compute how similar the given screenshot is to this one as the fraction
+	/// of matching pixels (from 0.0 to 1.0). Pixels match when each of their
+	/// color levels is within `CHANNEL_TOLERANCE`. Screenshots of different
+	/// sizes aren't similar at all.
+	pub fn similarity(&self, other: &VncScreenshot) -> f32 {
+		if self.width != other.width || self.height != other.height || self.data.is_empty() {
+			return 0.0;
+		}
+
+		let same = self
+			.data
+			.iter()
+			.zip(&other.data)
+			.filter(|(a, b)| {
+				channels(**a)
+					.iter()
+					.zip(channels(**b))
+					.all(|(a, b)| a.abs_diff(b) <= CHANNEL_TOLERANCE)
+			})
+			.count();
+		same as f32 / self.data.len() as f32
 	}
 
 	/// Create a trimmed screenshot according to the given dimensions
@@ -76,6 +144,20 @@ impl VncScreenshot {
 	}
 }
 
+/// How many levels each color channel of two pixels may differ by for
+/// `similarity` to count them as the same (i.e. antialiased edges).
+const CHANNEL_TOLERANCE: u8 = 1;
+
+/// Split a pixel in the connection's format into its red, green and blue
+/// levels.
+fn channels(pixel: u8) -> [u8; 3] {
+	[pixel >> 5, (pixel >> 2) & 0b111, pixel & 0b11]
+}
+
+/// How long screen waits last unless the build config says otherwise. This is
+/// generous because installs can take a long time without KVM.
+pub const DEFAULT_WAIT_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
 #[derive(Debug, Clone)]
 pub enum VncCmd {
 	Enter,
@@ -89,14 +171,25 @@ pub enum VncCmd {
 	/// Input the given text characters with a half-second delay between each.
 	Type(String),
 
+	/// Press a combination of named keys like "Ctrl+Alt+Delete" and release
+	/// them in reverse order.
+	Keys(String),
+
+	/// Hold down the named key until a matching `KeyUp`.
+	KeyDown(String),
+
+	/// Release the named key.
+	KeyUp(String),
+
 	/// Wait the given amount of seconds.
 	Wait(u64),
 
-	/// Wait for the screen to match the given hash.
-	WaitScreen(String),
+	/// Wait for the screen to match the given hash. Like all screen waits,
+	/// the last field overrides the connection's timeout in seconds.
+	WaitScreen(String, Option<u64>),
 
 	/// Wait for a section of the screen to match the given hash.
-	WaitScreenRect(String, u16, u16, u16, u16),
+	WaitScreenRect(String, u16, u16, u16, u16, Option<u64>),
 }
 
 /// Represents a VNC session to a running VM.
@@ -104,16 +197,33 @@ pub struct VncConnection {
 	pub width: u16,
 	pub height: u16,
 	pub vnc: vnc::Client,
+	pub worker: usize,
 	pub record: bool,
 	pub debug: bool,
+	pub cancel: CancelToken,
+
+	/// How long screen waits last unless the command has its own timeout
+	pub timeout: Duration,
+
+	/// Where diagnostics are written when a wait times out
+	pub artifacts: PathBuf,
+
+	/// The guest's keyboard layout which determines the keys `Type` presses
+	pub layout: KeyboardLayout,
+
+	/// The display is simulated (i.e. by the test harness), so screen waits
+	/// and delays pass immediately
+	pub simulated: bool,
 }
 
 impl VncConnection {
 	pub fn new(
 		host: &str,
 		port: u16,
+		worker: usize,
 		record: bool,
 		debug: bool,
+		cancel: CancelToken,
 	) -> Result<VncConnection, Box<dyn Error>> {
 		debug!("Attempting VNC connection to: {}:{}", host, port);
 
@@ -144,8 +254,14 @@ impl VncConnection {
 			width,
 			height,
 			vnc: vnc,
+			worker,
 			record,
 			debug,
+			cancel,
+			timeout: DEFAULT_WAIT_TIMEOUT,
+			artifacts: PathBuf::from("artifacts"),
+			layout: KeyboardLayout::default(),
+			simulated: false,
 		})
 	}
 
@@ -170,7 +286,7 @@ impl VncConnection {
 			};
 
 			// Request a full screen update
-			self.vnc.request_update(request_rect, false).unwrap();
+			self.vnc.request_update(request_rect, false)?;
 
 			for event in self.vnc.poll_iter() {
 				match event {
@@ -238,13 +354,109 @@ impl VncConnection {
 		}
 	}
 
+	/// Where this worker's artifacts are written.
+	pub fn artifacts_dir(&self) -> PathBuf {
+		self.artifacts.join(format!("worker{}", self.worker))
+	}
+
+	/// Press the given keys in order and then release them in reverse.
+	fn press(&mut self, keys: &[u32]) -> Result<(), Box<dyn Error>> {
+		for key in keys {
+			self.vnc.send_key_event(true, *key)?;
+		}
+		for key in keys.iter().rev() {
+			self.vnc.send_key_event(false, *key)?;
+		}
+		Ok(())
+	}
+
+	/// Sleep unless the display is simulated.
+	fn pause(&self, duration: Duration) -> Result<(), Box<dyn Error>> {
+		if self.simulated {
+			return Ok(());
+		}
+		self.cancel.sleep(duration)
+	}
+
+	/// Take screenshots until the given check passes, failing with
+	/// diagnostics in the artifacts directory if it doesn't in time.
+	fn wait_until(
+		&mut self,
+		step: usize,
+		cmd: &VncCmd,
+		timeout: Option<u64>,
+		hash: &str,
+		rect: Option<vnc::Rect>,
+		check: impl Fn(&VncScreenshot) -> bool,
+	) -> Result<(), Box<dyn Error>> {
+		if self.simulated {
+			return Ok(());
+		}
+
+		let timeout = timeout.map(Duration::from_secs).unwrap_or(self.timeout);
+		let start = Instant::now();
+
+		loop {
+			self.cancel.sleep(Duration::from_millis(
+				rand::thread_rng().gen_range(500..1000),
+			))?;
+
+			let screenshot = self.screenshot()?;
+			let matched = match rect {
+				Some(rect) => match screenshot.trim(rect) {
+					Ok(trimmed) => check(&trimmed),
+					// If the trim failed, the screen may not be the right size yet
+					Err(_) => false,
+				},
+				None => check(&screenshot),
+			};
+			if matched {
+				return Ok(());
+			}
+
+			if start.elapsed() >= timeout {
+				let directory = self.artifacts_dir();
+				screenshot.write_png(&directory.join(format!("step{step}.png")))?;
+				std::fs::write(
+					directory.join(format!("step{step}.json")),
+					serde_json::to_string_pretty(&json!({
+						"step": step,
+						"command": format!("{:?}", cmd),
+						"expected": hash,
+						"region": rect.map(|rect| json!({
+							"top": rect.top,
+							"left": rect.left,
+							"width": rect.width,
+							"height": rect.height,
+						})),
+						"actual": screenshot.hash(),
+					}))?,
+				)?;
+
+				bail!(
+					"Timed out after {} seconds waiting for the screen at step {} (see {})",
+					timeout.as_secs(),
+					step,
+					directory.display()
+				);
+			}
+		}
+	}
+
 	pub fn boot_command(&mut self, command: Vec<Vec<VncCmd>>) -> Result<(), Box<dyn Error>> {
 		info!("Running bootstrap sequence");
 
+		let total = command.iter().map(|step| step.len()).sum();
 		let mut step_number = 0;
 		for step in command {
 			for item in step {
+				self.cancel.check()?;
 				step_number += 1;
+				events::emit(BuildEvent::BootCommandStep {
+					worker: self.worker,
+					step: step_number,
+					total,
+				});
 
 				if self.debug {
 					match &item {
@@ -255,70 +467,51 @@ impl VncConnection {
 				match item {
 					VncCmd::Type(ref text) => {
 						for ch in text.chars() {
-							if ch == '\n' {
-								self.vnc.send_key_event(true, 0xff0d)?;
-								self.vnc.send_key_event(false, 0xff0d)?;
-							} else if ch.is_uppercase() {
-								self.vnc.send_key_event(true, 0xffe1)?;
-								self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
-								self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
-								self.vnc.send_key_event(false, 0xffe1)?;
-							} else {
-								match ch {
-									'~' | '!' | '#' | '$' | '%' | '^' | '&' | '*' | '(' | ')'
-									| '_' | '+' | '{' | '}' | '|' | ':' | '"' | '<' | '>' | '?' => {
-										self.vnc.send_key_event(true, 0xffe1)?;
-										self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
-										self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
-										self.vnc.send_key_event(false, 0xffe1)?;
-									}
-									_ => {
-										self.vnc.send_key_event(true, 0x01000000 + ch as u32)?;
-										self.vnc.send_key_event(false, 0x01000000 + ch as u32)?;
-									}
-								}
+							for keys in self.layout.presses(ch)? {
+								self.press(&keys)?;
 							}
-							std::thread::sleep(Duration::from_millis(100));
+							self.pause(Duration::from_millis(100))?;
 						}
 					}
+					VncCmd::Keys(ref keys) => {
+						self.press(&keyboard::combo(keys)?)?;
+					}
+					VncCmd::KeyDown(ref key) => {
+						self.vnc.send_key_event(true, keyboard::keysym(key)?)?;
+					}
+					VncCmd::KeyUp(ref key) => {
+						self.vnc.send_key_event(false, keyboard::keysym(key)?)?;
+					}
 					VncCmd::Wait(duration) => {
 						debug!("Waiting {} seconds", &duration);
-						std::thread::sleep(Duration::from_secs(duration));
+						self.pause(Duration::from_secs(duration))?;
 					}
-					VncCmd::WaitScreen(hash) => {
-						debug!("Waiting for screen hash to equal: {}", &hash);
-						loop {
-							std::thread::sleep(Duration::from_millis(
-								rand::thread_rng().gen_range(500..1000),
-							));
-							if self.screenshot()?.hash() == hash {
-								// Don't continue immediately
-								std::thread::sleep(Duration::from_secs(1));
-								break;
-							}
-						}
+					VncCmd::WaitScreen(ref hash, timeout) => {
+						debug!("Waiting for screen hash to equal: {}", hash);
+						self.wait_until(step_number, &item, timeout, hash, None, |screenshot| {
+							screenshot.hash() == *hash
+						})?;
+						// Don't continue immediately
+						self.pause(Duration::from_secs(1))?;
 					}
-					VncCmd::WaitScreenRect(hash, top, left, width, height) => {
-						debug!("Waiting for screen hash to equal: {}", &hash);
-						loop {
-							std::thread::sleep(Duration::from_secs(1));
-							match self.screenshot()?.trim(vnc::Rect {
-								top,
-								left,
-								width,
-								height,
-							}) {
-								Ok(screenshot) => {
-									if screenshot.hash() == hash {
-										// Wait a few before continuing
-										std::thread::sleep(Duration::from_secs(1));
-										break;
-									}
-								}
-								// If the trim failed, the screen may not be the right size yet
-								Err(_) => continue,
-							}
-						}
+					VncCmd::WaitScreenRect(ref hash, top, left, width, height, timeout) => {
+						debug!("Waiting for screen hash to equal: {}", hash);
+						let rect = vnc::Rect {
+							top,
+							left,
+							width,
+							height,
+						};
+						self.wait_until(
+							step_number,
+							&item,
+							timeout,
+							hash,
+							Some(rect),
+							|screenshot| screenshot.hash() == *hash,
+						)?;
+						// Wait a few before continuing
+						self.pause(Duration::from_secs(1))?;
 					}
 					VncCmd::Enter => {
 						self.vnc.send_key_event(true, 0xff0d)?;
@@ -405,7 +598,13 @@ pub mod bootcmds {
 	#[macro_export]
 	macro_rules! wait_screen {
 		($hash:expr) => {
-			vec![crate::vnc::VncCmd::WaitScreen($hash.to_string())]
+			vec![crate::vnc::VncCmd::WaitScreen($hash.to_string(), None)]
+		};
+		($hash:expr, timeout = $timeout:expr) => {
+			vec![crate::vnc::VncCmd::WaitScreen(
+				$hash.to_string(),
+				Some($timeout),
+			)]
 		};
 	}
 
@@ -418,8 +617,43 @@ pub mod bootcmds {
 				$left,
 				$width,
 				$height,
+				None,
 			)]
 		};
+		($hash:expr, $top:expr, $left:expr, $width:expr, $height:expr, timeout = $timeout:expr) => {
+			vec![crate::vnc::VncCmd::WaitScreenRect(
+				$hash.to_string(),
+				$top,
+				$left,
+				$width,
+				$height,
+				Some($timeout),
+			)]
+		};
+	}
+
+	#[macro_export]
+	macro_rules! keys {
+		($keys:expr) => {
+			vec![
+				crate::vnc::VncCmd::Keys($keys.to_string()),
+				crate::vnc::VncCmd::Wait(2),
+			]
+		};
+	}
+
+	#[macro_export]
+	macro_rules! key_down {
+		($key:expr) => {
+			vec![crate::vnc::VncCmd::KeyDown($key.to_string())]
+		};
+	}
+
+	#[macro_export]
+	macro_rules! key_up {
+		($key:expr) => {
+			vec![crate::vnc::VncCmd::KeyUp($key.to_string())]
+		};
 	}
 
 	#[macro_export]
@@ -439,3 +673,62 @@ pub mod bootcmds {
 		};
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::harness::{blank_screen, Harness};
+
+	#[test]
+	fn test_similarity() -> Result<(), Box<dyn Error>> {
+		let screen = VncScreenshot {
+			data: (0..100).map(|i| i as u8).collect(),
+			width: 10,
+			height: 10,
+		};
+
+		// A blinking cursor
+		let mut cursor = screen.clone();
+		cursor.data[0..3].fill(255);
+		assert_eq!(screen.similarity(&cursor), 0.97);
+
+		// Slightly different shades match
+		let mut shaded = screen.clone();
+		shaded.data[0] = 0b001_001_01;
+		shaded.data[99] = 99 + 0b100;
+		assert_eq!(screen.similarity(&shaded), 1.0);
+
+		assert_eq!(
+			screen.similarity(&screen.trim(vnc::Rect {
+				left: 0,
+				top: 0,
+				width: 5,
+				height: 5
+			})?),
+			0.0
+		);
+		Ok(())
+	}
+
+	#[test]
+	fn test_wait_timeout() -> Result<(), Box<dyn Error>> {
+		let harness = Harness::new(vec![blank_screen(640, 480)])?;
+		let tmp = tempfile::tempdir()?;
+
+		let mut vnc = harness.vnc_connection()?;
+		vnc.artifacts = tmp.path().to_path_buf();
+		assert!(vnc
+			.boot_command(vec![
+				vec![VncCmd::Tab],
+				vec![VncCmd::WaitScreen(String::from("0000"), Some(0))],
+			])
+			.is_err());
+
+		let failure: serde_json::Value =
+			serde_json::from_slice(&std::fs::read(tmp.path().join("worker0/step2.json"))?)?;
+		assert_eq!(failure["expected"], "0000");
+		assert_eq!(failure["actual"], blank_screen(640, 480).hash());
+		assert!(tmp.path().join("worker0/step2.png").exists());
+		Ok(())
+	}
+}